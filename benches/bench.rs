@@ -52,6 +52,7 @@ fn zerofee_state() -> State<InMemoryCas> {
         },
         stakes: Default::default(),
         init_fee_pool: 0.into(),
+        params: None,
     };
     let mut state = cfg.realize(&novasmt::Database::new(InMemoryCas::default()));
     state.fee_multiplier = 0;
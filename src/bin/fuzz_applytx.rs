@@ -0,0 +1,261 @@
+#[cfg(fuzzing)]
+use honggfuzz::fuzz;
+
+use arbitrary::{Result, Unstructured};
+use novasmt::{Database, InMemoryCas};
+use themelio_stf::melvm::{Address, Covenant};
+use themelio_stf::{
+    CoinData, CoinDataHeight, CoinID, Denom, GenesisConfig, NetID, SealedState, StakeDoc,
+    Transaction, TxKind,
+};
+
+#[cfg(fuzzing)]
+fn main() {
+    use env_logger::Env;
+    env_logger::Builder::from_env(Env::default().default_filter_or("blkstructs")).init();
+    let base = base_state();
+    loop {
+        fuzz!(|data: &[u8]| { test_once(&base, data) });
+    }
+}
+
+/// Number of coins the genesis coin is split into for the batches to spend.
+const COINS: u8 = 8;
+
+/// Fee paid by every generated transaction, comfortably above the base fee of any of them.
+const FEE: u128 = 1_000_000;
+
+/// Value of every SYM coin that can be staked.
+const SYMS: u128 = 1000;
+
+/// The state the batches are applied on top of, and the coins they can use.
+struct Base {
+    state: SealedState<InMemoryCas>,
+    /// MEL coins that anyone can spend.
+    coins: Vec<(CoinID, CoinData)>,
+    /// SYM coins that anyone can stake.
+    syms: Vec<(CoinID, CoinData)>,
+    /// Outputs of a staking transaction, which cannot be spent.
+    locked: Vec<CoinID>,
+}
+
+/// A sealed state one block after genesis, with [COINS] MEL coins that anyone can spend, as many SYM coins that anyone can stake, and an existing stake.
+#[cfg_attr(not(fuzzing), allow(dead_code))]
+fn base_state() -> Base {
+    let mut genesis = GenesisConfig {
+        network: NetID::Custom02,
+        init_coindata: always_true_coin(1 << 40),
+        ..GenesisConfig::std_testnet()
+    }
+    .realize(&Database::new(InMemoryCas::default()));
+    // genesis only has MEL, so the SYM coins are put in directly
+    let syms: Vec<(CoinID, CoinData)> = (0..=COINS)
+        .map(|i| {
+            let coin_id = CoinID {
+                txhash: tmelcrypt::hash_single([i]).into(),
+                index: 0,
+            };
+            (coin_id, sym_coin())
+        })
+        .collect();
+    for (coin_id, coin) in syms.iter() {
+        genesis.coins.insert(
+            *coin_id,
+            CoinDataHeight {
+                coin_data: coin.clone(),
+                height: 0.into(),
+            },
+        );
+    }
+    genesis.supply = genesis.scan_supply([]).ok();
+    let genesis = genesis.seal(None);
+
+    let split = Transaction::new(TxKind::Normal)
+        .add_input(CoinID::zero_zero())
+        .with_outputs(vec![always_true_coin(1 << 30); COINS as usize + 1])
+        .with_fee(((1 << 40) - (1 << 30) * (COINS as u128 + 1)).into())
+        .add_script(Covenant::always_true());
+    let mut coins: Vec<(CoinID, CoinData)> = split
+        .outputs
+        .iter()
+        .enumerate()
+        .map(|(i, coin)| (split.output_coinid(i as u8), coin.clone()))
+        .collect();
+    let mut syms = syms;
+    let stake = stake_tx(syms.pop().unwrap(), coins.pop().unwrap(), 1);
+    let mut next = genesis.next_state();
+    next.apply_tx_batch(&[split, stake.clone()]).unwrap();
+    let locked = (0..stake.outputs.len())
+        .map(|i| stake.output_coinid(i as u8))
+        .collect();
+    Base {
+        state: next.seal(None),
+        coins,
+        syms,
+        locked,
+    }
+}
+
+fn sym_coin() -> CoinData {
+    CoinData {
+        denom: Denom::Sym,
+        ..always_true_coin(SYMS)
+    }
+}
+
+/// Stakes a SYM coin from the given epoch on, paying the fee with a MEL coin.
+fn stake_tx(sym: (CoinID, CoinData), mel: (CoinID, CoinData), e_start: u64) -> Transaction {
+    Transaction::new(TxKind::Stake)
+        .add_input(sym.0)
+        .add_input(mel.0)
+        .add_output(sym.1)
+        .add_output(always_true_coin(mel.1.value.0 - FEE))
+        .with_fee(FEE.into())
+        .add_script(Covenant::always_true())
+        .with_data(
+            stdcode::serialize(&StakeDoc {
+                pubkey: tmelcrypt::Ed25519SK::generate().to_public(),
+                e_start,
+                e_post_end: e_start + 1,
+                syms_staked: SYMS.into(),
+            })
+            .unwrap(),
+        )
+}
+
+fn always_true_coin(value: u128) -> CoinData {
+    CoinData {
+        covhash: Covenant::always_true().hash(),
+        value: value.into(),
+        denom: Denom::Mel,
+        additional_data: vec![],
+    }
+}
+
+/// Builds a batch of valid transactions spending the given coins and each other's outputs, in a random order. Then corrupts at most one transaction that nothing else in the batch spends from, so that the batch has at most one offending transaction and both appliers must blame it for the same reason.
+///
+/// Staking transactions lock their outputs, so nothing else in the batch spends them.
+#[cfg_attr(not(fuzzing), allow(dead_code))]
+fn random_batch(u: &mut Unstructured, base: &Base) -> Result<Vec<Transaction>> {
+    let mut unspent = base.coins.clone();
+    let mut syms = base.syms.clone();
+    let mut txx = Vec::new();
+    for _ in 0..u.int_in_range(1..=12)? {
+        if !syms.is_empty() && !unspent.is_empty() && u.ratio(1, 8)? {
+            let sym = syms.swap_remove(u.choose_index(syms.len())?);
+            let mel = unspent.swap_remove(u.choose_index(unspent.len())?);
+            if mel.1.value.0 > FEE {
+                txx.push(stake_tx(sym, mel, u.int_in_range(1..=3)?));
+            }
+            continue;
+        }
+        let kind = if u.ratio(1, 8)? {
+            TxKind::Faucet
+        } else {
+            TxKind::Normal
+        };
+        let mut tx = Transaction::new(kind)
+            .with_fee(FEE.into())
+            .add_script(Covenant::always_true());
+        let mut total = 0;
+        if kind == TxKind::Faucet {
+            total = FEE + u.int_in_range(0..=1 << 30)?;
+        } else {
+            for _ in 0..u.int_in_range(1..=3)? {
+                if unspent.is_empty() {
+                    break;
+                }
+                let (coin_id, coin) = unspent.swap_remove(u.choose_index(unspent.len())?);
+                tx = tx.add_input(coin_id);
+                total += coin.value.0;
+            }
+            if total <= FEE {
+                continue;
+            }
+        }
+        let remaining = total - FEE;
+        let outputs = if remaining >= 4 * FEE && u.arbitrary()? {
+            let first = u.int_in_range(FEE..=remaining - FEE)?;
+            vec![always_true_coin(first), always_true_coin(remaining - first)]
+        } else {
+            vec![always_true_coin(remaining)]
+        };
+        tx = tx.with_outputs(outputs);
+        unspent.extend(
+            tx.outputs
+                .iter()
+                .enumerate()
+                .map(|(i, coin)| (tx.output_coinid(i as u8), coin.clone())),
+        );
+        txx.push(tx);
+    }
+    // shuffle, so that transactions often come before those whose outputs they spend
+    for i in (1..txx.len()).rev() {
+        let j = u.int_in_range(0..=i)?;
+        txx.swap(i, j);
+    }
+    let leaves = (0..txx.len())
+        .filter(|i| {
+            let txhash = txx[*i].hash_nosigs();
+            txx.iter()
+                .all(|tx| tx.inputs.iter().all(|coin| coin.txhash != txhash))
+        })
+        .collect::<Vec<_>>();
+    if leaves.is_empty() || !u.arbitrary()? {
+        return Ok(txx);
+    }
+    let victim = leaves[u.choose_index(leaves.len())?];
+    let stolen = txx[u.choose_index(txx.len())?].inputs.first().copied();
+    let locked = base.locked[u.choose_index(base.locked.len())?];
+    let tx = &mut txx[victim];
+    match u.int_in_range(0..=7)? {
+        0 => tx.scripts.clear(),
+        1 => tx.outputs[0].value += 1.into(),
+        2 => tx.inputs.push(CoinID {
+            txhash: tmelcrypt::hash_single(u.bytes(8)?).into(),
+            index: 0,
+        }),
+        3 => tx.inputs.extend(stolen),
+        4 => {
+            tx.outputs[0].value += tx.fee;
+            tx.fee = 0.into();
+        }
+        5 => tx.outputs[0].covhash = Address::coin_destroy(),
+        6 => tx.inputs.push(locked),
+        _ => {
+            let duplicate = tx.clone();
+            txx.push(duplicate);
+        }
+    }
+    Ok(txx)
+}
+
+/// Applies a random batch with both the parallel and the sequential applier, checking that they agree.
+#[cfg_attr(not(fuzzing), allow(dead_code))]
+fn test_once(base: &Base, data: &[u8]) {
+    let txx = match random_batch(&mut Unstructured::new(data), base) {
+        Ok(txx) => txx,
+        Err(_) => return,
+    };
+    let mut parallel = base.state.next_state();
+    let mut sequential = base.state.next_state();
+    match (
+        parallel.apply_tx_batch(&txx),
+        sequential.apply_tx_batch_sequential(&txx),
+    ) {
+        (Ok(()), Ok(())) => {
+            assert_eq!(parallel.supply, sequential.supply);
+            assert_eq!(parallel.seal(None).header(), sequential.seal(None).header())
+        }
+        (Err(p), Err(s)) => {
+            assert_eq!(
+                (p.tx_index, p.txhash, p.phase, format!("{:?}", p.error)),
+                (s.tx_index, s.txhash, s.phase, format!("{:?}", s.error))
+            );
+        }
+        (p, s) => panic!("parallel gave {:?}, sequential gave {:?}", p, s),
+    }
+}
+
+#[cfg(not(fuzzing))]
+fn main() {}
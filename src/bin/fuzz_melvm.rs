@@ -21,6 +21,7 @@ fn main() {
     }
 }
 
+#[cfg_attr(not(fuzzing), allow(dead_code))]
 fn test_once(data: &[u8]) {
     let (first, second) = data.split_at(data.len() / 2);
     let covenant = Covenant(first.to_vec());
@@ -0,0 +1,337 @@
+use crate::{Block, ProposerAction, SealedState, State, Transaction, TxHash};
+
+use std::collections::{BinaryHeap, HashMap};
+
+use novasmt::ContentAddrStore;
+
+/// A helper struct for proposers to assemble the next block out of candidate transactions.
+///
+/// Transactions are picked greedily by the tip per weight unit of the "package" formed by each transaction and its not-yet-included ancestors among the candidates, without ever exceeding the maximum block weight. Invalid candidates are skipped.
+#[derive(Debug)]
+pub struct BlockBuilder<C: ContentAddrStore> {
+    state: State<C>,
+    max_weight: u128,
+    weight: u128,
+    included: Vec<Transaction>,
+}
+
+impl<C: ContentAddrStore> BlockBuilder<C> {
+    /// Creates a new BlockBuilder for the block after the given sealed state. The maximum weight is further limited by the consensus maximum block weight, if there is one at that height.
+    pub fn new(base: &SealedState<C>, max_weight: u128) -> Self {
+        let state = base.next_state();
+        let max_weight = state
+            .max_block_weight()
+            .map_or(max_weight, |limit| limit.min(max_weight));
+        Self {
+            state,
+            max_weight,
+            weight: 0,
+            included: Vec::new(),
+        }
+    }
+
+    /// Returns the maximum total weight of the included transactions.
+    pub fn max_weight(&self) -> u128 {
+        self.max_weight
+    }
+
+    /// Returns the total weight of the included transactions.
+    pub fn weight(&self) -> u128 {
+        self.weight
+    }
+
+    /// Returns the included transactions, in an order in which they can be applied one by one: transactions spending outputs of other included transactions always come after them.
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.included
+    }
+
+    /// Picks transactions out of the given candidates. Transactions spending outputs of other candidates are only ever included after them.
+    pub fn add_candidates(&mut self, candidates: &[Transaction]) {
+        let by_hash: HashMap<TxHash, usize> = candidates
+            .iter()
+            .enumerate()
+            .map(|(idx, tx)| (tx.hash_nosigs(), idx))
+            .collect();
+        let mut children = vec![Vec::new(); candidates.len()];
+        for (idx, tx) in candidates.iter().enumerate() {
+            for input in tx.inputs.iter() {
+                if let Some(&parent) = by_hash.get(&input.txhash) {
+                    if parent != idx && !children[parent].contains(&idx) {
+                        children[parent].push(idx);
+                    }
+                }
+            }
+        }
+        let weights = candidates.iter().map(|tx| tx.weight()).collect::<Vec<_>>();
+        let tips = candidates.iter().map(|tx| self.tip(tx)).collect::<Vec<_>>();
+        let mut remaining = vec![true; candidates.len()];
+
+        // packages only change when some of their ancestors are included or dropped, so they are computed once and then only recomputed for the descendants of those
+        let mut packages: Vec<Package> = Vec::with_capacity(candidates.len());
+        let mut best = BinaryHeap::new();
+        for idx in 0..candidates.len() {
+            let package = Package::new(candidates, &by_hash, &remaining, &weights, &tips, idx);
+            best.push((package.rate(), idx, 0));
+            packages.push(package);
+        }
+        let mut versions = vec![0usize; candidates.len()];
+
+        // a package that doesn't fit never will, unless it shrinks and gets pushed again
+        while let Some((_, idx, version)) = best.pop() {
+            if !remaining[idx]
+                || version != versions[idx]
+                || self.weight.saturating_add(packages[idx].weight) > self.max_weight
+            {
+                continue;
+            }
+            let package = &packages[idx];
+            let package_txx = package
+                .members
+                .iter()
+                .map(|i| candidates[*i].clone())
+                .collect::<Vec<_>>();
+            let mut trial = self.state.clone();
+            let changed = match trial.apply_tx_batch(&package_txx) {
+                Ok(()) => {
+                    self.state = trial;
+                    self.weight += package.weight;
+                    package.members.iter().for_each(|i| remaining[*i] = false);
+                    self.included.extend(package_txx);
+                    package.members.clone()
+                }
+                Err(err) => {
+                    log::debug!("skipping candidate {}: {}", err.txhash, err.error);
+                    let failed = package.members[err.tx_index];
+                    remaining[failed] = false;
+                    vec![failed]
+                }
+            };
+            for idx in descendants(&children, &remaining, &changed) {
+                packages[idx] =
+                    Package::new(candidates, &by_hash, &remaining, &weights, &tips, idx);
+                versions[idx] += 1;
+                best.push((packages[idx].rate(), idx, versions[idx]));
+            }
+        }
+    }
+
+    /// Seals the block, returning the resulting state.
+    pub fn seal(self, action: Option<ProposerAction>) -> SealedState<C> {
+        self.state.seal(action)
+    }
+
+    /// Seals the block, returning it in serializable form. Like every block, it lists the transactions in canonical order rather than in [BlockBuilder::transactions] order; this is fine since blocks are applied as a batch, in which transactions can spend outputs of any other.
+    pub fn build(self, action: Option<ProposerAction>) -> Block {
+        self.seal(action).to_block()
+    }
+
+    fn tip(&self, tx: &Transaction) -> u128 {
+        tx.fee
+            .0
+            .saturating_sub(tx.base_fee(self.state.fee_multiplier, 0).0)
+    }
+}
+
+/// A candidate together with its remaining ancestors, which must be included along with it.
+struct Package {
+    /// Indices of the candidates in the package, parents first.
+    members: Vec<usize>,
+    weight: u128,
+    tips: u128,
+}
+
+impl Package {
+    fn new(
+        candidates: &[Transaction],
+        by_hash: &HashMap<TxHash, usize>,
+        remaining: &[bool],
+        weights: &[u128],
+        tips: &[u128],
+        idx: usize,
+    ) -> Self {
+        let members = package_of(candidates, by_hash, remaining, idx);
+        let weight = members.iter().map(|i| weights[*i]).sum();
+        let tips = members
+            .iter()
+            .map(|i| tips[*i])
+            .fold(0, u128::saturating_add);
+        Self {
+            members,
+            weight,
+            tips,
+        }
+    }
+
+    /// Tip per weight unit of the whole package.
+    fn rate(&self) -> u128 {
+        self.tips.saturating_mul(1 << 16) / self.weight.max(1)
+    }
+}
+
+/// Returns the indices of the candidate at `idx` and all its remaining ancestors, parents first.
+fn package_of(
+    candidates: &[Transaction],
+    by_hash: &HashMap<TxHash, usize>,
+    remaining: &[bool],
+    idx: usize,
+) -> Vec<usize> {
+    fn visit(
+        candidates: &[Transaction],
+        by_hash: &HashMap<TxHash, usize>,
+        remaining: &[bool],
+        idx: usize,
+        out: &mut Vec<usize>,
+    ) {
+        if out.contains(&idx) {
+            return;
+        }
+        for input in candidates[idx].inputs.iter() {
+            if let Some(parent) = by_hash.get(&input.txhash) {
+                if remaining[*parent] && *parent != idx {
+                    visit(candidates, by_hash, remaining, *parent, out);
+                }
+            }
+        }
+        out.push(idx);
+    }
+    let mut out = Vec::new();
+    visit(candidates, by_hash, remaining, idx, &mut out);
+    out
+}
+
+/// Returns the indices of the remaining candidates that transitively spend outputs of the given ones.
+fn descendants(children: &[Vec<usize>], remaining: &[bool], of: &[usize]) -> Vec<usize> {
+    let mut seen = vec![false; children.len()];
+    let mut stack = of.to_vec();
+    let mut out = Vec::new();
+    while let Some(idx) = stack.pop() {
+        for child in children[idx].iter() {
+            if remaining[*child] && !seen[*child] {
+                seen[*child] = true;
+                out.push(*child);
+                stack.push(*child);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::functions::{
+        always_true_coin, spend_split, split_custom_genesis_state, split_genesis_state,
+    };
+    use crate::{
+        melvm::Covenant, BlockHeight, ChainParams, ProposerAction, Tip, Transaction, TxKind,
+    };
+
+    use super::BlockBuilder;
+
+    #[test]
+    fn picks_best_paying() {
+        let (base, split) = split_genesis_state(10);
+        let txx = (0..10)
+            .map(|i| spend_split(&split, i, 100_000 + 10_000 * i as u128))
+            .collect::<Vec<_>>();
+        let max_weight = txx[..5].iter().map(|tx| tx.weight()).sum();
+
+        let mut builder = BlockBuilder::new(&base, max_weight);
+        builder.add_candidates(&txx);
+        assert_eq!(
+            builder.transactions(),
+            &txx[5..].iter().rev().cloned().collect::<Vec<_>>()
+        );
+        assert!(builder.weight() <= max_weight);
+
+        let action = ProposerAction {
+            fee_multiplier_delta: 0,
+            reward_dest: Covenant::always_true().hash(),
+        };
+        let block = builder.build(Some(action));
+        assert_eq!(block.transactions.len(), 5);
+        assert_eq!(block.proposer_action, Some(action));
+        // the block is valid on top of the base
+        assert!(base.apply_block(&block).is_ok());
+    }
+
+    #[test]
+    fn keeps_dependencies_in_order() {
+        let (base, split) = split_genesis_state(2);
+        let parent = spend_split(&split, 0, 10_000);
+        let child = Transaction::new(TxKind::Normal)
+            .add_input(parent.output_coinid(0))
+            .add_output(always_true_coin((1 << 30) - 10_000 - 1_000_000))
+            .with_fee(1_000_000.into())
+            .add_script(Covenant::always_true());
+        let other = spend_split(&split, 1, 200_000);
+
+        // the child pays for its parent, so the pair beats the other transaction
+        let max_weight = parent.weight() + child.weight();
+        let mut builder = BlockBuilder::new(&base, max_weight);
+        builder.add_candidates(&[child.clone(), other, parent.clone()]);
+        assert_eq!(builder.transactions(), &[parent, child]);
+    }
+
+    #[test]
+    fn skips_invalid() {
+        let (base, split) = split_genesis_state(2);
+        let good = spend_split(&split, 0, 100_000);
+        let conflicting = spend_split(&split, 0, 50_000);
+        let no_fee = spend_split(&split, 1, 0);
+
+        let mut builder = BlockBuilder::new(&base, u128::MAX);
+        builder.add_candidates(&[conflicting, good.clone(), no_fee]);
+        assert_eq!(builder.transactions(), &[good]);
+        let block = builder.build(None);
+        assert!(base.apply_block(&block).is_ok());
+    }
+
+    #[test]
+    fn keeps_chains_in_topological_order() {
+        let (base, split) = split_genesis_state(1);
+        let mut chain = vec![spend_split(&split, 0, 100_000)];
+        for i in 1..5u128 {
+            let parent = chain.last().unwrap();
+            chain.push(
+                Transaction::new(TxKind::Normal)
+                    .add_input(parent.output_coinid(0))
+                    .add_output(always_true_coin(
+                        parent.outputs[0].value.0 - 100_000 * (i + 1),
+                    ))
+                    .with_fee((100_000 * (i + 1)).into())
+                    .add_script(Covenant::always_true()),
+            );
+        }
+        let mut shuffled = chain.clone();
+        shuffled.reverse();
+        shuffled.swap(1, 3);
+
+        let mut builder = BlockBuilder::new(&base, u128::MAX);
+        builder.add_candidates(&shuffled);
+        assert_eq!(builder.transactions(), chain.as_slice());
+        // they can be applied one by one in that order
+        let mut state = base.next_state();
+        for tx in builder.transactions() {
+            state.apply_tx(tx).unwrap();
+        }
+        // the block lists them in canonical order instead, and is still valid
+        let block = builder.build(None);
+        assert!(block.is_canonical());
+        assert!(base.apply_block(&block).is_ok());
+    }
+
+    #[test]
+    fn limited_by_consensus_weight() {
+        let (base, split) = split_custom_genesis_state(
+            3,
+            ChainParams {
+                max_block_weight: 1,
+                ..ChainParams::custom().with_activation_height(Tip::Tip903, BlockHeight(0))
+            },
+        );
+        let mut builder = BlockBuilder::new(&base, u128::MAX);
+        assert_eq!(builder.max_weight(), 1);
+        builder.add_candidates(&[spend_split(&split, 0, 100_000)]);
+        assert!(builder.transactions().is_empty());
+    }
+}
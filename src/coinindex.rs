@@ -0,0 +1,240 @@
+use crate::{melvm::Address, CoinDataHeight, CoinID, State, StateEvent, StateObserver};
+
+use std::collections::{BTreeMap, HashMap};
+
+use novasmt::ContentAddrStore;
+use parking_lot::RwLock;
+
+/// A non-consensus index of unspent coins by the address that owns them, for answering wallet queries without scanning the whole coin SMT.
+///
+/// The index is a [StateObserver]: pass it to [crate::SealedState::apply_block_observed] for every block, or to [State::apply_tx_batch_observed] and [State::seal_observed] when building blocks, to keep it up to date with every coin created and spent by transactions, rewritten by melmint, or minted as a proposer reward. Coins sent to [Address::coin_destroy], including faucet deduplication pseudocoins, are not indexed.
+#[derive(Debug, Default)]
+pub struct CoinIndex {
+    inner: RwLock<IndexInner>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct IndexInner {
+    by_address: HashMap<Address, BTreeMap<CoinID, CoinDataHeight>>,
+    owners: HashMap<CoinID, Address>,
+}
+
+impl Clone for CoinIndex {
+    fn clone(&self) -> Self {
+        Self {
+            inner: RwLock::new(self.inner.read().clone()),
+        }
+    }
+}
+
+impl CoinIndex {
+    /// Creates an empty index.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rebuilds the index from the coins of a state. Since the coin SMT is keyed by hashes, the candidate coin IDs must be supplied, for instance by collecting the outputs of every block, or from [crate::SmtMapping::iter] if the state records keys; candidates that are not unspent in the state are skipped.
+    pub fn from_state<C: ContentAddrStore>(
+        state: &State<C>,
+        candidates: impl IntoIterator<Item = CoinID>,
+    ) -> Self {
+        let index = Self::new();
+        candidates.into_iter().for_each(|coin_id| {
+            if let Some(coin) = state.coins.get(&coin_id).0 {
+                index.insert(coin_id, coin);
+            }
+        });
+        index
+    }
+
+    /// Returns the unspent coins owned by the given address.
+    pub fn coins_of(&self, address: Address) -> BTreeMap<CoinID, CoinDataHeight> {
+        self.inner
+            .read()
+            .by_address
+            .get(&address)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns the address that owns the given coin, if it is indexed.
+    pub fn owner(&self, coin_id: CoinID) -> Option<Address> {
+        self.inner.read().owners.get(&coin_id).copied()
+    }
+
+    /// Returns the number of indexed coins.
+    pub fn len(&self) -> usize {
+        self.inner.read().owners.len()
+    }
+
+    /// Returns true iff no coins are indexed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Indexes a coin, replacing any previous entry for the same ID.
+    fn insert(&self, coin_id: CoinID, coin: CoinDataHeight) {
+        self.inner.write().insert(coin_id, coin)
+    }
+
+    /// Removes a coin from the index, if it is there.
+    fn remove(&self, coin_id: CoinID) {
+        self.inner.write().remove(coin_id)
+    }
+}
+
+impl IndexInner {
+    fn insert(&mut self, coin_id: CoinID, coin: CoinDataHeight) {
+        self.remove(coin_id);
+        let address = coin.coin_data.covhash;
+        if address != Address::coin_destroy() {
+            self.owners.insert(coin_id, address);
+            self.by_address
+                .entry(address)
+                .or_default()
+                .insert(coin_id, coin);
+        }
+    }
+
+    fn remove(&mut self, coin_id: CoinID) {
+        if let Some(address) = self.owners.remove(&coin_id) {
+            if let Some(coins) = self.by_address.get_mut(&address) {
+                coins.remove(&coin_id);
+                if coins.is_empty() {
+                    self.by_address.remove(&address);
+                }
+            }
+        }
+    }
+}
+
+impl StateObserver for CoinIndex {
+    fn on_event(&self, event: StateEvent<'_>) {
+        match event {
+            StateEvent::CoinCreated { coin_id, coin }
+            | StateEvent::ProposerRewardMinted { coin_id, coin } => {
+                self.insert(coin_id, coin.clone())
+            }
+            StateEvent::CoinSpent { coin_id } => self.remove(coin_id),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::melvm::{Address, Covenant};
+    use crate::testing::functions::{always_true_coin, spend_split, split_genesis_state};
+    use crate::{CoinData, CoinID, Denom, PoolKey, ProposerAction, Transaction, TxKind};
+
+    use super::CoinIndex;
+
+    #[test]
+    fn follows_batches_and_seals() {
+        let (base, split) = split_genesis_state(3);
+        let always_true = Covenant::always_true().hash();
+        let other = Covenant(vec![0xff]).hash();
+        let index = CoinIndex::from_state(
+            base.inner_ref(),
+            (0..3)
+                .map(|i| split.output_coinid(i))
+                .chain([CoinID::zero_zero()]),
+        );
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.coins_of(always_true).len(), 3);
+
+        let moved = Transaction::new(TxKind::Normal)
+            .add_input(split.output_coinid(0))
+            .add_output(CoinData {
+                covhash: other,
+                ..always_true_coin((1 << 30) - 100_000)
+            })
+            .with_fee(100_000.into())
+            .add_script(Covenant::always_true());
+        let swap =
+            spend_split(&split, 1, 100_000).with_data(PoolKey::mel_and(Denom::Sym).to_bytes());
+        let faucet = Transaction::new(TxKind::Faucet)
+            .add_output(always_true_coin(1000))
+            .with_fee(100_000.into());
+        let mut next = base.next_state();
+        next.apply_tx_batch_observed(&[moved.clone(), swap.clone(), faucet.clone()], &index)
+            .unwrap();
+        assert_eq!(index.owner(moved.output_coinid(0)), Some(other));
+        assert_eq!(index.owner(split.output_coinid(0)), None);
+        assert_eq!(index.coins_of(other).len(), 1);
+        assert!(index.coins_of(Address::coin_destroy()).is_empty());
+
+        let sealed = next.seal_observed(
+            Some(ProposerAction {
+                fee_multiplier_delta: 0,
+                reward_dest: always_true,
+            }),
+            &index,
+        );
+        // the index agrees with the sealed state, including the melmint rewrite and the reward
+        let coins = index.coins_of(always_true);
+        assert_eq!(coins.len(), 4);
+        assert_eq!(coins[&swap.output_coinid(0)].coin_data.denom, Denom::Sym);
+        assert!(coins.contains_key(&CoinID::proposer_reward(sealed.inner_ref().height)));
+        for (coin_id, coin) in coins.iter() {
+            assert_eq!(sealed.inner_ref().coins.get(coin_id).0.as_ref(), Some(coin));
+        }
+
+        // rebuilding from the sealed state gives the same result
+        let candidates = index
+            .coins_of(always_true)
+            .into_keys()
+            .chain(index.coins_of(other).into_keys())
+            .chain([split.output_coinid(0)]);
+        let rebuilt = CoinIndex::from_state(sealed.inner_ref(), candidates);
+        assert_eq!(rebuilt.len(), index.len());
+        assert_eq!(rebuilt.coins_of(always_true), index.coins_of(always_true));
+    }
+
+    #[test]
+    fn follows_apply_block() {
+        let (base, split) = split_genesis_state(3);
+        let always_true = Covenant::always_true().hash();
+        let index = CoinIndex::from_state(base.inner_ref(), (0..3).map(|i| split.output_coinid(i)));
+
+        // a block with a melmint rewrite, a coin created and spent within it, and a reward
+        let swap =
+            spend_split(&split, 0, 100_000).with_data(PoolKey::mel_and(Denom::Sym).to_bytes());
+        let parent = spend_split(&split, 1, 100_000);
+        let child = Transaction::new(TxKind::Normal)
+            .add_input(parent.output_coinid(0))
+            .add_output(always_true_coin((1 << 30) - 300_000))
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true());
+        let mut next = base.next_state();
+        next.apply_tx_batch(&[swap.clone(), parent.clone(), child.clone()])
+            .unwrap();
+        let block = next
+            .seal(Some(ProposerAction {
+                fee_multiplier_delta: 0,
+                reward_dest: always_true,
+            }))
+            .to_block();
+
+        // an invalid block reports nothing
+        let mut wrong = block.clone();
+        wrong.header.fee_pool += 1.into();
+        assert!(base.apply_block_observed(&wrong, &index).is_err());
+        assert_eq!(index.len(), 3);
+
+        let applied = base.apply_block_observed(&block, &index).unwrap();
+        let coins = index.coins_of(always_true);
+        assert_eq!(coins.len(), 4);
+        assert_eq!(coins[&swap.output_coinid(0)].coin_data.denom, Denom::Sym);
+        assert!(coins.contains_key(&child.output_coinid(0)));
+        assert!(coins.contains_key(&split.output_coinid(2)));
+        assert!(coins.contains_key(&CoinID::proposer_reward(applied.inner_ref().height)));
+        assert_eq!(index.owner(parent.output_coinid(0)), None);
+        for (coin_id, coin) in coins.iter() {
+            assert_eq!(
+                applied.inner_ref().coins.get(coin_id).0.as_ref(),
+                Some(coin)
+            );
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use crate::{BlockHeight, CoinValue};
 
+use num::rational::Ratio;
+
 /// Maximum coin value
 pub const MAX_COINVAL: CoinValue = CoinValue(1 << 120);
 
@@ -9,8 +11,17 @@ pub const MICRO_CONVERTER: u128 = 1_000_000;
 /// A stake epoch is 200,000 blocks.
 pub const STAKE_EPOCH: u64 = 200000;
 
+/// Fraction of the staked syms that must sign a block for it to be confirmed. Must be strictly exceeded.
+pub const CONFIRM_THRESHOLD: Ratio<u128> = Ratio::new_raw(2, 3);
+
 /// TIP 901: change fee multiplier calculation
 pub const TIP_901_HEIGHT: BlockHeight = BlockHeight(42700);
 
 /// TIP 902: introduce non-MEL/non-MEL pools
 pub const TIP_902_HEIGHT: BlockHeight = BlockHeight(180000);
+
+/// TIP 903: limit the total weight of a block. Not yet scheduled on mainnet or testnet; custom networks can opt in through [crate::GenesisConfig::params].
+pub const TIP_903_HEIGHT: BlockHeight = BlockHeight(u64::MAX);
+
+/// Maximum total weight of the transactions in a block, once TIP 903 applies. Only a proposal until TIP 903 is scheduled.
+pub const MAX_BLOCK_WEIGHT: u128 = 10_000_000;
@@ -1,6 +1,6 @@
 use crate::{
-    melvm::Covenant, stake::StakeDoc, BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue,
-    Denom, NetID, SmtMapping, State, TxHash, MICRO_CONVERTER,
+    melvm::Covenant, stake::StakeDoc, BlockHeight, ChainParams, CoinData, CoinDataHeight, CoinID,
+    CoinValue, Denom, NetID, ParamsError, SmtMapping, State, Supply, TxHash, MICRO_CONVERTER,
 };
 
 use std::{collections::BTreeMap, convert::TryInto};
@@ -20,6 +20,9 @@ pub struct GenesisConfig {
     pub stakes: BTreeMap<TxHash, StakeDoc>,
     /// Initial fee pool. Half-life is approximately 15 days.
     pub init_fee_pool: CoinValue,
+    /// Consensus parameters overriding the network's defaults. Only honored for custom networks.
+    #[serde(default)]
+    pub params: Option<ChainParams>,
 }
 
 impl GenesisConfig {
@@ -44,7 +47,7 @@ impl GenesisConfig {
                 .map(|v| Ed25519PK(hex::decode(v).unwrap().try_into().unwrap()))
                 .map(|pubkey| {
                     (
-                        tmelcrypt::hash_single(&pubkey.0).into(), // A nonexistent hash
+                        tmelcrypt::hash_single(pubkey.0).into(), // A nonexistent hash
                         StakeDoc {
                             pubkey,
                             e_start: 0,
@@ -55,6 +58,7 @@ impl GenesisConfig {
                 })
                 .collect(),
             init_fee_pool: CoinValue::from_millions(6553600u64), // subsidy, decreasing rapidly
+            params: None,
         }
     }
 
@@ -78,7 +82,7 @@ impl GenesisConfig {
             .map(|v| Ed25519PK(hex::decode(v).unwrap().try_into().unwrap()))
             .map(|pubkey| {
                 (
-                    tmelcrypt::hash_single(&pubkey.0).into(),
+                    tmelcrypt::hash_single(pubkey.0).into(),
                     StakeDoc {
                         pubkey,
                         e_start: 0,
@@ -89,14 +93,38 @@ impl GenesisConfig {
             })
             .collect(),
             init_fee_pool: (1 << 64).into(),
+            params: None,
         }
     }
 
-    /// Creates a [State] from this configuration.
+    /// Returns the consensus parameters of the network: the overriding ones if this is a custom network and they are given, and otherwise the network's defaults.
+    pub fn chain_params(&self) -> ChainParams {
+        match (self.network, self.params) {
+            (NetID::Mainnet | NetID::Testnet, Some(_)) => {
+                log::warn!("ignoring chain parameters given for {:?}", self.network);
+                ChainParams::for_network(self.network)
+            }
+            (_, Some(params)) => params,
+            (network, None) => ChainParams::for_network(network),
+        }
+    }
+
+    /// Creates a [State] from this configuration. Panics if the chain parameters are invalid; see [GenesisConfig::try_realize].
     pub fn realize<C: ContentAddrStore>(self, db: &novasmt::Database<C>) -> State<C> {
+        self.try_realize(db).expect("invalid chain parameters")
+    }
+
+    /// Creates a [State] from this configuration, failing if the chain parameters are invalid for the network.
+    pub fn try_realize<C: ContentAddrStore>(
+        self,
+        db: &novasmt::Database<C>,
+    ) -> Result<State<C>, ParamsError> {
+        let params = self.chain_params();
+        params.validate(self.network)?;
         let empty_tree = db.get_tree(HashVal::default().0).unwrap();
         let mut new_state = State {
             network: self.network,
+            params,
             height: 0.into(),
             history: SmtMapping::new(empty_tree.clone()),
             coins: SmtMapping::new(empty_tree.clone()),
@@ -116,6 +144,11 @@ impl GenesisConfig {
 
                 stakes
             },
+
+            supply: {
+                let mut supply = Supply::default();
+                supply.add_coin(&self.init_coindata).ok().map(|_| supply)
+            },
         };
         // init micromels etc
         new_state.coins.insert(
@@ -125,6 +158,6 @@ impl GenesisConfig {
                 coin_data: self.init_coindata,
             },
         );
-        new_state
+        Ok(new_state)
     }
 }
@@ -7,10 +7,19 @@
 //! Roughly, the structs in this crate are organized as follows:
 //! - `State` represents a full Themelio world-state and it's not directly serializable. It includes *all* the information needed to validate new transactions and blocks, such as a SMT of all outstanding coins, Melmint parameters, etc. It has methods taking `Transaction`s etc that advance the state, as well as others to produce serializable blocks, headers, etc.
 //! - `Transaction` represents a serializable Themelio transaction. It has some helper methods to count coins, estimate fees, etc, largely to help build wallets.
+//! - `ChainParams` holds the consensus parameters of a network, such as the activation height of every `Tip`, which custom networks can override at genesis.
 //! - `StakeDoc`, which every `State` includes, encapsulates the Symphonia epoch-based stake information.
+//! - The `lightclient` module verifies data served by untrusted full nodes against a trusted `Header`.
+//! - `Mempool` keeps a speculative `State` of unconfirmed transactions on top of the last sealed state.
+//! - `CoinIndex` is an optional, non-consensus index of unspent coins by address, kept up to date as a `StateObserver`.
 //! - `SmtMapping` represents a type-safe SMT-backed mapping that is extensively used within the crate.
+mod blockbuilder;
+mod coinindex;
 mod constants;
 mod genesis;
+pub mod lightclient;
+mod mempool;
+mod params;
 pub mod melpow;
 pub mod melvm;
 mod stake;
@@ -21,10 +30,14 @@ mod transaction;
 mod txbuilder;
 mod units;
 
+pub use crate::blockbuilder::*;
+pub use crate::coinindex::*;
 pub use crate::state::melmint::*;
 pub use crate::units::*;
 pub use crate::constants::*;
 pub use crate::genesis::*;
+pub use crate::mempool::*;
+pub use crate::params::*;
 pub use crate::smtmapping::*;
 pub use crate::state::melswap::PoolState;
 pub use crate::state::*;
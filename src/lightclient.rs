@@ -0,0 +1,332 @@
+//! Helpers for light clients, which verify data served by untrusted full nodes against a trusted [Header].
+//!
+//! The `verify_*` helpers take the raw value a full node claims is stored under a key (empty if it claims there is none) together with the [FullProof] returned by [crate::SmtMapping::get], and return the decoded value only if the proof matches the header. [StateProof] bundles all of this into a single serializable value.
+
+use crate::{
+    BlockHeight, CoinDataHeight, CoinID, Header, PoolKey, PoolState, SmtMapping, StakeDoc,
+    Transaction, TxHash,
+};
+
+use novasmt::{CompressedProof, ContentAddrStore, FullProof};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+use tmelcrypt::HashVal;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+/// A error that happens while verifying a proof against a header
+pub enum ProofError {
+    #[error("proof does not match the header")]
+    BadProof,
+    #[error("proven value cannot be decoded")]
+    Undecodable,
+    #[error("proven value does not belong to the requested key")]
+    WrongKey,
+}
+
+/// A value stored in one of the SMTs a [Header] commits to, under keys of type `K`.
+pub trait Provable<K: Serialize>: Serialize + DeserializeOwned {
+    /// Returns the root of the SMT this kind of value is stored in.
+    fn root(header: &Header) -> HashVal;
+
+    /// Returns whether the value may be stored under the given key in a state with the given header.
+    fn belongs_to(&self, _key: &K, _header: &Header) -> bool {
+        true
+    }
+}
+
+impl Provable<CoinID> for CoinDataHeight {
+    fn root(header: &Header) -> HashVal {
+        header.coins_hash
+    }
+}
+
+impl Provable<TxHash> for Transaction {
+    fn root(header: &Header) -> HashVal {
+        header.transactions_hash
+    }
+
+    fn belongs_to(&self, key: &TxHash, _header: &Header) -> bool {
+        self.hash_nosigs() == *key
+    }
+}
+
+impl Provable<PoolKey> for PoolState {
+    fn root(header: &Header) -> HashVal {
+        header.pools_hash
+    }
+}
+
+impl Provable<TxHash> for StakeDoc {
+    fn root(header: &Header) -> HashVal {
+        header.stakes_hash
+    }
+}
+
+impl Provable<BlockHeight> for Header {
+    fn root(header: &Header) -> HashVal {
+        header.history_hash
+    }
+
+    fn belongs_to(&self, key: &BlockHeight, header: &Header) -> bool {
+        self.height == *key && self.network == header.network
+    }
+}
+
+/// Verifies that a coin exists with the given data, or that it does not exist if `value` is empty, against `header.coins_hash`.
+pub fn verify_coin(
+    header: &Header,
+    coin_id: CoinID,
+    value: &[u8],
+    proof: &FullProof,
+) -> Result<Option<CoinDataHeight>, ProofError> {
+    verify(header, &coin_id, value, proof)
+}
+
+/// Verifies that a transaction was, or was not, included in the block with the given header, against `header.transactions_hash`.
+pub fn verify_transaction(
+    header: &Header,
+    txhash: TxHash,
+    value: &[u8],
+    proof: &FullProof,
+) -> Result<Option<Transaction>, ProofError> {
+    verify(header, &txhash, value, proof)
+}
+
+/// Verifies the state of a pool, against `header.pools_hash`.
+pub fn verify_pool(
+    header: &Header,
+    pool: PoolKey,
+    value: &[u8],
+    proof: &FullProof,
+) -> Result<Option<PoolState>, ProofError> {
+    verify(header, &pool, value, proof)
+}
+
+/// Verifies the stake document created by a staking transaction, against `header.stakes_hash`.
+pub fn verify_stake(
+    header: &Header,
+    txhash: TxHash,
+    value: &[u8],
+    proof: &FullProof,
+) -> Result<Option<StakeDoc>, ProofError> {
+    verify(header, &txhash, value, proof)
+}
+
+/// Verifies the header of an earlier block, against `header.history_hash`.
+pub fn verify_history(
+    header: &Header,
+    height: BlockHeight,
+    value: &[u8],
+    proof: &FullProof,
+) -> Result<Option<Header>, ProofError> {
+    verify(header, &height, value, proof)
+}
+
+/// A self-contained proof that a key maps to a value, or to nothing, in the state committed to by a header. Produced by [crate::SealedState::prove_coin] and friends, and meant to be sent to light clients in its stdcode encoding.
+///
+/// The proof only shows consistency with the bundled header: it is up to the receiver to check that the header itself is trusted, for instance by comparing its hash with a confirmed one.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StateProof<K, V> {
+    /// The key being proven.
+    pub key: K,
+    /// The value stored under the key, or `None` if there is none.
+    pub value: Option<V>,
+    /// Compressed SMT proof against the relevant root in the header.
+    pub proof: CompressedProof,
+    /// The header whose state the proof refers to.
+    pub header: Header,
+}
+
+impl<K: Serialize, V: Provable<K>> StateProof<K, V> {
+    /// Creates a proof for a key of a mapping in the state with the given header.
+    pub(crate) fn new<C: ContentAddrStore>(
+        mapping: &SmtMapping<C, K, V>,
+        key: K,
+        header: Header,
+    ) -> Self {
+        let (value, proof) = mapping.get(&key);
+        Self {
+            key,
+            value,
+            proof: proof.compress(),
+            header,
+        }
+    }
+
+    /// Verifies the proof against the bundled header.
+    pub fn verify(&self) -> Result<(), ProofError> {
+        let proof = self.proof.decompress().ok_or(ProofError::BadProof)?;
+        let value = self
+            .value
+            .as_ref()
+            .map(|v| stdcode::serialize(v).unwrap())
+            .unwrap_or_default();
+        verify::<K, V>(&self.header, &self.key, &value, &proof)?;
+        Ok(())
+    }
+}
+
+/// Verifies a proof against the relevant root of a header, keyed the same way as [crate::SmtMapping].
+fn verify<K: Serialize, V: Provable<K>>(
+    header: &Header,
+    key: &K,
+    value: &[u8],
+    proof: &FullProof,
+) -> Result<Option<V>, ProofError> {
+    let hashed_key = tmelcrypt::hash_single(stdcode::serialize(key).unwrap());
+    if !proof.verify(V::root(header).0, hashed_key.0, value) {
+        return Err(ProofError::BadProof);
+    }
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let value: V = stdcode::deserialize(value).map_err(|_| ProofError::Undecodable)?;
+    if value.belongs_to(key, header) {
+        Ok(Some(value))
+    } else {
+        Err(ProofError::WrongKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::testing::functions::{create_state, spend_split, split_genesis_state};
+    use crate::{BlockHeight, CoinID, CoinValue, Denom, PoolKey};
+
+    use super::*;
+
+    #[test]
+    fn coins_and_transactions() {
+        let (base, split) = split_genesis_state(2);
+        let mut next = base.next_state();
+        let tx = spend_split(&split, 0, 100_000);
+        next.apply_tx(&tx).unwrap();
+        let sealed = next.seal(None);
+        let header = sealed.header();
+        let state = sealed.inner_ref();
+
+        // an existing coin
+        let (coin, proof) = state.coins.get(&tx.output_coinid(0));
+        let bytes = stdcode::serialize(&coin.clone().unwrap()).unwrap();
+        assert_eq!(
+            verify_coin(&header, tx.output_coinid(0), &bytes, &proof),
+            Ok(coin)
+        );
+        // a spent coin
+        let (_, proof) = state.coins.get(&split.output_coinid(0));
+        assert_eq!(
+            verify_coin(&header, split.output_coinid(0), &[], &proof),
+            Ok(None)
+        );
+        // lying about either is caught
+        assert_eq!(
+            verify_coin(&header, split.output_coinid(0), &bytes, &proof),
+            Err(ProofError::BadProof)
+        );
+        let (_, proof) = state.coins.get(&tx.output_coinid(0));
+        assert_eq!(
+            verify_coin(&header, tx.output_coinid(0), &[], &proof),
+            Err(ProofError::BadProof)
+        );
+        // so is a proof against another header
+        assert_eq!(
+            verify_coin(&base.header(), tx.output_coinid(0), &bytes, &proof),
+            Err(ProofError::BadProof)
+        );
+
+        let (proven, proof) = state.transactions.get(&tx.hash_nosigs());
+        let bytes = stdcode::serialize(&tx).unwrap();
+        assert_eq!(
+            verify_transaction(&header, tx.hash_nosigs(), &bytes, &proof),
+            Ok(proven)
+        );
+        let missing = CoinID::zero_zero().txhash;
+        let (_, proof) = state.transactions.get(&missing);
+        assert_eq!(verify_transaction(&header, missing, &[], &proof), Ok(None));
+    }
+
+    #[test]
+    fn pools_stakes_and_history() {
+        let stakers = [100u128, 200]
+            .iter()
+            .map(|v| (tmelcrypt::Ed25519SK::generate(), CoinValue(*v)))
+            .collect::<HashMap<_, _>>();
+        let first = create_state(&stakers, 0).seal(None);
+        let second = first.next_state().seal(None);
+        let header = second.header();
+        let state = second.inner_ref();
+
+        let pool = PoolKey::mel_and(Denom::Sym);
+        let (pool_state, proof) = state.pools.get(&pool);
+        let bytes = stdcode::serialize(&pool_state.unwrap()).unwrap();
+        let verified = verify_pool(&header, pool, &bytes, &proof).unwrap().unwrap();
+        assert_eq!(verified.lefts, pool_state.unwrap().lefts);
+
+        // create_state keys the stakes by the hash of their index
+        let staking_txhash: TxHash = tmelcrypt::hash_single(0u128.to_be_bytes()).into();
+        let (stake, proof) = state.stakes.get(&staking_txhash);
+        let stake = stake.unwrap();
+        let bytes = stdcode::serialize(&stake).unwrap();
+        let verified = verify_stake(&header, staking_txhash, &bytes, &proof)
+            .unwrap()
+            .unwrap();
+        assert_eq!(verified.pubkey, stake.pubkey);
+        assert_eq!(
+            verify_stake(&header, staking_txhash, &[], &proof).unwrap_err(),
+            ProofError::BadProof
+        );
+
+        let (old, proof) = state.history.get(&BlockHeight(0));
+        let bytes = stdcode::serialize(&old.unwrap()).unwrap();
+        assert_eq!(
+            verify_history(&header, BlockHeight(0), &bytes, &proof),
+            Ok(Some(first.header()))
+        );
+        // a proof for one height cannot vouch for another
+        assert_eq!(
+            verify_history(&header, BlockHeight(1), &bytes, &proof),
+            Err(ProofError::BadProof)
+        );
+    }
+
+    #[test]
+    fn state_proof_bundles() {
+        let (base, split) = split_genesis_state(2);
+        let mut next = base.next_state();
+        let tx = spend_split(&split, 0, 100_000);
+        next.apply_tx(&tx).unwrap();
+        let sealed = next.seal(None);
+
+        let proof = sealed.prove_coin(tx.output_coinid(0));
+        assert!(proof.value.is_some());
+        let decoded: StateProof<CoinID, CoinDataHeight> =
+            stdcode::deserialize(&stdcode::serialize(&proof).unwrap()).unwrap();
+        assert_eq!(decoded, proof);
+        assert_eq!(decoded.verify(), Ok(()));
+
+        // tampering with any part of the bundle is caught
+        let mut tampered = proof.clone();
+        tampered.value = None;
+        assert_eq!(tampered.verify(), Err(ProofError::BadProof));
+        let mut tampered = proof.clone();
+        tampered.key = split.output_coinid(1);
+        assert_eq!(tampered.verify(), Err(ProofError::BadProof));
+        let mut tampered = proof;
+        tampered.header = base.header();
+        assert_eq!(tampered.verify(), Err(ProofError::BadProof));
+
+        let proof = sealed.prove_coin(split.output_coinid(0));
+        assert!(proof.value.is_none());
+        assert_eq!(proof.verify(), Ok(()));
+
+        let proof = sealed.prove_tx(tx.hash_nosigs());
+        assert_eq!(proof.value.as_ref(), Some(&tx));
+        assert_eq!(proof.verify(), Ok(()));
+
+        let proof = sealed.prove_pool(PoolKey::mel_and(Denom::Sym));
+        assert!(proof.value.is_some());
+        assert_eq!(proof.verify(), Ok(()));
+    }
+}
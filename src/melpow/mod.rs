@@ -33,7 +33,7 @@ impl Proof {
         });
 
         node::calc_labels(&chi, difficulty, &mut |nd, lab| {
-            if proof_map.get(&nd).is_some() || nd.len == 0 {
+            if proof_map.contains_key(&nd) || nd.len == 0 {
                 proof_map.insert(nd, SVec::from_slice(lab));
             }
         });
@@ -124,7 +124,7 @@ impl Proof {
     pub fn from_bytes(mut bts: &[u8]) -> Option<Self> {
         let unit_size = 8 + 32;
 
-        if bts.len() % unit_size != 0 {
+        if !bts.len().is_multiple_of(unit_size) {
             None
         } else {
             let mut omap = FxHashMap::default();
@@ -382,7 +382,8 @@ mod tests {
 
     #[test]
     fn check_sig() {
-        let (pk, sk) = tmelcrypt::ed25519_keygen();
+        let sk = tmelcrypt::Ed25519SK::generate();
+        let pk = sk.to_public();
         // (SIGEOK (LOAD 1) (PUSH pk) (VREF (VREF (LOAD 0) 6) 0))
         let check_sig_script = Covenant::from_ops(&[
             OpCode::Loop(5, 8),
@@ -140,7 +140,7 @@ impl Display for OpCode {
             OpCode::ItoB => "itob".fmt(f),
             OpCode::BtoI => "btoi".fmt(f),
             OpCode::TypeQ => "typeq".fmt(f),
-            OpCode::PushB(v) => format!("pushb {}", hex::encode(&v)).fmt(f),
+            OpCode::PushB(v) => format!("pushb {}", hex::encode(v)).fmt(f),
             OpCode::PushI(i) => format!("pushi {}", i).fmt(f),
             OpCode::PushIC(i) => format!("pushic {}", i).fmt(f),
             OpCode::Dup => "dup".fmt(f),
@@ -280,9 +280,7 @@ impl OpCode {
                 let bytes_repr = i.to_be_bytes();
                 let leading_zeros = bytes_repr.iter().take_while(|i| **i == 0).count();
                 output.write_all(&[32 - (leading_zeros as u8)]).unwrap();
-                output
-                    .write_all(&bytes_repr[leading_zeros as usize..])
-                    .unwrap();
+                output.write_all(&bytes_repr[leading_zeros..]).unwrap();
             }
             OpCode::Dup => output.write_all(&[OPCODE_DUP]).unwrap(),
         };
@@ -379,8 +377,8 @@ impl OpCode {
                 if nonzero_len > 32 {
                     return Err(DecodeError::InvalidVarint);
                 }
-                let mut blit = &mut buf[..nonzero_len as usize];
-                input.read_exact(&mut blit)?;
+                let blit = &mut buf[..nonzero_len as usize];
+                input.read_exact(blit)?;
                 if blit.len() > 32 {
                     return Err(DecodeError::InvalidVarint);
                 }
@@ -506,7 +504,7 @@ mod tests {
     use catvec::CatVec;
     use ethnum::{u256, U256};
     use log::LevelFilter;
-    use tmelcrypt::{ed25519_keygen, Ed25519PK, Ed25519SK};
+    use tmelcrypt::{Ed25519PK, Ed25519SK};
 
     #[test]
     fn test_noop() {
@@ -581,7 +579,7 @@ mod tests {
     fn test_addition_with_overflow() {
         let covenant: Covenant = Covenant::from_ops(&[
             OpCode::PushI(1_u8.into()),
-            OpCode::PushI(u256::MAX.into()),
+            OpCode::PushI(u256::MAX),
             OpCode::Add,
             OpCode::PushI(0_u8.into()),
             OpCode::Eql,
@@ -667,7 +665,7 @@ mod tests {
         let maximum_plus_two: u256 = half_maximum + 2;
 
         let covenant: Covenant = Covenant::from_ops(&[
-            OpCode::PushI(maximum_plus_two.into()),
+            OpCode::PushI(maximum_plus_two),
             OpCode::PushI(2_u128.into()),
             OpCode::Mul,
             OpCode::PushI(2_u8.into()),
@@ -900,7 +898,7 @@ mod tests {
         let covenant: Covenant = Covenant::from_ops(&[
             OpCode::PushI(0_u8.into()),
             OpCode::Not,
-            OpCode::PushI(u256::MAX.into()),
+            OpCode::PushI(u256::MAX),
             OpCode::Eql,
         ])
         .expect("Failed to create a Not covenant.");
@@ -1025,9 +1023,9 @@ mod tests {
         let half_max: u256 = u256::MAX / 2;
         let covenant: Covenant = Covenant::from_ops(&[
             OpCode::PushI(1_u8.into()),
-            OpCode::PushI(half_max.into()),
+            OpCode::PushI(half_max),
             OpCode::Shl,
-            OpCode::PushI((u256::MAX - 1).into()),
+            OpCode::PushI(u256::MAX - 1),
             OpCode::Eql,
         ])
         .expect("Failed to create a Shl covenant.");
@@ -1038,9 +1036,9 @@ mod tests {
         let half_max: u256 = u256::MAX / 2;
         let covenant: Covenant = Covenant::from_ops(&[
             OpCode::PushI(3_u8.into()),
-            OpCode::PushI(half_max.into()),
+            OpCode::PushI(half_max),
             OpCode::Shl,
-            OpCode::PushI((u256::MAX - 1).into()),
+            OpCode::PushI(u256::MAX - 1),
             OpCode::Eql,
         ])
         .expect("Failed to create a Shl covenant.");
@@ -1117,7 +1115,8 @@ mod tests {
 
     #[test]
     fn test_sigeok() {
-        let (public_key, secret_key): (Ed25519PK, Ed25519SK) = ed25519_keygen();
+        let secret_key: Ed25519SK = Ed25519SK::generate();
+        let public_key: Ed25519PK = secret_key.to_public();
 
         let message_byte_vector: Vec<u8> = vec![3];
 
@@ -1747,7 +1746,7 @@ mod tests {
         let covenant: Covenant = Covenant::from_ops(&[
             OpCode::PushB(array.to_vec()),
             OpCode::BtoI,
-            OpCode::PushI(number.into()),
+            OpCode::PushI(number),
             OpCode::Eql,
         ])
         .expect("Failed to create a BtoI covenant.");
@@ -1778,7 +1777,7 @@ mod tests {
         )
         .expect("could not create a U256 from a str.");
 
-        let covenant: Covenant = Covenant::from_ops(&[OpCode::PushI(number.into()), OpCode::ItoB])
+        let covenant: Covenant = Covenant::from_ops(&[OpCode::PushI(number), OpCode::ItoB])
             .expect("Failed to create a ItoB covenant.");
         let (stack, _heap): (Vec<Value>, HashMap<u16, Value>) = covenant
             .debug_run_outputting_stack_and_heap(&[])
@@ -0,0 +1,327 @@
+use crate::{CoinValue, SealedState, State, StateError, Transaction, TxHash};
+
+use std::collections::HashSet;
+
+use novasmt::ContentAddrStore;
+
+/// A pool of unconfirmed transactions, kept as a speculative [State] on top of the last [SealedState].
+///
+/// Transactions are validated as they come in, exactly like [State::apply_tx] would. When the pool grows past its weight cap, the transactions paying the lowest tip per weight unit are evicted, together with everything that spends their outputs.
+#[derive(Debug)]
+pub struct Mempool<C: ContentAddrStore> {
+    base: SealedState<C>,
+    provisional: State<C>,
+    txx: Vec<Transaction>,
+    weight: u128,
+    max_weight: u128,
+}
+
+impl<C: ContentAddrStore> Mempool<C> {
+    /// Creates an empty mempool on top of the given sealed state, holding at most `max_weight` weight units of transactions.
+    pub fn new(base: &SealedState<C>, max_weight: u128) -> Self {
+        Self {
+            base: base.clone(),
+            provisional: base.next_state(),
+            txx: Vec::new(),
+            weight: 0,
+            max_weight,
+        }
+    }
+
+    /// Returns the speculative state, with every transaction in the mempool applied.
+    pub fn provisional_state(&self) -> &State<C> {
+        &self.provisional
+    }
+
+    /// Returns the transactions in the mempool, in an order in which they can be applied.
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.txx
+    }
+
+    /// Returns the transactions in the mempool, from the highest to the lowest tip per weight unit.
+    pub fn by_tip_rate(&self) -> Vec<&Transaction> {
+        let mut sorted = self.txx.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|tx| std::cmp::Reverse(self.tip_rate(tx)));
+        sorted
+    }
+
+    /// Looks up a transaction in the mempool by its hash.
+    pub fn lookup(&self, txhash: TxHash) -> Option<Transaction> {
+        self.provisional.transactions.get(&txhash).0
+    }
+
+    /// Returns the total weight of the transactions in the mempool.
+    pub fn weight(&self) -> u128 {
+        self.weight
+    }
+
+    /// Returns the number of transactions in the mempool.
+    pub fn len(&self) -> usize {
+        self.txx.len()
+    }
+
+    /// Returns true iff the mempool has no transactions.
+    pub fn is_empty(&self) -> bool {
+        self.txx.is_empty()
+    }
+
+    /// Tip per weight unit paid by a transaction, scaled like the fee multiplier.
+    pub fn tip_rate(&self, tx: &Transaction) -> u128 {
+        let tip = tx
+            .fee
+            .0
+            .saturating_sub(tx.base_fee(self.provisional.fee_multiplier, 0).0);
+        tip.saturating_mul(1 << 16) / tx.weight().max(1)
+    }
+
+    /// Adds a transaction to the mempool. Invalid and conflicting transactions are rejected with the error [State::apply_tx] gives.
+    ///
+    /// If the mempool is then over its weight cap, the cheapest transactions are evicted; if this would evict the new transaction itself, it is rejected with [StateError::InsufficientFees] and the mempool is left unchanged.
+    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), StateError> {
+        if self.lookup(tx.hash_nosigs()).is_some() {
+            return Err(StateError::DuplicateTx);
+        }
+        let mut provisional = self.provisional.clone();
+        provisional.apply_tx(tx)?;
+
+        let mut candidates = self.txx.clone();
+        candidates.push(tx.clone());
+        let mut weight = self.weight.saturating_add(tx.weight());
+        let mut evicted: HashSet<TxHash> = HashSet::new();
+        while weight > self.max_weight {
+            // evict the cheapest, preferring the most recent one among equals
+            let (cheapest, _) = candidates
+                .iter()
+                .enumerate()
+                .min_by_key(|(idx, tx)| (self.tip_rate(tx), std::cmp::Reverse(*idx)))
+                .expect("overweight mempool cannot be empty");
+            let newly_evicted = evict_with_dependents(&mut candidates, cheapest);
+            weight -= newly_evicted.iter().map(|tx| tx.weight()).sum::<u128>();
+            evicted.extend(newly_evicted.iter().map(|tx| tx.hash_nosigs()));
+        }
+        if evicted.contains(&tx.hash_nosigs()) {
+            let cheapest_rate = candidates
+                .iter()
+                .map(|tx| self.tip_rate(tx))
+                .min()
+                .unwrap_or_default();
+            let required = tx.base_fee(self.provisional.fee_multiplier, 0).0
+                + (cheapest_rate + 1).saturating_mul(tx.weight()) / (1 << 16)
+                + 1;
+            return Err(StateError::InsufficientFees(CoinValue(required)));
+        }
+
+        if evicted.is_empty() {
+            self.provisional = provisional;
+            self.txx = candidates;
+            self.weight = weight;
+        } else {
+            log::debug!("evicting {} transactions from mempool", evicted.len());
+            self.txx = candidates;
+            self.rebuild();
+        }
+        Ok(())
+    }
+
+    /// Moves the mempool on top of a newly sealed state. Transactions that are no longer valid, such as those included in the new block, are dropped.
+    pub fn rebase(&mut self, new_base: &SealedState<C>) {
+        self.base = new_base.clone();
+        self.rebuild();
+    }
+
+    /// Rebuilds the speculative state from the base, applying the transactions one by one and dropping those that fail to apply, together with everything that spends their outputs.
+    fn rebuild(&mut self) {
+        let mut provisional = self.base.next_state();
+        let mut dropped: HashSet<TxHash> = HashSet::new();
+        self.txx.retain(|tx| {
+            let txhash = tx.hash_nosigs();
+            if let Some(input) = tx
+                .inputs
+                .iter()
+                .find(|input| dropped.contains(&input.txhash))
+            {
+                log::debug!(
+                    "dropping {} from mempool: spends dropped {}",
+                    txhash,
+                    input.txhash
+                );
+            } else if let Err(err) = provisional.apply_tx(tx) {
+                log::debug!("dropping {} from mempool: {}", txhash, err);
+            } else {
+                return true;
+            }
+            dropped.insert(txhash);
+            false
+        });
+        self.provisional = provisional;
+        self.weight = self.txx.iter().map(|tx| tx.weight()).sum();
+    }
+}
+
+/// Removes the transaction at the given index, and every later transaction that transitively spends its outputs, returning the removed transactions.
+fn evict_with_dependents(txx: &mut Vec<Transaction>, idx: usize) -> Vec<Transaction> {
+    let mut removed_hashes: HashSet<TxHash> = HashSet::new();
+    let mut removed = Vec::new();
+    let mut current = 0;
+    txx.retain(|tx| {
+        let evict = current == idx
+            || (current > idx
+                && tx
+                    .inputs
+                    .iter()
+                    .any(|input| removed_hashes.contains(&input.txhash)));
+        current += 1;
+        if evict {
+            removed_hashes.insert(tx.hash_nosigs());
+            removed.push(tx.clone());
+        }
+        !evict
+    });
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use novasmt::InMemoryCas;
+
+    use crate::testing::functions::{always_true_coin, spend_split, split_genesis_state};
+    use crate::{melvm::Covenant, SealedState, StateError, Transaction, TxKind};
+
+    use super::Mempool;
+
+    const SPLIT_COUNT: u8 = 10;
+
+    fn split_state() -> (SealedState<InMemoryCas>, Transaction) {
+        split_genesis_state(SPLIT_COUNT)
+    }
+
+    #[test]
+    fn accepts_and_rejects() {
+        let (base, split) = split_state();
+        let mut mempool = Mempool::new(&base, u128::MAX);
+        let tx = spend_split(&split, 0, 100_000);
+        mempool.apply_transaction(&tx).unwrap();
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool
+            .provisional_state()
+            .coins
+            .get(&tx.output_coinid(0))
+            .0
+            .is_some());
+
+        assert!(matches!(
+            mempool.apply_transaction(&tx),
+            Err(StateError::DuplicateTx)
+        ));
+        assert!(matches!(
+            mempool.apply_transaction(&spend_split(&split, 0, 200_000)),
+            Err(StateError::NonexistentCoin(_))
+        ));
+        assert!(matches!(
+            mempool.apply_transaction(&spend_split(&split, 1, 0)),
+            Err(StateError::InsufficientFees(_))
+        ));
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn evicts_cheapest() {
+        let (base, split) = split_state();
+        let txx = (0..SPLIT_COUNT)
+            .map(|i| spend_split(&split, i, 100_000 + 10_000 * i as u128))
+            .collect::<Vec<_>>();
+        let max_weight = txx[..5].iter().map(|tx| tx.weight()).sum();
+        let mut mempool = Mempool::new(&base, max_weight);
+        for tx in txx.iter() {
+            mempool.apply_transaction(tx).unwrap();
+        }
+        // only the five best-paying ones are left, best first
+        assert_eq!(mempool.len(), 5);
+        assert!(mempool.weight() <= max_weight);
+        let kept = mempool
+            .by_tip_rate()
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(kept, txx[5..].iter().rev().cloned().collect::<Vec<_>>());
+        // the evicted coins are unspent again in the speculative state
+        assert!(mempool
+            .provisional_state()
+            .coins
+            .get(&split.output_coinid(0))
+            .0
+            .is_some());
+
+        // a transaction that pays too little is rejected
+        let cheap = txx[0].clone();
+        assert!(matches!(
+            mempool.apply_transaction(&cheap),
+            Err(StateError::InsufficientFees(_))
+        ));
+        assert_eq!(mempool.len(), 5);
+    }
+
+    #[test]
+    fn evicts_dependents() {
+        let (base, split) = split_state();
+        let parent = spend_split(&split, 0, 100_000);
+        let child = Transaction::new(TxKind::Normal)
+            .add_input(parent.output_coinid(0))
+            .add_output(always_true_coin((1 << 30) - 300_000))
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true());
+        let rich = spend_split(&split, 1, 1_000_000);
+        let mut mempool = Mempool::new(&base, parent.weight() + child.weight());
+        mempool.apply_transaction(&parent).unwrap();
+        mempool.apply_transaction(&child).unwrap();
+        mempool.apply_transaction(&rich).unwrap();
+        assert_eq!(mempool.transactions(), &[rich]);
+    }
+
+    #[test]
+    fn rebase_drops_included() {
+        let (base, split) = split_state();
+        let txx = (0..SPLIT_COUNT)
+            .map(|i| spend_split(&split, i, 100_000))
+            .collect::<Vec<_>>();
+        let mut mempool = Mempool::new(&base, u128::MAX);
+        for tx in txx.iter() {
+            mempool.apply_transaction(tx).unwrap();
+        }
+        let mut next = base.next_state();
+        next.apply_tx_batch(&txx[..3]).unwrap();
+        let next = next.seal(None);
+
+        mempool.rebase(&next);
+        assert_eq!(mempool.transactions(), &txx[3..]);
+        assert_eq!(
+            mempool.provisional_state().height,
+            next.inner_ref().height + 1.into()
+        );
+    }
+
+    #[test]
+    fn rebase_drops_conflicts_and_dependents() {
+        let (base, split) = split_state();
+        let parent = spend_split(&split, 0, 100_000);
+        let child = Transaction::new(TxKind::Normal)
+            .add_input(parent.output_coinid(0))
+            .add_output(always_true_coin((1 << 30) - 300_000))
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true());
+        let other = spend_split(&split, 1, 100_000);
+        let mut mempool = Mempool::new(&base, u128::MAX);
+        for tx in [&parent, &child, &other] {
+            mempool.apply_transaction(tx).unwrap();
+        }
+        // the block spends the parent's input differently
+        let mut next = base.next_state();
+        next.apply_tx(&spend_split(&split, 0, 200_000)).unwrap();
+        let next = next.seal(None);
+
+        mempool.rebase(&next);
+        assert_eq!(mempool.transactions(), std::slice::from_ref(&other));
+        assert_eq!(mempool.weight(), other.weight());
+        assert!(mempool.lookup(child.hash_nosigs()).is_none());
+    }
+}
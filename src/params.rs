@@ -0,0 +1,322 @@
+use crate::{
+    BlockHeight, CoinValue, NetID, MAX_BLOCK_WEIGHT, MAX_COINVAL, MICRO_CONVERTER, STAKE_EPOCH,
+    TIP_901_HEIGHT, TIP_902_HEIGHT, TIP_903_HEIGHT,
+};
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use thiserror::Error;
+
+/// A Themelio Improvement Proposal that changes consensus rules from some height on. The discriminant is the number of the TIP.
+#[derive(
+    Clone,
+    Copy,
+    IntoPrimitive,
+    TryFromPrimitive,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Debug,
+    Serialize_repr,
+    Deserialize_repr,
+    Hash,
+)]
+#[repr(u16)]
+pub enum Tip {
+    Tip901 = 901,
+    Tip902 = 902,
+    Tip903 = 903,
+}
+
+/// Every TIP and what it changes, in order of activation on mainnet. A new TIP is added here and to [ChainParams].
+const REGISTRY: &[(Tip, &str)] = &[
+    (Tip::Tip901, "change fee multiplier calculation"),
+    (
+        Tip::Tip902,
+        "introduce non-MEL/non-MEL pools and peg against the nomDOSC/SYM pool",
+    ),
+    (Tip::Tip903, "limit the total weight of a block"),
+];
+
+impl Tip {
+    /// Returns every TIP, in order of activation on mainnet.
+    pub fn all() -> impl Iterator<Item = Tip> {
+        REGISTRY.iter().map(|(tip, _)| *tip)
+    }
+
+    /// Returns the number of the TIP.
+    pub fn number(self) -> u16 {
+        self.into()
+    }
+
+    /// Returns a short description of what the TIP changes.
+    pub fn description(self) -> &'static str {
+        REGISTRY
+            .iter()
+            .find(|(tip, _)| *tip == self)
+            .map(|(_, description)| *description)
+            .expect("TIP missing from the registry")
+    }
+
+    /// Returns the height at which the TIP activates on the given network, by default.
+    pub fn activation_height(self, network: NetID) -> BlockHeight {
+        ChainParams::for_network(network).activation_height(self)
+    }
+}
+
+/// Consensus parameters of a network: when each TIP activates, and the limits and rates that the state transition function would otherwise hard-code.
+///
+/// Mainnet and testnet always use [ChainParams::mainnet]. Custom networks default to [ChainParams::custom], but can override any parameter through [crate::GenesisConfig], so that upgrades can be tested at low heights.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChainParams {
+    /// Height at which TIP 901 (fee multiplier calculation) activates.
+    pub tip_901_height: BlockHeight,
+    /// Height at which TIP 902 (non-MEL/non-MEL pools) activates.
+    pub tip_902_height: BlockHeight,
+    /// Height at which TIP 903 (block weight limit) activates.
+    pub tip_903_height: BlockHeight,
+    /// Maximum total weight of the transactions in a block, once TIP 903 applies.
+    pub max_block_weight: u128,
+    /// Length of a stake epoch, in blocks.
+    pub stake_epoch: u64,
+    /// Maximum value of any output or fee.
+    pub max_coinval: CoinValue,
+    /// Divisor applied to the melmint peg correction of every block before TIP 902.
+    pub pegging_throttler: u128,
+    /// Divisor applied to the melmint peg correction of every block once TIP 902 applies.
+    pub pegging_throttler_tip_902: u128,
+    /// Fee of every swap, in millionths of the swapped amount.
+    pub swap_fee_micro: u128,
+    /// Height below which staking transactions are let through without validation, as they were by the original buggy rules.
+    pub old_staking_rules_until: BlockHeight,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+/// A error that happens while validating [ChainParams]
+pub enum ParamsError {
+    #[error("swap fee of {0} millionths is more than the whole swap")]
+    SwapFeeTooHigh(u128),
+    #[error("stake epochs must be at least one block long")]
+    ZeroStakeEpoch,
+    #[error("pegging throttlers must be nonzero")]
+    ZeroPeggingThrottler,
+    #[error("{0:?} cannot override its default parameters")]
+    NotDefault(NetID),
+}
+
+impl ChainParams {
+    /// The parameters of mainnet and testnet.
+    pub fn mainnet() -> Self {
+        Self {
+            tip_901_height: TIP_901_HEIGHT,
+            tip_902_height: TIP_902_HEIGHT,
+            tip_903_height: TIP_903_HEIGHT,
+            max_block_weight: MAX_BLOCK_WEIGHT,
+            stake_epoch: STAKE_EPOCH,
+            max_coinval: MAX_COINVAL,
+            pegging_throttler: 1000,
+            pegging_throttler_tip_902: 200,
+            swap_fee_micro: 5000,
+            old_staking_rules_until: BlockHeight(500000),
+        }
+    }
+
+    /// The default parameters of custom networks: the same as mainnet, except that TIPs 901 and 902 apply from genesis and staking transactions are always validated.
+    ///
+    /// TIP 903 never applies unless a network opts in through [crate::GenesisConfig::params], since a weight limit would retroactively invalidate the heavier blocks of existing custom chains.
+    pub fn custom() -> Self {
+        Self {
+            tip_901_height: BlockHeight(0),
+            tip_902_height: BlockHeight(0),
+            tip_903_height: BlockHeight(u64::MAX),
+            old_staking_rules_until: BlockHeight(0),
+            ..Self::mainnet()
+        }
+    }
+
+    /// Returns the height at which the given TIP activates.
+    pub fn activation_height(&self, tip: Tip) -> BlockHeight {
+        match tip {
+            Tip::Tip901 => self.tip_901_height,
+            Tip::Tip902 => self.tip_902_height,
+            Tip::Tip903 => self.tip_903_height,
+        }
+    }
+
+    /// Sets the height at which the given TIP activates.
+    pub fn with_activation_height(mut self, tip: Tip, height: BlockHeight) -> Self {
+        match tip {
+            Tip::Tip901 => self.tip_901_height = height,
+            Tip::Tip902 => self.tip_902_height = height,
+            Tip::Tip903 => self.tip_903_height = height,
+        }
+        self
+    }
+
+    /// Returns true iff the given TIP applies at the given height.
+    pub fn is_active(&self, tip: Tip, height: BlockHeight) -> bool {
+        height >= self.activation_height(tip)
+    }
+
+    /// Lists the TIPs that apply at the given height.
+    pub fn active_tips(&self, height: BlockHeight) -> Vec<Tip> {
+        Tip::all()
+            .filter(|tip| self.is_active(*tip, height))
+            .collect()
+    }
+
+    /// Checks that the parameters can be used on the given network: mainnet and testnet must use their defaults, and no parameter may make the state transition function divide by zero or underflow.
+    pub fn validate(&self, network: NetID) -> Result<(), ParamsError> {
+        if matches!(network, NetID::Mainnet | NetID::Testnet) && *self != Self::for_network(network)
+        {
+            return Err(ParamsError::NotDefault(network));
+        }
+        if self.swap_fee_micro > MICRO_CONVERTER {
+            return Err(ParamsError::SwapFeeTooHigh(self.swap_fee_micro));
+        }
+        if self.stake_epoch == 0 {
+            return Err(ParamsError::ZeroStakeEpoch);
+        }
+        if self.pegging_throttler == 0 || self.pegging_throttler_tip_902 == 0 {
+            return Err(ParamsError::ZeroPeggingThrottler);
+        }
+        Ok(())
+    }
+
+    /// Returns the default parameters of the given network.
+    pub fn for_network(network: NetID) -> Self {
+        match network {
+            NetID::Mainnet | NetID::Testnet => Self::mainnet(),
+            _ => Self::custom(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use novasmt::InMemoryCas;
+
+    use crate::melvm::Covenant;
+    use crate::testing::functions::{spend_split, split_custom_genesis_state};
+    use crate::{
+        BlockHeight, ChainParams, Denom, NetID, PoolKey, ProposerAction, SealedState, StateError,
+        Tip, Transaction, TIP_902_HEIGHT, TIP_903_HEIGHT,
+    };
+
+    /// Height at which the TIP under test activates.
+    const BOUNDARY: BlockHeight = BlockHeight(3);
+
+    /// Returns the parents of the last block before the given TIP activates and of the first block after, together with transactions that are valid on top of both.
+    fn around_activation(
+        tip: Tip,
+        params: ChainParams,
+    ) -> (
+        SealedState<InMemoryCas>,
+        SealedState<InMemoryCas>,
+        Vec<Transaction>,
+    ) {
+        let (before, split) =
+            split_custom_genesis_state(2, params.with_activation_height(tip, BOUNDARY));
+        let after = before.next_state().seal(None);
+        assert!(!before.next_state().is_active(tip));
+        assert!(after.next_state().is_active(tip));
+        assert_eq!(after.next_state().height, BOUNDARY);
+        let txx = vec![
+            spend_split(&split, 0, 100_000),
+            spend_split(&split, 1, 100_000),
+        ];
+        (before, after, txx)
+    }
+
+    #[test]
+    fn registry() {
+        let numbers = Tip::all().map(Tip::number).collect::<Vec<_>>();
+        assert_eq!(numbers, vec![901, 902, 903]);
+        for tip in Tip::all() {
+            assert_eq!(Tip::try_from(tip.number()).unwrap(), tip);
+            assert!(!tip.description().is_empty());
+        }
+        assert_eq!(
+            Tip::Tip902.activation_height(NetID::Custom03),
+            BlockHeight(0)
+        );
+        assert_eq!(
+            Tip::Tip903.activation_height(NetID::Custom03),
+            BlockHeight(u64::MAX)
+        );
+        assert_eq!(
+            Tip::Tip903.activation_height(NetID::Testnet),
+            TIP_903_HEIGHT
+        );
+
+        let mainnet = ChainParams::mainnet();
+        assert!(mainnet.active_tips(BlockHeight(0)).is_empty());
+        assert_eq!(
+            mainnet.active_tips(TIP_902_HEIGHT - BlockHeight(1)),
+            vec![Tip::Tip901]
+        );
+        assert_eq!(
+            mainnet.active_tips(TIP_902_HEIGHT),
+            vec![Tip::Tip901, Tip::Tip902]
+        );
+        assert_eq!(
+            ChainParams::custom().active_tips(BlockHeight(0)),
+            vec![Tip::Tip901, Tip::Tip902]
+        );
+    }
+
+    #[test]
+    fn tip_901_boundary() {
+        let (before, after, txx) = around_activation(Tip::Tip901, ChainParams::custom());
+        let fee_multiplier_after = |parent: &SealedState<InMemoryCas>| {
+            let mut next = parent.next_state();
+            next.fee_multiplier = 100;
+            next.apply_tx_batch(&txx).unwrap();
+            next.seal(Some(ProposerAction {
+                fee_multiplier_delta: 127,
+                reward_dest: Covenant::always_true().hash(),
+            }))
+            .inner_ref()
+            .fee_multiplier
+        };
+        // a fee multiplier this small only moves once there is a minimum movement
+        assert_eq!(fee_multiplier_after(&before), 100);
+        assert_eq!(fee_multiplier_after(&after), 101);
+    }
+
+    #[test]
+    fn tip_902_boundary() {
+        let (before, after, txx) = around_activation(Tip::Tip902, ChainParams::custom());
+        let pool = PoolKey::new(Denom::NomDosc, Denom::Sym);
+        let has_pool = |parent: &SealedState<InMemoryCas>| {
+            let mut next = parent.next_state();
+            next.apply_tx_batch(&txx).unwrap();
+            next.seal(None).inner_ref().pools.get(&pool).0.is_some()
+        };
+        assert!(!has_pool(&before));
+        assert!(has_pool(&after));
+    }
+
+    #[test]
+    fn tip_903_boundary() {
+        let params = ChainParams {
+            max_block_weight: 1,
+            ..ChainParams::custom()
+        };
+        let (before, after, txx) = around_activation(Tip::Tip903, params);
+        let apply = |parent: &SealedState<InMemoryCas>| {
+            let mut next = parent.next_state();
+            next.apply_tx_batch(&txx).unwrap();
+            parent.apply_block(&next.seal(None).to_block())
+        };
+        assert!(apply(&before).is_ok());
+        assert!(matches!(
+            apply(&after),
+            Err(StateError::BlockTooHeavy { max: 1, .. })
+        ));
+    }
+}
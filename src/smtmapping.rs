@@ -1,3 +1,5 @@
+use crate::DecodeError;
+
 use novasmt::{ContentAddrStore, FullProof};
 use serde::{de::DeserializeOwned, Serialize};
 use std::fmt::Debug;
@@ -7,6 +9,7 @@ use tmelcrypt::HashVal;
 /// SmtMapping is a type-safe, constant-time cloneable, imperative-style interface to a sparse Merkle tree.
 pub struct SmtMapping<C: ContentAddrStore, K: Serialize, V: Serialize + DeserializeOwned> {
     pub mapping: novasmt::Tree<C>,
+    record_keys: bool,
     _phantom_k: PhantomData<K>,
     _phantom_v: PhantomData<V>,
 }
@@ -23,7 +26,9 @@ impl<C: ContentAddrStore, K: Serialize, V: Serialize + DeserializeOwned> Clone
     for SmtMapping<C, K, V>
 {
     fn clone(&self) -> Self {
-        SmtMapping::new(self.mapping.clone())
+        let mut new = SmtMapping::new(self.mapping.clone());
+        new.record_keys = self.record_keys;
+        new
     }
 }
 
@@ -41,31 +46,48 @@ impl<C: ContentAddrStore, K: Serialize, V: Serialize + DeserializeOwned> SmtMapp
     pub fn new(tree: novasmt::Tree<C>) -> Self {
         SmtMapping {
             mapping: tree,
+            record_keys: false,
             _phantom_k: PhantomData,
             _phantom_v: PhantomData,
         }
     }
+    /// Opens the tree with the given root in a database, checking that it exists.
+    pub fn from_root(db: &novasmt::Database<C>, root: HashVal) -> Result<Self, DecodeError> {
+        if root != HashVal::default() && db.storage().get(&root).is_none() {
+            return Err(DecodeError::MissingTree(root));
+        }
+        Ok(SmtMapping::new(
+            db.get_tree(root.0).ok_or(DecodeError::MissingTree(root))?,
+        ))
+    }
     /// get obtains a mapping
     pub fn get(&self, key: &K) -> (Option<V>, FullProof) {
-        let key = tmelcrypt::hash_single(&stdcode::serialize(key).unwrap());
+        self.try_get(key).expect("SmtMapping saw invalid data")
+    }
+    /// try_get obtains a mapping, failing if the stored value cannot be decoded
+    pub fn try_get(&self, key: &K) -> Result<(Option<V>, FullProof), DecodeError> {
+        let key = tmelcrypt::hash_single(stdcode::serialize(key).unwrap());
         let (v_bytes, proof) = self.mapping.get_with_proof(key.0);
         match v_bytes.len() {
-            0 => (None, proof),
+            0 => Ok((None, proof)),
             _ => {
-                let res: V = stdcode::deserialize(&v_bytes).expect("SmtMapping saw invalid data");
-                (Some(res), proof)
+                let res: V = stdcode::deserialize(&v_bytes).map_err(|_| DecodeError::BadValue)?;
+                Ok((Some(res), proof))
             }
         }
     }
     /// insert inserts a mapping, replacing any existing mapping
     pub fn insert(&mut self, key: K, val: V) {
-        let key = tmelcrypt::hash_single(&stdcode::serialize(&key).unwrap());
+        if self.record_keys {
+            self.record_key(&key);
+        }
+        let key = tmelcrypt::hash_single(stdcode::serialize(&key).unwrap());
         self.mapping
             .insert(key.0, &stdcode::serialize(&val).unwrap());
     }
     /// delete deletes a mapping, replacing the mapping with a mapping to the empty bytestring
     pub fn delete(&mut self, key: &K) {
-        let key = tmelcrypt::hash_single(&stdcode::serialize(key).unwrap());
+        let key = tmelcrypt::hash_single(stdcode::serialize(key).unwrap());
         self.mapping.insert(key.0, Default::default());
     }
     /// root_hash returns the root hash
@@ -78,4 +100,55 @@ impl<C: ContentAddrStore, K: Serialize, V: Serialize + DeserializeOwned> SmtMapp
             .iter()
             .map(|(_, v)| stdcode::deserialize::<V>(&v).unwrap())
     }
+    /// try_val_iter returns an iterator over the values, yielding an error for every value that cannot be decoded
+    pub fn try_val_iter(&'_ self) -> impl Iterator<Item = Result<V, DecodeError>> + '_ {
+        self.mapping
+            .iter()
+            .map(|(_, v)| stdcode::deserialize::<V>(&v).map_err(|_| DecodeError::BadValue))
+    }
+
+    /// Turns on or off recording the preimages of keys as they are inserted, which is needed for [SmtMapping::iter]. The preimages are stored in the underlying [ContentAddrStore], beside the tree, and do not affect the root hash. Recording is kept across clones.
+    pub fn set_record_keys(&mut self, record: bool) {
+        self.record_keys = record;
+    }
+
+    /// Returns whether key preimages are recorded on insertion.
+    pub fn records_keys(&self) -> bool {
+        self.record_keys
+    }
+
+    /// Records the preimage of a key, without touching the mapping itself. Useful for keys inserted before recording was turned on.
+    pub fn record_key(&self, key: &K) {
+        let preimage = stdcode::serialize(key).unwrap();
+        let hashed = tmelcrypt::hash_single(&preimage);
+        self.mapping
+            .storage()
+            .insert(&preimage_location(hashed), &preimage);
+    }
+}
+
+impl<C: ContentAddrStore, K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned>
+    SmtMapping<C, K, V>
+{
+    /// Finds the key whose hash is the given one, if its preimage was recorded.
+    pub fn key_preimage(&self, hashed: HashVal) -> Option<K> {
+        let preimage = self.mapping.storage().get(&preimage_location(hashed))?;
+        stdcode::deserialize(&preimage).ok()
+    }
+
+    /// iter returns an iterator over the keys and values, yielding an error for every key whose preimage was never recorded (see [SmtMapping::set_record_keys]) and every value that cannot be decoded
+    pub fn iter(&'_ self) -> impl Iterator<Item = Result<(K, V), DecodeError>> + '_ {
+        self.mapping.iter().map(move |(k, v)| {
+            let key = self
+                .key_preimage(HashVal(k))
+                .ok_or(DecodeError::MissingKeyPreimage(HashVal(k)))?;
+            let value = stdcode::deserialize::<V>(&v).map_err(|_| DecodeError::BadValue)?;
+            Ok((key, value))
+        })
+    }
+}
+
+/// Where the preimage of a hashed key is stored in the content-addressed store. Keyed hashing keeps it apart from the tree nodes, which are stored under plain hashes.
+fn preimage_location(hashed: HashVal) -> HashVal {
+    tmelcrypt::hash_keyed(b"smt_key_preimage", hashed)
 }
@@ -1,12 +1,14 @@
 #![allow(clippy::float_cmp)]
 
 use crate::{CoinValue, SmtMapping, TxHash};
+use std::collections::BTreeMap;
+
 use novasmt::ContentAddrStore;
 use serde::{Deserialize, Serialize};
 use tmelcrypt::Ed25519PK;
 
 /// StakeDoc is a stake document. It encapsulates all the information needed to verify consensus proofs.
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct StakeDoc {
     /// Public key.
     pub pubkey: Ed25519PK,
@@ -39,10 +41,29 @@ impl<C: ContentAddrStore> StakeMapping<C> {
         target_votes / total_votes
     }
 
+    /// Gets the number of syms staked by every public key that has stake effective in the given epoch.
+    pub fn stakers(&self, epoch: u64) -> BTreeMap<Ed25519PK, CoinValue> {
+        let mut stakers: BTreeMap<Ed25519PK, CoinValue> = BTreeMap::new();
+        self.val_iter().for_each(|sdoc| {
+            if epoch >= sdoc.e_start && epoch < sdoc.e_post_end {
+                let votes = stakers.entry(sdoc.pubkey).or_default();
+                votes.0 = votes.0.saturating_add(sdoc.syms_staked.0);
+            }
+        });
+        stakers
+    }
+
     /// Filter out all the elements that no longer matter.
     pub fn remove_stale(&mut self, epoch: u64) {
-        let stale_key_hashes: Vec<[u8; 32]> = self
-            .mapping
+        let stale_key_hashes = self.stale_key_hashes(epoch);
+        stale_key_hashes.iter().for_each(|stale_key| {
+            self.mapping.insert(*stale_key, Default::default());
+        });
+    }
+
+    /// Returns the hashed keys of the elements that [StakeMapping::remove_stale] would remove.
+    pub(crate) fn stale_key_hashes(&self, epoch: u64) -> Vec<[u8; 32]> {
+        self.mapping
             .iter()
             .filter_map(|(kh, v)| {
                 let v: StakeDoc = stdcode::deserialize(&v).unwrap();
@@ -52,15 +73,12 @@ impl<C: ContentAddrStore> StakeMapping<C> {
                     None
                 }
             })
-            .collect::<Vec<[u8; 32]>>();
-
-        stale_key_hashes.iter().for_each(|stale_key| {
-            self.mapping.insert(*stale_key, Default::default());
-        });
+            .collect::<Vec<[u8; 32]>>()
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::vec_init_then_push)]
 mod tests {
     use crate::testing::functions::create_state;
     use crate::CoinValue;
@@ -78,13 +96,13 @@ mod tests {
             // Generate genesis block for stakers
             // let staked_syms =vec![100 as u64; 3];
             let stakers = staked_syms
-                .into_iter()
-                .map(|e| (tmelcrypt::ed25519_keygen().1, CoinValue(*e)))
+                .iter()
+                .map(|e| (tmelcrypt::Ed25519SK::generate(), CoinValue(*e)))
                 .collect();
             let genesis = create_state(&stakers, 0);
 
             // call vote_power for a key pair who is not a staker
-            let (pk, _sk) = tmelcrypt::ed25519_keygen();
+            let pk = tmelcrypt::Ed25519SK::generate().to_public();
             let vote_power = genesis.stakes.vote_power(0, pk);
 
             // assert they have no vote power
@@ -103,8 +121,8 @@ mod tests {
             // Generate state for stakers
             let total_staked_syms: u128 = staked_syms.iter().sum();
             let stakers = staked_syms
-                .into_iter()
-                .map(|e| (tmelcrypt::ed25519_keygen().1, CoinValue(*e)))
+                .iter()
+                .map(|e| (tmelcrypt::Ed25519SK::generate(), CoinValue(*e)))
                 .collect();
             let state = create_state(&stakers, 0);
 
@@ -126,7 +144,7 @@ mod tests {
             let staked_syms = vec![100u128; 3];
             let stakers = staked_syms
                 .into_iter()
-                .map(|e| (tmelcrypt::ed25519_keygen().1, CoinValue(e)))
+                .map(|e| (tmelcrypt::Ed25519SK::generate(), CoinValue(e)))
                 .collect();
             let state = create_state(&stakers, epoch_start);
 
@@ -156,7 +174,8 @@ mod tests {
 
         staked_sym_group.iter().for_each(|staked_sym| {
             // Add in a single staker to get a state at epoch 0
-            let (pk, sk) = tmelcrypt::ed25519_keygen();
+            let sk = tmelcrypt::Ed25519SK::generate();
+            let pk = sk.to_public();
             let mut stakers = HashMap::new();
             stakers.insert(sk, CoinValue(*staked_sym));
             let state = create_state(&stakers, 0);
@@ -177,7 +196,7 @@ mod tests {
 
             let voting_power = state
                 .stakes
-                .vote_power(epoch, tmelcrypt::ed25519_keygen().0);
+                .vote_power(epoch, tmelcrypt::Ed25519SK::generate().to_public());
             assert_eq!(voting_power, 0.0);
         });
     }
@@ -192,8 +211,8 @@ mod tests {
         staked_sym_group.iter().for_each(|staked_syms| {
             // Generate state for stakers
             let stakers = staked_syms
-                .into_iter()
-                .map(|e| (tmelcrypt::ed25519_keygen().1, CoinValue(*e)))
+                .iter()
+                .map(|e| (tmelcrypt::Ed25519SK::generate(), CoinValue(*e)))
                 .collect();
             let state = create_state(&stakers, 0);
 
@@ -205,6 +224,23 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_stakers_sums_per_key() {
+        let staked_syms: Vec<u128> = vec![100, 200, 300];
+        let stakers = staked_syms
+            .into_iter()
+            .map(|e| (tmelcrypt::Ed25519SK::generate(), CoinValue(e)))
+            .collect::<HashMap<_, _>>();
+        let state = create_state(&stakers, 5);
+
+        assert!(state.stakes.stakers(4).is_empty());
+        let effective = state.stakes.stakers(5);
+        assert_eq!(effective.len(), stakers.len());
+        stakers.iter().for_each(|(sk, syms)| {
+            assert_eq!(effective.get(&sk.to_public()), Some(syms));
+        });
+    }
+
     #[test]
     fn test_remove_stale_all_stale() {
         let staked_syms: Vec<u128> = vec![0; 100];
@@ -212,7 +248,7 @@ mod tests {
         // Generate state for stakers
         let stakers = staked_syms
             .into_iter()
-            .map(|e| (tmelcrypt::ed25519_keygen().1, CoinValue(e)))
+            .map(|e| (tmelcrypt::Ed25519SK::generate(), CoinValue(e)))
             .collect();
         let mut state = create_state(&stakers, 0);
 
@@ -231,7 +267,7 @@ mod tests {
         // Generate state for stakers
         let stakers = staked_syms
             .into_iter()
-            .map(|e| (tmelcrypt::ed25519_keygen().1, CoinValue(e)))
+            .map(|e| (tmelcrypt::Ed25519SK::generate(), CoinValue(e)))
             .collect();
         let mut state = create_state(&stakers, 0);
 
@@ -1,31 +1,58 @@
 mod applytx;
+mod audit;
+mod cproof;
+mod encoding;
 pub(crate) mod melmint;
 pub(crate) mod melswap;
+mod observer;
 mod poolkey;
+mod receipt;
+mod reconstruct;
+#[cfg(any(test, feature = "reference"))]
+mod reference;
+mod snapshot;
+mod supply;
+mod undo;
 
 pub use crate::stake::*;
 use crate::state::applytx::StateHandle;
-use crate::{constants::*, melvm::Address, preseal_melmint, CoinDataHeight, Denom, TxHash};
+use crate::state::melmint::preseal_melmint_recording;
+use crate::{
+    constants::*, melvm::Address, ChainParams, CoinDataHeight, Denom, ParamsError, Tip, TxHash,
+};
 use crate::{smtmapping::*, BlockHeight, CoinData, CoinValue};
 use crate::{transaction::Transaction, CoinID};
 
+use std::fmt::Debug;
 use std::{collections::BTreeMap, convert::TryInto};
 use std::{collections::BTreeSet, io::Read};
-use std::{collections::HashSet, fmt::Debug};
 
 use arbitrary::Arbitrary;
 use defmac::defmac;
 use derivative::Derivative;
 use novasmt::ContentAddrStore;
+use num::rational::Ratio;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use thiserror::Error;
 use tmelcrypt::{Ed25519PK, HashVal};
 
-use crate::state::melswap::PoolMapping;
+use crate::lightclient::StateProof;
+use crate::state::melswap::{PoolMapping, PoolState};
 
+pub use applytx::StateDiff;
+pub use audit::AuditViolation;
+use cproof::exceeds_threshold;
+pub use cproof::{ConsensusProofBuilder, SignatureShare};
+pub use observer::{NoopObserver, StateEvent, StateObserver};
 pub use poolkey::PoolKey;
+use receipt::{MelmintEffects, MelmintSink};
+pub use receipt::{MelmintOutcome, TxReceipt};
+pub use reconstruct::{ReconstructError, TxSource};
+pub use snapshot::SnapshotError;
+pub use supply::{Supply, SupplyError};
+pub use undo::BlockUndo;
 
 #[derive(Error, Debug)]
 /// A error that happens while applying a transaction to a state
@@ -52,6 +79,97 @@ pub enum StateError {
     CoinLocked,
     #[error("duplicate transaction")]
     DuplicateTx,
+    #[error(
+        "coin {coin} spent by both transaction {first_tx} and transaction {second_tx} of the batch"
+    )]
+    DoubleSpend {
+        coin: CoinID,
+        /// Index of the transaction that spent the coin first.
+        first_tx: usize,
+        /// Index of the transaction that spent it again; equal to `first_tx` if a transaction spends the same coin twice.
+        second_tx: usize,
+    },
+    #[error("block weight {weight} exceeds the maximum of {max}")]
+    BlockTooHeavy { weight: u128, max: u128 },
+    #[error("block transactions are not in canonical order")]
+    NonCanonicalBlock,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+/// A error that happens while decoding a state, or values stored in its SMTs, from possibly corrupted data
+pub enum DecodeError {
+    #[error("encoding is truncated")]
+    Truncated,
+    #[error("{0} unexpected bytes after the encoding")]
+    TrailingBytes(usize),
+    #[error("unknown network ID {0:#04x}")]
+    BadNetwork(u8),
+    #[error("unsupported encoding version {0}")]
+    UnsupportedVersion(u8),
+    #[error("tree {0} is missing from the database")]
+    MissingTree(HashVal),
+    #[error("SMT contains a value that cannot be decoded")]
+    BadValue,
+    #[error("preimage of SMT key {0} was not recorded")]
+    MissingKeyPreimage(HashVal),
+    #[error("malformed encoding")]
+    Malformed,
+    #[error("encoding is not canonical")]
+    NonCanonical,
+    #[error("invalid chain parameters: {0}")]
+    BadParams(ParamsError),
+    #[error("encoding has chain parameters {0:?}, which are not the network's")]
+    ParamsMismatch(Box<ChainParams>),
+    #[error("chain parameters of custom network {0:?} must be given")]
+    MissingParams(NetID),
+}
+
+/// The phase of batch application in which a transaction failed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum BatchPhase {
+    /// Sequential checks: well-formedness, deduplication, and fees.
+    Preliminary,
+    /// Validation of special transactions, such as stakes and DOSC minting.
+    Special,
+    /// Spending of inputs, including covenant checks and balancing.
+    Inputs,
+}
+
+#[derive(Error, Debug)]
+#[error("transaction {tx_index} ({txhash}) failed in phase {phase:?}: {error}")]
+/// A error that happens while applying a batch of transactions, attributed to a particular transaction.
+pub struct BatchError {
+    /// Index of the offending transaction within the batch.
+    pub tx_index: usize,
+    /// Hash of the offending transaction.
+    pub txhash: TxHash,
+    /// Phase in which the transaction failed.
+    pub phase: BatchPhase,
+    /// The underlying error.
+    #[source]
+    pub error: StateError,
+}
+
+impl BatchError {
+    pub(crate) fn new(
+        tx_index: usize,
+        tx: &Transaction,
+        phase: BatchPhase,
+        error: StateError,
+    ) -> Self {
+        Self {
+            tx_index,
+            txhash: tx.hash_nosigs(),
+            phase,
+            error,
+        }
+    }
+}
+
+impl From<BatchError> for StateError {
+    fn from(err: BatchError) -> Self {
+        err.error
+    }
 }
 
 /// Identifies a network.
@@ -85,6 +203,8 @@ pub enum NetID {
 #[derive(Debug)]
 pub struct State<C: ContentAddrStore> {
     pub network: NetID,
+    /// Consensus parameters of the network. They are not committed to by the header, so states restored from one get the network's defaults.
+    pub params: ChainParams,
 
     pub height: BlockHeight,
     pub history: SmtMapping<C, BlockHeight, Header>,
@@ -99,14 +219,18 @@ pub struct State<C: ContentAddrStore> {
     pub pools: PoolMapping<C>,
 
     pub stakes: StakeMapping<C>,
+
+    /// How much of every denomination exists. Kept up to date by applying transactions and sealing, but not by changes made directly to the trees. `None` if not known, as for states restored from a header; it can then be set from [State::scan_supply].
+    pub supply: Option<Supply>,
 }
 
 impl<C: ContentAddrStore> Clone for State<C> {
     fn clone(&self) -> Self {
         Self {
             network: self.network,
+            params: self.params,
 
-            height: self.height.clone(),
+            height: self.height,
             history: self.history.clone(),
             coins: self.coins.clone(),
             transactions: self.transactions.clone(),
@@ -118,6 +242,8 @@ impl<C: ContentAddrStore> Clone for State<C> {
             dosc_speed: self.dosc_speed,
             pools: self.pools.clone(),
             stakes: self.stakes.clone(),
+
+            supply: self.supply.clone(),
         }
     }
 }
@@ -128,17 +254,95 @@ fn read_bts(r: &mut impl Read, n: usize) -> Option<Vec<u8>> {
     Some(buf)
 }
 
+/// Returns the chain parameters of a restored state: the given ones if they are valid for the network, or else the network's defaults.
+fn restored_params(
+    network: NetID,
+    params: Option<&ChainParams>,
+) -> Result<ChainParams, DecodeError> {
+    match params {
+        Some(params) => {
+            params.validate(network).map_err(DecodeError::BadParams)?;
+            Ok(*params)
+        }
+        None if matches!(network, NetID::Mainnet | NetID::Testnet) => {
+            Ok(ChainParams::for_network(network))
+        }
+        None => Err(DecodeError::MissingParams(network)),
+    }
+}
+
 impl<C: ContentAddrStore> State<C> {
+    /// Returns true iff the rule changes of the given TIP apply.
+    pub fn is_active(&self, tip: Tip) -> bool {
+        self.params.is_active(tip, self.height)
+    }
+
+    /// Lists the TIPs whose rule changes apply.
+    pub fn active_tips(&self) -> Vec<Tip> {
+        self.params.active_tips(self.height)
+    }
+
     /// Returns true iff TIP 901 rule changes apply.
     pub fn tip_901(&self) -> bool {
-        self.height >= TIP_901_HEIGHT
-            || (self.network != NetID::Mainnet && self.network != NetID::Testnet)
+        self.is_active(Tip::Tip901)
     }
 
     /// Returns true iff TIP 902 rule changes apply.
     pub fn tip_902(&self) -> bool {
-        self.height >= TIP_902_HEIGHT
-            || (self.network != NetID::Mainnet && self.network != NetID::Testnet)
+        self.is_active(Tip::Tip902)
+    }
+
+    /// Returns true iff TIP 903 rule changes apply.
+    pub fn tip_903(&self) -> bool {
+        self.is_active(Tip::Tip903)
+    }
+
+    /// Returns the stake epoch of this height.
+    pub fn epoch(&self) -> u64 {
+        self.height.0 / self.params.stake_epoch
+    }
+
+    /// Returns the maximum total weight of the transactions in a block at this height, if there is one.
+    pub fn max_block_weight(&self) -> Option<u128> {
+        if self.is_active(Tip::Tip903) {
+            Some(self.params.max_block_weight)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the total value of the given denomination in existence: in coins, in pools, and for MEL, in the fee pool and tips. `None` if the supply is not known.
+    pub fn total_supply(&self, denom: Denom) -> Option<CoinValue> {
+        let supply = self.supply.as_ref()?.get(denom);
+        if denom == Denom::Mel {
+            Some(supply + self.fee_pool + self.tips)
+        } else {
+            Some(supply)
+        }
+    }
+
+    /// Computes the supply from scratch by scanning every coin and the given pools. Since the pool tree only stores hashes of pool keys, the keys must be supplied, for example from [SmtMapping::iter] on a state that records keys.
+    pub fn scan_supply(
+        &self,
+        pools: impl IntoIterator<Item = PoolKey>,
+    ) -> Result<Supply, SupplyError> {
+        supply::scan(self, pools)
+    }
+
+    /// Scans the state and checks its global invariants, returning every violation found. Slow, since every coin and header is visited; meant for debug builds and tooling.
+    ///
+    /// Pools whose keys were never recorded (see [State::set_record_keys]) are only checked if they are built-in, and the supply is only checked if every pool key is known.
+    pub fn audit(&self) -> Vec<AuditViolation> {
+        audit::audit(self, false)
+    }
+
+    /// Turns on or off recording key preimages in every SMT of the state, so that they can be listed with [SmtMapping::iter]. Only keys inserted from now on are recorded; this has no effect on consensus.
+    pub fn set_record_keys(&mut self, record: bool) {
+        self.history.set_record_keys(record);
+        self.coins.set_record_keys(record);
+        self.transactions.set_record_keys(record);
+        self.pools.set_record_keys(record);
+        self.stakes.set_record_keys(record);
     }
 
     /// Generates an encoding of the state that, in conjunction with a SMT database, can recover the entire state.
@@ -161,11 +365,13 @@ impl<C: ContentAddrStore> State<C> {
         out
     }
 
-    /// Restores a state from a header, in conjunction with a database. **Does not validate data and will panic; do not use on untrusted data**
+    /// Restores a state from a header, in conjunction with a database, with the chain parameters of mainnet or testnet. **Does not validate data and will panic, including on custom networks; do not use on untrusted data**
+    #[deprecated = "use State::from_header, which takes the chain parameters"]
     pub fn from_header_infallible(header: Header, db: &novasmt::Database<C>) -> Self {
         defmac!(readtree hash => SmtMapping::new(db.get_tree(hash.0).unwrap()));
         State {
             network: header.network,
+            params: restored_params(header.network, None).unwrap(),
             height: header.height,
             history: readtree!(header.history_hash),
             coins: readtree!(header.coins_hash),
@@ -179,10 +385,13 @@ impl<C: ContentAddrStore> State<C> {
             pools: readtree!(header.pools_hash),
 
             stakes: readtree!(header.stakes_hash),
+
+            supply: None,
         }
     }
 
-    /// Restores a state from its partial encoding in conjunction with a database. **Does not validate data and will panic; do not use on untrusted data**
+    /// Restores a state from its partial encoding in conjunction with a database, with the chain parameters of mainnet or testnet. **Does not validate data and will panic, including on custom networks; do not use on untrusted data**
+    #[deprecated = "use State::from_partial_encoding, which takes the chain parameters"]
     pub fn from_partial_encoding_infallible(
         mut encoding: &[u8],
         db: &novasmt::Database<C>,
@@ -209,6 +418,7 @@ impl<C: ContentAddrStore> State<C> {
         let stakes = readtree!();
         State {
             network,
+            params: restored_params(network, None).unwrap(),
             height: height.into(),
             history,
             coins,
@@ -222,17 +432,109 @@ impl<C: ContentAddrStore> State<C> {
             pools,
 
             stakes,
+
+            supply: None,
+        }
+    }
+
+    /// Restores a state from a header, in conjunction with a database, checking that every tree the header refers to exists. Headers do not include the chain parameters, so they must be given, usually from [crate::GenesisConfig::chain_params], and valid for the network.
+    pub fn from_header(
+        header: Header,
+        params: &ChainParams,
+        db: &novasmt::Database<C>,
+    ) -> Result<Self, DecodeError> {
+        Ok(State {
+            network: header.network,
+            params: restored_params(header.network, Some(params))?,
+            height: header.height,
+            history: SmtMapping::from_root(db, header.history_hash)?,
+            coins: SmtMapping::from_root(db, header.coins_hash)?,
+            transactions: SmtMapping::from_root(db, header.transactions_hash)?,
+
+            fee_pool: header.fee_pool,
+            fee_multiplier: header.fee_multiplier,
+            tips: 0.into(),
+
+            dosc_speed: header.dosc_speed,
+            pools: SmtMapping::from_root(db, header.pools_hash)?,
+
+            stakes: SmtMapping::from_root(db, header.stakes_hash)?,
+
+            supply: None,
+        })
+    }
+
+    /// Restores a state from its partial encoding in conjunction with a database, checking the network ID, the length of the encoding, and that every tree it refers to exists. Only the roots of the trees are checked, not their contents. Like [State::from_header], the chain parameters are not encoded and must be given.
+    pub fn from_partial_encoding(
+        encoding: &[u8],
+        params: &ChainParams,
+        db: &novasmt::Database<C>,
+    ) -> Result<Self, DecodeError> {
+        Self::decode_partial(encoding, Some(params), db)
+    }
+
+    /// Decodes a partial encoding with the given chain parameters, or with the network's defaults if there are none.
+    pub(crate) fn decode_partial(
+        mut encoding: &[u8],
+        params: Option<&ChainParams>,
+        db: &novasmt::Database<C>,
+    ) -> Result<Self, DecodeError> {
+        defmac!(readbts n => read_bts(&mut encoding, n).ok_or(DecodeError::Truncated)?);
+        defmac!(readu8 => u8::from_be_bytes(readbts!(1).try_into().unwrap()));
+        defmac!(readu64 => u64::from_be_bytes(readbts!(8).try_into().unwrap()));
+        defmac!(readu128 => u128::from_be_bytes(readbts!(16).try_into().unwrap()));
+        defmac!(readtree => SmtMapping::from_root(db, HashVal(readbts!(32).try_into().unwrap()))?);
+        let network = readu8!();
+        let network: NetID = network
+            .try_into()
+            .map_err(|_| DecodeError::BadNetwork(network))?;
+        let height = readu64!();
+        let history = readtree!();
+        let coins = readtree!();
+        let transactions = readtree!();
+
+        let fee_pool = readu128!();
+        let fee_multiplier = readu128!();
+        let tips = readu128!();
+
+        let dosc_multiplier = readu128!();
+        let pools = readtree!();
+
+        let stakes = readtree!();
+        if !encoding.is_empty() {
+            return Err(DecodeError::TrailingBytes(encoding.len()));
         }
+        Ok(State {
+            network,
+            params: restored_params(network, params)?,
+            height: height.into(),
+            history,
+            coins,
+            transactions,
+
+            fee_pool: fee_pool.into(),
+            fee_multiplier,
+            tips: tips.into(),
+
+            dosc_speed: dosc_multiplier,
+            pools,
+
+            stakes,
+
+            supply: None,
+        })
     }
 
     /// Applies a single transaction.
     pub fn apply_tx(&mut self, tx: &Transaction) -> Result<(), StateError> {
-        self.apply_tx_batch(std::slice::from_ref(tx))
+        Ok(self.apply_tx_batch(std::slice::from_ref(tx))?)
     }
 
-    pub fn apply_tx_batch(&mut self, txx: &[Transaction]) -> Result<(), StateError> {
+    /// Applies a batch of transactions. If any of them fail, the state is left unchanged and the offending transaction is identified in the error.
+    pub fn apply_tx_batch(&mut self, txx: &[Transaction]) -> Result<(), BatchError> {
         let old_hash = self.coins.root_hash();
-        StateHandle::new(self).apply_tx_batch(txx)?.commit();
+        let diff = StateHandle::new(self).apply_tx_batch(txx)?.into_changes();
+        diff.commit(self);
         log::debug!(
             "applied a batch of {} txx to {:?} => {:?}",
             txx.len(),
@@ -242,13 +544,74 @@ impl<C: ContentAddrStore> State<C> {
         Ok(())
     }
 
+    /// Applies a batch of transactions like [State::apply_tx_batch], but one at a time with a slow, sequential algorithm. Only meant as a reference for testing the parallel algorithm against.
+    #[cfg(any(test, feature = "reference"))]
+    pub fn apply_tx_batch_sequential(&mut self, txx: &[Transaction]) -> Result<(), BatchError> {
+        reference::apply_tx_batch(self, txx)
+    }
+
+    /// Applies a batch of transactions like [State::apply_tx_batch], reporting the changes to the given observer.
+    pub fn apply_tx_batch_observed(
+        &mut self,
+        txx: &[Transaction],
+        observer: &(impl StateObserver + ?Sized),
+    ) -> Result<(), BatchError> {
+        let diff = self.simulate_tx_batch(txx)?;
+        diff.notify(self.fee_multiplier, observer);
+        diff.commit(self);
+        Ok(())
+    }
+
+    /// Fully validates a batch of transactions against this state, returning the changes that applying them would make without actually applying them.
+    pub fn simulate_tx_batch(&self, txx: &[Transaction]) -> Result<StateDiff, BatchError> {
+        Ok(StateHandle::new(self).apply_tx_batch(txx)?.into_diff())
+    }
+
     /// Finalizes a state into a block. This consumes the state.
-    pub fn seal(mut self, action: Option<ProposerAction>) -> SealedState<C> {
+    pub fn seal(self, action: Option<ProposerAction>) -> SealedState<C> {
+        self.seal_recording(action, &mut NoopObserver)
+    }
+
+    /// Finalizes a state into a block like [State::seal], also returning a receipt for every transaction in the block, ordered by transaction hash.
+    pub fn seal_with_receipts(
+        self,
+        action: Option<ProposerAction>,
+    ) -> (SealedState<C>, Vec<TxReceipt>) {
+        let (height, fee_multiplier) = (self.height, self.fee_multiplier);
+        let mut txx = self.transactions.val_iter().collect::<Vec<_>>();
+        txx.sort_by_cached_key(|tx| tx.hash_nosigs());
+
+        let mut effects = MelmintEffects::default();
+        let sealed = self.seal_recording(action, &mut effects);
+        let receipts = txx
+            .iter()
+            .map(|tx| effects.receipt(tx, height, fee_multiplier))
+            .collect();
+        (sealed, receipts)
+    }
+
+    /// Finalizes a state into a block like [State::seal], reporting melmint's changes and the proposer reward to the given observer.
+    pub fn seal_observed(
+        self,
+        action: Option<ProposerAction>,
+        observer: &(impl StateObserver + ?Sized),
+    ) -> SealedState<C> {
+        let mut effects = MelmintEffects::default();
+        let sealed = self.seal_recording(action, &mut effects);
+        sealed.notify_seal(&effects, observer);
+        sealed
+    }
+
+    fn seal_recording(
+        mut self,
+        action: Option<ProposerAction>,
+        effects: &mut impl MelmintSink,
+    ) -> SealedState<C> {
         // first apply melmint
-        self = preseal_melmint(self);
+        self = preseal_melmint_recording(self, effects);
         assert!(self.pools.val_iter().count() >= 2);
 
-        let after_tip_901 = self.tip_901();
+        let after_tip_901 = self.is_active(Tip::Tip901);
 
         // apply the proposer action
         if let Some(action) = action {
@@ -267,7 +630,7 @@ impl<C: ContentAddrStore> State<C> {
             if scaled_movement >= 0 {
                 self.fee_multiplier += scaled_movement as u128;
             } else {
-                self.fee_multiplier -= scaled_movement.abs() as u128;
+                self.fee_multiplier -= scaled_movement.unsigned_abs() as u128;
             }
 
             // then it's time to collect the fees dude! we synthesize a coin with 1/65536 of the fee pool and all the tips.
@@ -286,6 +649,9 @@ impl<C: ContentAddrStore> State<C> {
                 height: self.height,
             };
             // insert the fake coin
+            supply::update(&mut self, |supply| {
+                supply.add_coin(&pseudocoin_data.coin_data)
+            });
             self.coins.insert(pseudocoin_id, pseudocoin_data);
         }
         // create the finalized state
@@ -310,16 +676,46 @@ impl<C: ContentAddrStore> SealedState<C> {
         self.1.is_none() && self.inner_ref().transactions.root_hash() == Default::default()
     }
 
-    /// Returns the **partial** encoding, which must be combined with a SMT database to reconstruct the actual state.
+    /// Checks the global invariants of the state like [State::audit], also requiring the built-in pools that sealing creates at this height.
+    pub fn audit(&self) -> Vec<AuditViolation> {
+        audit::audit(&self.0, true)
+    }
+
+    /// Returns the **partial** encoding, which must be combined with a SMT database to reconstruct the actual state. The encoding is versioned, so that it can still be read after fields are added to the state.
     pub fn partial_encoding(&self) -> Vec<u8> {
-        let tmp = (self.0.partial_encoding(), &self.1);
-        stdcode::serialize(&tmp).unwrap()
+        encoding::encode(self)
     }
 
-    /// Decodes from the partial encoding.
+    /// Decodes from the partial encoding, either versioned or in the older unversioned format. Unversioned encodings get the chain parameters of mainnet or testnet, so those of custom networks are rejected. **Panics on malformed data; do not use on untrusted data**
+    #[deprecated = "use SealedState::from_partial_encoding, which takes the chain parameters"]
     pub fn from_partial_encoding_infallible(bts: &[u8], db: &novasmt::Database<C>) -> Self {
-        let tmp: (Vec<u8>, Option<ProposerAction>) = stdcode::deserialize(bts).unwrap();
-        SealedState(State::from_partial_encoding_infallible(&tmp.0, db), tmp.1)
+        encoding::decode(bts, None, db).expect("invalid SealedState encoding")
+    }
+
+    /// Decodes from the partial encoding, either versioned or in the older unversioned format, failing instead of panicking on malformed data; see [State::from_partial_encoding].
+    ///
+    /// The network's chain parameters must be given, usually from [crate::GenesisConfig::chain_params]. Versioned encodings record their parameters, which must be the same; unversioned ones are given these.
+    pub fn from_partial_encoding(
+        bts: &[u8],
+        params: &ChainParams,
+        db: &novasmt::Database<C>,
+    ) -> Result<Self, DecodeError> {
+        encoding::decode(bts, Some(params), db)
+    }
+
+    /// Writes a self-contained snapshot of the state, including the contents of every SMT, so that it can be restored without the original database.
+    pub fn export_snapshot(&self, writer: impl std::io::Write) -> std::io::Result<()> {
+        snapshot::export(self, writer)
+    }
+
+    /// Restores a state from a snapshot written by [SealedState::export_snapshot], rebuilding its trees in the given database. Fails unless the snapshot is of the block whose header hashes to `expected`, usually from a trusted source, and the rebuilt state has exactly that header and the given chain parameters, usually from [crate::GenesisConfig::chain_params].
+    pub fn import_snapshot(
+        reader: impl std::io::Read,
+        expected: HashVal,
+        params: &ChainParams,
+        db: &novasmt::Database<C>,
+    ) -> Result<Self, SnapshotError> {
+        snapshot::import(reader, expected, params, db)
     }
 
     /// Returns the block header represented by the finalized state.
@@ -343,28 +739,35 @@ impl<C: ContentAddrStore> SealedState<C> {
         }
     }
 
+    /// Proves that a coin exists, or does not exist, in this state.
+    pub fn prove_coin(&self, coin_id: CoinID) -> StateProof<CoinID, CoinDataHeight> {
+        StateProof::new(&self.0.coins, coin_id, self.header())
+    }
+
+    /// Proves that a transaction is, or is not, included in this block.
+    pub fn prove_tx(&self, txhash: TxHash) -> StateProof<TxHash, Transaction> {
+        StateProof::new(&self.0.transactions, txhash, self.header())
+    }
+
+    /// Proves the state of a pool.
+    pub fn prove_pool(&self, pool: PoolKey) -> StateProof<PoolKey, PoolState> {
+        StateProof::new(&self.0.pools, pool, self.header())
+    }
+
     /// Returns the proposer action.
     pub fn proposer_action(&self) -> Option<&ProposerAction> {
         self.1.as_ref()
     }
 
-    /// Returns the final state represented as a "block" (header + transactions).
+    /// Returns the final state represented as a "block" (header + transactions), with the transactions in canonical order.
     pub fn to_block(&self) -> Block {
-        let mut txx = HashSet::default();
-        self.0.transactions.val_iter().for_each(|tx| {
-            txx.insert(tx);
-        });
-
-        // self check since imbl sometimes is buggy
-        self.0.transactions.val_iter().for_each(|tx| {
-            assert!(txx.contains(&tx));
-        });
-
-        Block {
+        let mut block = Block {
             header: self.header(),
-            transactions: txx,
+            transactions: self.0.transactions.val_iter().collect(),
             proposer_action: self.1,
-        }
+        };
+        block.canonicalize();
+        block
     }
     /// Creates a new unfinalized state representing the next block.
     pub fn next_state(&self) -> State<C> {
@@ -372,19 +775,53 @@ impl<C: ContentAddrStore> SealedState<C> {
         // fee variables
         new.history.insert(self.0.height, self.header());
         new.height += BlockHeight(1);
-        new.stakes.remove_stale((new.height / STAKE_EPOCH).0);
+        new.stakes.remove_stale(new.epoch());
         new.transactions.clear();
         new
     }
 
-    /// Applies a block to this state.
+    /// Applies a block to this state. The block's transactions must be in canonical order.
     pub fn apply_block(&self, block: &Block) -> Result<SealedState<C>, StateError> {
+        Ok(self.apply_block_recording(block, &mut NoopObserver)?.0)
+    }
+
+    /// Applies a block like [SealedState::apply_block], reporting the changes made by its transactions and by sealing to the given observer. Nothing is reported unless the whole block is valid.
+    pub fn apply_block_observed(
+        &self,
+        block: &Block,
+        observer: &(impl StateObserver + ?Sized),
+    ) -> Result<SealedState<C>, StateError> {
+        let fee_multiplier = self.0.fee_multiplier;
+        let mut effects = MelmintEffects::default();
+        let (next, diff) = self.apply_block_recording(block, &mut effects)?;
+        diff.notify(fee_multiplier, observer);
+        next.notify_seal(&effects, observer);
+        Ok(next)
+    }
+
+    /// Applies a block, returning the resulting state along with the changes its transactions made.
+    fn apply_block_recording(
+        &self,
+        block: &Block,
+        effects: &mut impl MelmintSink,
+    ) -> Result<(SealedState<C>, StateDiff), StateError> {
+        if !block.is_canonical() {
+            return Err(StateError::NonCanonicalBlock);
+        }
         let mut basis = self.next_state();
         assert!(basis.pools.val_iter().count() >= 2);
-        let transactions = block.transactions.iter().cloned().collect::<Vec<_>>();
-        basis.apply_tx_batch(&transactions)?;
+        if let Some(max) = basis.max_block_weight() {
+            let weight = block.weight();
+            if weight > max {
+                return Err(StateError::BlockTooHeavy { weight, max });
+            }
+        }
+        let diff = StateHandle::new(&basis)
+            .apply_tx_batch(&block.transactions)?
+            .into_changes();
+        diff.commit(&mut basis);
         assert!(basis.pools.val_iter().count() >= 2);
-        let basis = basis.seal(block.proposer_action);
+        let basis = basis.seal_recording(block.proposer_action, effects);
         assert!(basis.inner_ref().pools.val_iter().count() >= 2);
 
         if basis.header() != block.header {
@@ -392,7 +829,7 @@ impl<C: ContentAddrStore> SealedState<C> {
                 "post-apply header {:#?} doesn't match declared header {:#?} with {} txx",
                 basis.header(),
                 block.header,
-                transactions.len()
+                block.transactions.len()
             );
             block.transactions.iter().for_each(|tx| {
                 log::warn!("{:?}", tx);
@@ -400,19 +837,92 @@ impl<C: ContentAddrStore> SealedState<C> {
 
             Err(StateError::WrongHeader)
         } else {
-            Ok(basis)
+            Ok((basis, diff))
         }
     }
 
-    /// Confirms a state with a given consensus proof. If called with a second argument, this function is supposed to be called to *verify* the consensus proof.
-    ///
-    /// **TODO**: Right now it DOES NOT check the consensus proof!
+    /// Reports what sealing this state did, as recorded in `effects`, to an observer.
+    fn notify_seal(&self, effects: &MelmintEffects, observer: &(impl StateObserver + ?Sized)) {
+        effects.notify(observer);
+        if self.1.is_some() {
+            let coin_id = CoinID::proposer_reward(self.0.height);
+            if let Some(coin) = self.0.coins.get(&coin_id).0 {
+                observer.on_event(StateEvent::ProposerRewardMinted {
+                    coin_id,
+                    coin: &coin,
+                });
+            }
+        }
+    }
+
+    /// Reconstructs the block behind an [AbbrBlock] from the given source of transactions and applies it. If transactions are missing, they are all listed so that just those can be fetched.
+    pub fn apply_abbr_block(
+        &self,
+        abbr: &AbbrBlock,
+        source: &(impl TxSource + ?Sized),
+    ) -> Result<SealedState<C>, ReconstructError> {
+        let block = abbr.reconstruct(source)?;
+        Ok(self.apply_block(&block)?)
+    }
+
+    /// Applies a block to this state, also returning the data needed to [roll it back](SealedState::rollback).
+    pub fn apply_block_with_undo(
+        &self,
+        block: &Block,
+    ) -> Result<(SealedState<C>, BlockUndo), StateError> {
+        let mut effects = MelmintEffects::default();
+        let (next, _) = self.apply_block_recording(block, &mut effects)?;
+        let undo = BlockUndo::new(self, &next, &effects);
+        Ok((next, undo))
+    }
+
+    /// Undoes the block that produced this state, returning the exact previous state. Fails with [StateError::WrongHeader] if the undo data belongs to a different block.
+    pub fn rollback(&self, undo: &BlockUndo) -> Result<SealedState<C>, StateError> {
+        undo.apply(self)
+    }
+
+    /// Confirms a state with a given consensus proof, verified against the stakes of the previous state: it needs signatures from more than [CONFIRM_THRESHOLD] of the syms staked in this block's epoch.
     pub fn confirm(
         self,
         cproof: ConsensusProof,
-        _previous_state: Option<&State<C>>,
-    ) -> Option<ConfirmedState<C>> {
-        Some(ConfirmedState {
+        previous_state: &State<C>,
+    ) -> Result<ConfirmedState<C>, ConfirmError> {
+        self.confirm_with_threshold(cproof, previous_state, CONFIRM_THRESHOLD)
+    }
+
+    /// Like [SealedState::confirm], but with a custom threshold: the signers must hold strictly more than this fraction of the staked syms.
+    pub fn confirm_with_threshold(
+        self,
+        cproof: ConsensusProof,
+        previous_state: &State<C>,
+        threshold: Ratio<u128>,
+    ) -> Result<ConfirmedState<C>, ConfirmError> {
+        let header_hash = self.header().hash();
+        let stakers = previous_state.stakes.stakers(self.0.epoch());
+
+        let bad_signatures = cproof
+            .iter()
+            .filter(|(pk, sig)| !pk.verify(&header_hash, sig))
+            .map(|(pk, _)| *pk)
+            .collect::<Vec<_>>();
+        if !bad_signatures.is_empty() {
+            return Err(ConfirmError::BadSignatures(bad_signatures));
+        }
+        let unstaked = cproof
+            .keys()
+            .filter(|pk| !stakers.contains_key(pk))
+            .copied()
+            .collect::<Vec<_>>();
+        if !unstaked.is_empty() {
+            return Err(ConfirmError::Unstaked(unstaked));
+        }
+
+        let total: CoinValue = stakers.values().copied().sum();
+        let signed: CoinValue = cproof.keys().map(|pk| stakers[pk]).sum();
+        if !exceeds_threshold(signed, total, threshold) {
+            return Err(ConfirmError::InsufficientVotes { signed, total });
+        }
+        Ok(ConfirmedState {
             state: self,
             cproof,
         })
@@ -430,6 +940,17 @@ pub struct ProposerAction {
 
 pub type ConsensusProof = BTreeMap<Ed25519PK, Vec<u8>>;
 
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+/// A error that happens while verifying a consensus proof
+pub enum ConfirmError {
+    #[error("invalid signatures from {0:?}")]
+    BadSignatures(Vec<Ed25519PK>),
+    #[error("signatures from keys without stake in this epoch: {0:?}")]
+    Unstaked(Vec<Ed25519PK>),
+    #[error("only {signed} out of {total} staked syms signed")]
+    InsufficientVotes { signed: CoinValue, total: CoinValue },
+}
+
 /// ConfirmedState represents a fully confirmed state with a consensus proof.
 #[derive(Derivative, Debug)]
 #[derivative(Clone(bound = ""))]
@@ -469,15 +990,15 @@ pub struct Header {
 
 impl Header {
     pub fn hash(&self) -> tmelcrypt::HashVal {
-        tmelcrypt::hash_single(&stdcode::serialize(self).unwrap())
+        tmelcrypt::hash_single(stdcode::serialize(self).unwrap())
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-/// A (serialized) block.
+/// A (serialized) block. Its transactions are in canonical order: sorted by [Transaction::hash_nosigs], without duplicates.
 pub struct Block {
     pub header: Header,
-    pub transactions: HashSet<Transaction>,
+    pub transactions: Vec<Transaction>,
     pub proposer_action: Option<ProposerAction>,
 }
 
@@ -490,12 +1011,567 @@ impl Block {
             proposer_action: self.proposer_action,
         }
     }
+
+    /// Total weight of the transactions in the block.
+    pub fn weight(&self) -> u128 {
+        self.transactions
+            .iter()
+            .map(|tx| tx.weight())
+            .fold(0, u128::saturating_add)
+    }
+
+    /// Returns true iff the transactions are in canonical order.
+    pub fn is_canonical(&self) -> bool {
+        let txhashes = self
+            .transactions
+            .iter()
+            .map(|tx| tx.hash_nosigs())
+            .collect::<Vec<_>>();
+        txhashes.windows(2).all(|pair| pair[0] < pair[1])
+    }
+
+    /// Puts the transactions in canonical order, dropping duplicates.
+    pub fn canonicalize(&mut self) {
+        self.transactions.sort_by_cached_key(|tx| tx.hash_nosigs());
+        self.transactions.dedup_by_key(|tx| tx.hash_nosigs());
+    }
+
+    /// Returns the canonical encoding of the block: its stdcode encoding, with the transactions in canonical order.
+    pub fn canonical_encoding(&self) -> Vec<u8> {
+        if self.is_canonical() {
+            stdcode::serialize(self).unwrap()
+        } else {
+            let mut canonical = self.clone();
+            canonical.canonicalize();
+            stdcode::serialize(&canonical).unwrap()
+        }
+    }
+
+    /// Decodes a block, failing unless the bytes are exactly its canonical encoding.
+    pub fn from_canonical_encoding(bts: &[u8]) -> Result<Self, DecodeError> {
+        let block: Self = stdcode::deserialize(bts).map_err(|_| DecodeError::Malformed)?;
+        if block.is_canonical() && stdcode::serialize(&block).unwrap() == bts {
+            Ok(block)
+        } else {
+            Err(DecodeError::NonCanonical)
+        }
+    }
+
+    /// Hash of the canonical encoding. Unlike the hash of the header, this also commits to the transactions' signatures and to the proposer action.
+    pub fn hash(&self) -> HashVal {
+        tmelcrypt::hash_single(self.canonical_encoding())
+    }
 }
 
-/// An abbreviated block
+/// An abbreviated block. The transaction hashes are kept sorted, which is the same order as the transactions of a canonical [Block].
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AbbrBlock {
     pub header: Header,
     pub txhashes: BTreeSet<TxHash>,
     pub proposer_action: Option<ProposerAction>,
 }
+
+impl AbbrBlock {
+    /// Rebuilds the full block from the given source of transactions, failing with the hashes of all the transactions the source lacks. The result is not validated; see [SealedState::apply_abbr_block].
+    pub fn reconstruct(
+        &self,
+        source: &(impl TxSource + ?Sized),
+    ) -> Result<Block, ReconstructError> {
+        reconstruct::reconstruct(self, source)
+    }
+
+    /// Returns the canonical encoding of the abbreviated block.
+    pub fn canonical_encoding(&self) -> Vec<u8> {
+        stdcode::serialize(self).unwrap()
+    }
+
+    /// Decodes an abbreviated block, failing unless the bytes are exactly its canonical encoding, with sorted and unique hashes.
+    pub fn from_canonical_encoding(bts: &[u8]) -> Result<Self, DecodeError> {
+        let abbr: Self = stdcode::deserialize(bts).map_err(|_| DecodeError::Malformed)?;
+        if abbr.canonical_encoding() == bts {
+            Ok(abbr)
+        } else {
+            Err(DecodeError::NonCanonical)
+        }
+    }
+
+    /// Hash of the canonical encoding.
+    pub fn hash(&self) -> HashVal {
+        tmelcrypt::hash_single(self.canonical_encoding())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use num::rational::Ratio;
+    use tmelcrypt::Ed25519SK;
+
+    use novasmt::{Database, InMemoryCas};
+
+    use crate::melvm::{opcode::OpCode, Covenant};
+    use crate::testing::functions::{
+        always_true_coin, create_state, spend_split, split_genesis_state,
+    };
+    use crate::{
+        AbbrBlock, Block, BlockHeight, ChainParams, CoinID, CoinValue, ConfirmError,
+        ConsensusProof, DecodeError, Denom, GenesisConfig, NetID, ParamsError, PoolKey,
+        SealedState, State, StateError, Tip, Transaction, TxKind, MAX_BLOCK_WEIGHT,
+        MICRO_CONVERTER, TIP_903_HEIGHT,
+    };
+
+    /// Seals a state with the given stakers, returning it together with the block after it.
+    fn two_blocks(
+        stakers: &HashMap<Ed25519SK, CoinValue>,
+    ) -> (
+        SealedState<novasmt::InMemoryCas>,
+        SealedState<novasmt::InMemoryCas>,
+    ) {
+        let first = create_state(stakers, 0).seal(None);
+        let second = first.next_state().seal(None);
+        (first, second)
+    }
+
+    fn sign_all<'a>(
+        sealed: &SealedState<novasmt::InMemoryCas>,
+        signers: impl Iterator<Item = &'a Ed25519SK>,
+    ) -> ConsensusProof {
+        let header_hash = sealed.header().hash();
+        signers
+            .map(|sk| (sk.to_public(), sk.sign(&header_hash)))
+            .collect()
+    }
+
+    #[test]
+    fn record_keys_lists_coins_and_pools() {
+        let db = Database::new(InMemoryCas::default());
+        let mut genesis = GenesisConfig {
+            init_coindata: always_true_coin(1 << 40),
+            ..GenesisConfig::std_testnet()
+        }
+        .realize(&db);
+        genesis.set_record_keys(true);
+        genesis.coins.record_key(&CoinID::zero_zero());
+        let genesis = genesis.seal(None);
+
+        let tx = Transaction::new(TxKind::Normal)
+            .add_input(CoinID::zero_zero())
+            .with_outputs(vec![always_true_coin(1 << 30); 3])
+            .with_fee(CoinValue((1 << 40) - 3 * (1 << 30)))
+            .add_script(Covenant::always_true());
+        let mut next = genesis.next_state();
+        next.apply_tx(&tx).unwrap();
+        let sealed = next.seal(None);
+        let state = sealed.inner_ref();
+
+        let mut coin_ids = state
+            .coins
+            .iter()
+            .map(|entry| entry.unwrap().0)
+            .collect::<Vec<_>>();
+        coin_ids.sort_unstable();
+        assert_eq!(
+            coin_ids,
+            (0..3).map(|i| tx.output_coinid(i)).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            state.transactions.iter().collect::<Vec<_>>(),
+            vec![Ok((tx.hash_nosigs(), tx))]
+        );
+        assert!(state
+            .pools
+            .iter()
+            .any(|entry| entry.unwrap().0 == PoolKey::mel_and(Denom::Sym)));
+        assert_eq!(state.history.iter().count(), 1);
+
+        // without recorded keys, entries can't be listed, but this is not fatal
+        let unrecorded = GenesisConfig {
+            init_coindata: always_true_coin(1 << 40),
+            ..GenesisConfig::std_testnet()
+        }
+        .realize(&Database::new(InMemoryCas::default()));
+        assert_eq!(
+            unrecorded.coins.iter().collect::<Vec<_>>(),
+            vec![Err(DecodeError::MissingKeyPreimage(
+                tmelcrypt::hash_single(stdcode::serialize(&CoinID::zero_zero()).unwrap())
+            ))]
+        );
+    }
+
+    #[test]
+    fn fallible_partial_decoding() {
+        let (sealed, _) = split_genesis_state(1);
+        let db = sealed.inner_ref().coins.mapping.database();
+        let params = sealed.inner_ref().params;
+        let encoding = sealed.partial_encoding();
+        let decoded = SealedState::from_partial_encoding(&encoding, &params, &db).unwrap();
+        assert_eq!(decoded.header(), sealed.header());
+
+        let inner = sealed.inner_ref().partial_encoding();
+        assert_eq!(
+            State::from_partial_encoding(&inner[..inner.len() - 1], &params, &db).unwrap_err(),
+            DecodeError::Truncated
+        );
+        let mut trailing = inner.clone();
+        trailing.extend_from_slice(&[0, 0]);
+        assert_eq!(
+            State::from_partial_encoding(&trailing, &params, &db).unwrap_err(),
+            DecodeError::TrailingBytes(2)
+        );
+        let mut bad_network = inner.clone();
+        bad_network[0] = 0x42;
+        assert_eq!(
+            State::from_partial_encoding(&bad_network, &params, &db).unwrap_err(),
+            DecodeError::BadNetwork(0x42)
+        );
+        // a database that lacks the trees
+        let empty = Database::new(InMemoryCas::default());
+        assert!(matches!(
+            State::from_partial_encoding(&inner, &params, &empty),
+            Err(DecodeError::MissingTree(_))
+        ));
+        assert!(matches!(
+            State::from_header(sealed.header(), &params, &empty),
+            Err(DecodeError::MissingTree(_))
+        ));
+        assert_eq!(
+            State::from_header(sealed.header(), &params, &db)
+                .unwrap()
+                .coins
+                .root_hash(),
+            sealed.inner_ref().coins.root_hash()
+        );
+        assert_eq!(
+            SealedState::from_partial_encoding(&encoding[1..], &params, &db).unwrap_err(),
+            DecodeError::Malformed
+        );
+    }
+
+    #[test]
+    fn canonical_blocks() {
+        let (base, split) = split_genesis_state(4);
+        let txx = (0..4)
+            .map(|i| spend_split(&split, i, 100_000))
+            .collect::<Vec<_>>();
+        let mut next = base.next_state();
+        next.apply_tx_batch(&txx).unwrap();
+        let block = next.seal(None).to_block();
+        assert!(block.is_canonical());
+        assert_eq!(
+            Block::from_canonical_encoding(&block.canonical_encoding())
+                .unwrap()
+                .hash(),
+            block.hash()
+        );
+
+        let mut shuffled = block.clone();
+        shuffled.transactions.reverse();
+        shuffled.transactions.push(txx[0].clone());
+        assert!(!shuffled.is_canonical());
+        assert_eq!(shuffled.canonical_encoding(), block.canonical_encoding());
+        assert_eq!(shuffled.hash(), block.hash());
+        assert_eq!(
+            Block::from_canonical_encoding(&stdcode::serialize(&shuffled).unwrap()).unwrap_err(),
+            DecodeError::NonCanonical
+        );
+        assert!(matches!(
+            base.apply_block(&shuffled),
+            Err(StateError::NonCanonicalBlock)
+        ));
+        shuffled.canonicalize();
+        assert_eq!(base.apply_block(&shuffled).unwrap().header(), block.header);
+
+        let abbr = block.abbreviate();
+        assert!(abbr.txhashes.iter().eq(block
+            .transactions
+            .iter()
+            .map(|tx| tx.hash_nosigs())
+            .collect::<Vec<_>>()
+            .iter()));
+        let decoded = AbbrBlock::from_canonical_encoding(&abbr.canonical_encoding()).unwrap();
+        assert_eq!(decoded.hash(), abbr.hash());
+        let unsorted = (
+            abbr.header,
+            abbr.txhashes.iter().rev().collect::<Vec<_>>(),
+            abbr.proposer_action,
+        );
+        assert_eq!(
+            AbbrBlock::from_canonical_encoding(&stdcode::serialize(&unsorted).unwrap())
+                .unwrap_err(),
+            DecodeError::NonCanonical
+        );
+    }
+
+    #[test]
+    fn confirm_with_quorum() {
+        let stakers = [100u128, 100, 100, 100]
+            .iter()
+            .map(|v| (tmelcrypt::Ed25519SK::generate(), CoinValue(*v)))
+            .collect::<HashMap<_, _>>();
+        let (first, second) = two_blocks(&stakers);
+        let cproof = sign_all(&second, stakers.keys().take(3));
+        assert!(second.confirm(cproof, first.inner_ref()).is_ok());
+    }
+
+    #[test]
+    fn confirm_rejects_exactly_two_thirds() {
+        let stakers = [100u128, 100, 100]
+            .iter()
+            .map(|v| (tmelcrypt::Ed25519SK::generate(), CoinValue(*v)))
+            .collect::<HashMap<_, _>>();
+        let (first, second) = two_blocks(&stakers);
+        let cproof = sign_all(&second, stakers.keys().take(2));
+        assert_eq!(
+            second
+                .clone()
+                .confirm(cproof.clone(), first.inner_ref())
+                .unwrap_err(),
+            ConfirmError::InsufficientVotes {
+                signed: CoinValue(200),
+                total: CoinValue(300)
+            }
+        );
+        // a lower threshold accepts the same proof
+        assert!(second
+            .confirm_with_threshold(cproof, first.inner_ref(), Ratio::new(1, 2))
+            .is_ok());
+    }
+
+    #[test]
+    fn confirm_names_bad_signatures() {
+        let stakers = [100u128, 100, 100]
+            .iter()
+            .map(|v| (tmelcrypt::Ed25519SK::generate(), CoinValue(*v)))
+            .collect::<HashMap<_, _>>();
+        let (first, second) = two_blocks(&stakers);
+        let mut cproof = sign_all(&second, stakers.keys());
+        let victim = stakers.keys().next().unwrap().to_public();
+        cproof.insert(victim, first.header().hash().to_vec());
+        assert_eq!(
+            second.confirm(cproof, first.inner_ref()).unwrap_err(),
+            ConfirmError::BadSignatures(vec![victim])
+        );
+    }
+
+    #[test]
+    fn confirm_rejects_unstaked_signers() {
+        let stakers = [100u128]
+            .iter()
+            .map(|v| (tmelcrypt::Ed25519SK::generate(), CoinValue(*v)))
+            .collect::<HashMap<_, _>>();
+        let (first, second) = two_blocks(&stakers);
+        let outsider = tmelcrypt::Ed25519SK::generate();
+        let cproof = sign_all(&second, stakers.keys().chain(std::iter::once(&outsider)));
+        assert_eq!(
+            second.confirm(cproof, first.inner_ref()).unwrap_err(),
+            ConfirmError::Unstaked(vec![outsider.to_public()])
+        );
+    }
+
+    fn always_true_genesis(
+        network: NetID,
+        params: Option<ChainParams>,
+    ) -> SealedState<InMemoryCas> {
+        GenesisConfig {
+            network,
+            init_coindata: always_true_coin(1 << 40),
+            params,
+            ..GenesisConfig::std_testnet()
+        }
+        .realize(&Database::new(InMemoryCas::default()))
+        .seal(None)
+    }
+
+    /// Builds the block after `genesis`, containing a single transaction of exactly the given weight. The weight comes from an extra script full of loops, which no input references and thus never runs.
+    fn block_of_weight(genesis: &SealedState<InMemoryCas>, weight: u128) -> Block {
+        let with_ballast = |outer: u16, inner: u16| {
+            let ballast = Covenant::from_ops(&[
+                OpCode::Loop(outer, 1),
+                OpCode::Loop(u16::MAX, 1),
+                OpCode::Noop,
+                OpCode::Loop(inner, 1),
+                OpCode::Noop,
+            ])
+            .unwrap();
+            let fee = 1 << 32;
+            Transaction::new(TxKind::Normal)
+                .add_input(CoinID::zero_zero())
+                .add_output(always_true_coin((1 << 40) - fee))
+                .with_fee(fee.into())
+                .add_script(Covenant::always_true())
+                .add_script(ballast)
+        };
+        // the ballast weighs outer * 2^16 + inner + 2 and its encoding has a fixed size
+        let remaining = weight - with_ballast(0, 0).weight();
+        let tx = with_ballast(
+            (remaining >> 16).try_into().unwrap(),
+            (remaining & 0xffff).try_into().unwrap(),
+        );
+        let mut next = genesis.next_state();
+        next.apply_tx(&tx).unwrap();
+        next.seal(None).to_block()
+    }
+
+    #[test]
+    fn block_weight_boundary() {
+        let params = ChainParams::custom().with_activation_height(Tip::Tip903, BlockHeight(0));
+        let genesis = always_true_genesis(NetID::Custom02, Some(params));
+        let block = block_of_weight(&genesis, MAX_BLOCK_WEIGHT);
+        assert_eq!(block.weight(), MAX_BLOCK_WEIGHT);
+        assert!(genesis.apply_block(&block).is_ok());
+
+        let block = block_of_weight(&genesis, MAX_BLOCK_WEIGHT + 1);
+        assert!(matches!(
+            genesis.apply_block(&block),
+            Err(StateError::BlockTooHeavy {
+                weight,
+                max: MAX_BLOCK_WEIGHT
+            }) if weight == MAX_BLOCK_WEIGHT + 1
+        ));
+    }
+
+    #[test]
+    fn block_weight_before_activation() {
+        for network in [NetID::Mainnet, NetID::Testnet] {
+            let genesis = always_true_genesis(network, None);
+            let block = block_of_weight(&genesis, MAX_BLOCK_WEIGHT + 1);
+            assert!(genesis.apply_block(&block).is_ok());
+        }
+    }
+
+    #[test]
+    fn block_weight_activation_height() {
+        let mut state = create_state(&HashMap::new(), 0);
+        // no network schedules TIP 903 by default
+        for network in [NetID::Mainnet, NetID::Testnet, NetID::Custom02] {
+            state.network = network;
+            state.params = ChainParams::for_network(network);
+            assert_eq!(state.params.tip_903_height, TIP_903_HEIGHT);
+            state.height = 0.into();
+            assert_eq!(state.max_block_weight(), None);
+            state.height = TIP_903_HEIGHT - 1.into();
+            assert_eq!(state.max_block_weight(), None);
+            state.height = TIP_903_HEIGHT;
+            assert_eq!(state.max_block_weight(), Some(MAX_BLOCK_WEIGHT));
+        }
+        // custom networks get the limit if they opt in
+        state.params = state.params.with_activation_height(Tip::Tip903, 0.into());
+        state.height = 0.into();
+        assert_eq!(state.max_block_weight(), Some(MAX_BLOCK_WEIGHT));
+    }
+
+    #[test]
+    fn custom_network_params() {
+        let genesis_with = |network, tip_903_height| {
+            GenesisConfig {
+                network,
+                init_coindata: always_true_coin(1 << 40),
+                params: Some(ChainParams {
+                    tip_903_height: BlockHeight(tip_903_height),
+                    max_block_weight: 1_000_000,
+                    ..ChainParams::custom()
+                }),
+                ..GenesisConfig::std_testnet()
+            }
+            .realize(&Database::new(InMemoryCas::default()))
+            .seal(None)
+        };
+
+        // TIP 903 activates at the overridden height, with the overridden limit
+        let early = genesis_with(NetID::Custom02, 1);
+        assert_eq!(early.inner_ref().params.tip_903_height, BlockHeight(1));
+        assert_eq!(early.inner_ref().max_block_weight(), None);
+        assert_eq!(early.next_state().max_block_weight(), Some(1_000_000));
+        let block = block_of_weight(&early, 1_000_001);
+        assert!(matches!(
+            early.apply_block(&block),
+            Err(StateError::BlockTooHeavy { max: 1_000_000, .. })
+        ));
+        let late = genesis_with(NetID::Custom02, 2);
+        assert!(late.apply_block(&block_of_weight(&late, 1_000_001)).is_ok());
+
+        // the parameters survive the partial encoding, and must be the network's
+        let db = early.inner_ref().coins.mapping.database();
+        let params = early.inner_ref().params;
+        let encoding = early.partial_encoding();
+        let decoded = SealedState::from_partial_encoding(&encoding, &params, &db).unwrap();
+        assert_eq!(decoded.inner_ref().params, params);
+        assert_eq!(
+            SealedState::from_partial_encoding(&encoding, &ChainParams::custom(), &db).unwrap_err(),
+            DecodeError::ParamsMismatch(Box::new(params))
+        );
+        // headers do not record them, so they are taken from the caller
+        let restored = State::from_header(early.header(), &params, &db).unwrap();
+        assert_eq!(restored.params, params);
+        let inner = early.inner_ref().partial_encoding();
+        let restored = State::from_partial_encoding(&inner, &params, &db).unwrap();
+        assert_eq!(restored.params, params);
+
+        // without them, only encodings that record them can be restored
+        #[allow(deprecated)]
+        let decoded = SealedState::from_partial_encoding_infallible(&encoding, &db);
+        assert_eq!(decoded.inner_ref().params, params);
+        let unversioned = stdcode::serialize(&(inner, None::<crate::ProposerAction>)).unwrap();
+        assert_eq!(
+            super::encoding::decode(&unversioned, None, &db).unwrap_err(),
+            DecodeError::MissingParams(NetID::Custom02)
+        );
+        assert!(super::encoding::decode(&unversioned, Some(&params), &db).is_ok());
+
+        // and cannot be overridden on testnet
+        let testnet = genesis_with(NetID::Testnet, 1);
+        assert_eq!(testnet.inner_ref().params, ChainParams::mainnet());
+        assert_eq!(
+            State::from_header(testnet.header(), &params, &db).unwrap_err(),
+            DecodeError::BadParams(ParamsError::NotDefault(NetID::Testnet))
+        );
+    }
+
+    #[test]
+    fn invalid_params() {
+        let config = |params| GenesisConfig {
+            network: NetID::Custom02,
+            params: Some(params),
+            ..GenesisConfig::std_testnet()
+        };
+        let db = Database::new(InMemoryCas::default());
+        let cases = [
+            (
+                ChainParams {
+                    swap_fee_micro: MICRO_CONVERTER + 1,
+                    ..ChainParams::custom()
+                },
+                ParamsError::SwapFeeTooHigh(MICRO_CONVERTER + 1),
+            ),
+            (
+                ChainParams {
+                    stake_epoch: 0,
+                    ..ChainParams::custom()
+                },
+                ParamsError::ZeroStakeEpoch,
+            ),
+            (
+                ChainParams {
+                    pegging_throttler_tip_902: 0,
+                    ..ChainParams::custom()
+                },
+                ParamsError::ZeroPeggingThrottler,
+            ),
+        ];
+        let valid = config(ChainParams::custom()).realize(&db).seal(None);
+        for (params, err) in cases {
+            assert_eq!(config(params).try_realize(&db).unwrap_err(), err);
+            assert_eq!(
+                State::from_header(valid.header(), &params, &db).unwrap_err(),
+                DecodeError::BadParams(err)
+            );
+        }
+        // a whole swap as the fee is still allowed
+        let params = ChainParams {
+            swap_fee_micro: MICRO_CONVERTER,
+            ..ChainParams::custom()
+        };
+        assert!(config(params).try_realize(&db).is_ok());
+    }
+}
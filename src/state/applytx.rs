@@ -3,24 +3,30 @@ use crate::{
     melvm::{Address, CovenantEnv},
     stake::StakeDoc,
     state::melmint,
-    BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, NetID, State, StateError,
-    Transaction, TxHash, TxKind,
+    state::observer::{StateEvent, StateObserver},
+    state::supply,
+    BatchError, BatchPhase, BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, NetID,
+    State, StateError, Transaction, TxHash, TxKind,
 };
 
-use std::convert::TryInto;
+use std::{collections::BTreeMap, convert::TryInto};
 
 use dashmap::DashMap;
 use novasmt::ContentAddrStore;
 use parking_lot::Mutex;
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use rustc_hash::FxHashMap;
 use tmelcrypt::HashVal;
 
-/// A mutable "handle" to a particular State. Can be "committed" like a database transaction.
+/// A "handle" to a particular State that buffers all changes. Can be turned into a [StateDiff] and then "committed" like a database transaction.
 pub(crate) struct StateHandle<'a, C: ContentAddrStore> {
-    state: &'a mut State<C>,
+    state: &'a State<C>,
 
-    coin_cache: DashMap<CoinID, Option<CoinDataHeight>>,
+    coin_cache: DashMap<CoinID, Cached<CoinDataHeight>>,
+    /// Coins of the underlying state that were spent through this handle, with their old data.
+    spent_coins: DashMap<CoinID, CoinDataHeight>,
+    /// Coins that were both created and spent through this handle.
+    transient_coins: DashMap<CoinID, CoinDataHeight>,
     transactions_cache: DashMap<TxHash, Transaction>,
 
     fee_pool_cache: CoinValue,
@@ -28,19 +34,128 @@ pub(crate) struct StateHandle<'a, C: ContentAddrStore> {
 
     dosc_speed_cache: Mutex<u128>,
 
-    stakes_cache: DashMap<TxHash, StakeDoc>,
+    stakes_cache: DashMap<TxHash, Cached<StakeDoc>>,
 }
 
-fn faucet_dedup_pseudocoin(txhash: TxHash) -> CoinID {
+/// A value in a [StateHandle]'s cache. Writes don't look up the value they replace, so that applying transactions doesn't pay for [StateHandle::into_diff].
+struct Cached<T> {
+    after: Option<T>,
+    /// Whether the value was written through the handle, rather than only read from the underlying state.
+    written: bool,
+}
+
+impl<T> Cached<T> {
+    fn read(value: Option<T>) -> Self {
+        Self {
+            after: value,
+            written: false,
+        }
+    }
+}
+
+/// The changes that applying a batch of transactions makes to a [State]. Obtained through [State::simulate_tx_batch] without touching the state itself.
+#[derive(Clone, Debug, Default)]
+pub struct StateDiff {
+    /// Coins that did not exist before the batch and are unspent after it.
+    pub coins_created: BTreeMap<CoinID, CoinDataHeight>,
+    /// Coins that existed before the batch and were spent by it, with their old data.
+    pub coins_spent: BTreeMap<CoinID, CoinDataHeight>,
+    /// Coins that were created by the batch and spent within it as well. They never appear in the state.
+    pub coins_transient: BTreeMap<CoinID, CoinDataHeight>,
+    /// Transactions in the batch.
+    pub transactions: BTreeMap<TxHash, Transaction>,
+    /// Stake documents added by the batch.
+    pub stakes_added: BTreeMap<TxHash, StakeDoc>,
+    /// Increase in the fee pool (base fees).
+    pub fee_pool_delta: CoinValue,
+    /// Increase in the tips.
+    pub tips_delta: CoinValue,
+    /// Increase in the DOSC speed.
+    pub dosc_speed_delta: u128,
+}
+
+impl StateDiff {
+    /// Tells an observer about every change in the diff, as if it were committed to a state with the given fee multiplier. Events are grouped by kind: collected fees, coins created and spent within the batch, spent coins, created coins, and added stakes.
+    pub(crate) fn notify(&self, fee_multiplier: u128, observer: &(impl StateObserver + ?Sized)) {
+        self.transactions.iter().for_each(|(txhash, tx)| {
+            let base_fee = tx.base_fee(fee_multiplier, 0);
+            observer.on_event(StateEvent::FeeCollected {
+                txhash: *txhash,
+                base_fee,
+                tip: tx.fee - base_fee,
+            })
+        });
+        self.coins_transient.iter().for_each(|(coin_id, coin)| {
+            observer.on_event(StateEvent::CoinCreated {
+                coin_id: *coin_id,
+                coin,
+            });
+            observer.on_event(StateEvent::CoinSpent { coin_id: *coin_id });
+        });
+        self.coins_spent
+            .keys()
+            .for_each(|coin_id| observer.on_event(StateEvent::CoinSpent { coin_id: *coin_id }));
+        self.coins_created.iter().for_each(|(coin_id, coin)| {
+            observer.on_event(StateEvent::CoinCreated {
+                coin_id: *coin_id,
+                coin,
+            })
+        });
+        self.stakes_added.iter().for_each(|(txhash, stake)| {
+            observer.on_event(StateEvent::StakeAdded {
+                txhash: *txhash,
+                stake,
+            })
+        });
+    }
+
+    /// Commits all the changes to the given state, at once. The state must be the one the diff was computed against.
+    pub(crate) fn commit<C: ContentAddrStore>(&self, state: &mut State<C>) {
+        // commit coins
+        supply::update(state, |supply| {
+            for coin in self.coins_spent.values() {
+                supply.remove_coin(&coin.coin_data)?;
+            }
+            for coin in self.coins_created.values() {
+                supply.add_coin(&coin.coin_data)?;
+            }
+            Ok(())
+        });
+        self.coins_spent.keys().for_each(|key| {
+            state.coins.delete(key);
+        });
+        self.coins_created.iter().for_each(|(key, value)| {
+            state.coins.insert(*key, value.clone());
+        });
+
+        // commit txx
+        self.transactions.iter().for_each(|(key, value)| {
+            state.transactions.insert(*key, value.clone());
+        });
+
+        // commit fees
+        state.fee_pool += self.fee_pool_delta;
+        state.tips += self.tips_delta;
+
+        // commit stakes
+        self.stakes_added.iter().for_each(|(key, value)| {
+            state.stakes.insert(*key, *value);
+        });
+
+        state.dosc_speed += self.dosc_speed_delta;
+    }
+}
+
+pub(crate) fn faucet_dedup_pseudocoin(txhash: TxHash) -> CoinID {
     CoinID {
-        txhash: tmelcrypt::hash_keyed(b"fdp", &txhash.0).into(),
+        txhash: tmelcrypt::hash_keyed(b"fdp", txhash.0).into(),
         index: 0,
     }
 }
 
 impl<'a, C: ContentAddrStore> StateHandle<'a, C> {
     /// Creates a new state handle.
-    pub fn new(state: &'a mut State<C>) -> Self {
+    pub fn new(state: &'a State<C>) -> Self {
         let fee_pool_cache = state.fee_pool;
         let tips_cache = state.tips;
         let dosc_speed = state.dosc_speed;
@@ -48,6 +163,8 @@ impl<'a, C: ContentAddrStore> StateHandle<'a, C> {
             state,
 
             coin_cache: DashMap::new(),
+            spent_coins: DashMap::new(),
+            transient_coins: DashMap::new(),
             transactions_cache: DashMap::new(),
 
             fee_pool_cache,
@@ -60,78 +177,136 @@ impl<'a, C: ContentAddrStore> StateHandle<'a, C> {
     }
 
     /// Applies a batch of transactions, returning an error if any of them fail. Consumes and re-returns the handle; if any fail the handle is gone.
-    pub fn apply_tx_batch(mut self, txx: &[Transaction]) -> Result<Self, StateError> {
-        for tx in txx.iter() {
-            if tx.kind == TxKind::Faucet {
-                let pseudocoin = faucet_dedup_pseudocoin(tx.hash_nosigs());
-                if self.state.coins.get(&pseudocoin).0.is_some() {
-                    return Err(StateError::DuplicateTx);
-                } else {
-                    self.state.coins.insert(
-                        pseudocoin,
-                        CoinDataHeight {
-                            coin_data: CoinData {
-                                denom: Denom::Mel,
-                                value: 0.into(),
-                                additional_data: vec![],
-                                covhash: HashVal::default().into(),
-                            },
-                            height: 0.into(),
-                        },
-                    );
-                }
-            }
-            if !tx.is_well_formed() {
-                return Err(StateError::MalformedTx);
-            }
-            if tx.kind == TxKind::Faucet && self.state.network == NetID::Mainnet {
-                return Err(StateError::UnbalancedInOut);
-            }
-            self.transactions_cache.insert(tx.hash_nosigs(), tx.clone());
-            self.apply_tx_fees(tx)?;
+    ///
+    /// The error identifies the first failing transaction (by index in the batch) of the first phase that fails.
+    pub fn apply_tx_batch(mut self, txx: &[Transaction]) -> Result<Self, BatchError> {
+        // which transaction spends each coin; this catches double-spends deterministically before anything runs in parallel
+        let mut spenders: FxHashMap<CoinID, usize> = FxHashMap::default();
+        for (tx_index, tx) in txx.iter().enumerate() {
+            self.apply_tx_preliminary(tx, tx_index, &mut spenders)
+                .map_err(|error| BatchError::new(tx_index, tx, BatchPhase::Preliminary, error))?;
         }
         // apply specials in parallel
-        txx.par_iter()
-            .filter(|tx| tx.kind != TxKind::Normal && tx.kind != TxKind::Faucet)
-            .map(|tx| self.apply_tx_special(tx))
-            .collect::<Result<_, _>>()?;
+        if let Some(err) = txx
+            .par_iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.kind != TxKind::Normal && tx.kind != TxKind::Faucet)
+            .find_map_first(|(tx_index, tx)| {
+                self.apply_tx_special(tx)
+                    .err()
+                    .map(|error| BatchError::new(tx_index, tx, BatchPhase::Special, error))
+            })
+        {
+            return Err(err);
+        }
         // apply outputs in parallel
         txx.par_iter().for_each(|tx| self.apply_tx_outputs(tx));
         // apply inputs in parallel
-        txx.par_iter()
-            .map(|tx| self.apply_tx_inputs(tx))
-            .collect::<Result<_, _>>()?;
+        if let Some(err) = txx.par_iter().enumerate().find_map_first(|(tx_index, tx)| {
+            self.apply_tx_inputs(tx)
+                .err()
+                .map(|error| BatchError::new(tx_index, tx, BatchPhase::Inputs, error))
+        }) {
+            return Err(err);
+        }
         Ok(self)
     }
 
-    /// Commits all the changes in this handle, at once.
-    pub fn commit(self) {
-        // commit coins
-        self.coin_cache.into_iter().for_each(|(key, value)| {
-            if let Some(value) = value {
-                self.state.coins.insert(key, value);
-            } else {
-                self.state.coins.delete(&key);
-            }
-        });
+    /// Consumes the handle, returning everything it changed relative to the underlying state. Coins and stakes that were written without being read are looked up in the state now, so that the diff is exact even if a write replaced or repeated an existing value.
+    pub fn into_diff(self) -> StateDiff {
+        self.into_diff_with(true)
+    }
 
-        // commit txx
-        self.transactions_cache
-            .into_iter()
-            .for_each(|(key, value)| {
-                self.state.transactions.insert(key, value);
-            });
+    /// Like [StateHandle::into_diff], but without looking anything up: coins and stakes written without being read are taken to be new. That is the case in every valid batch, since they are keyed by the hash of the transaction that created them, so this is what applying a batch commits.
+    pub fn into_changes(self) -> StateDiff {
+        self.into_diff_with(false)
+    }
 
-        // commit fees
-        self.state.fee_pool = self.fee_pool_cache;
-        self.state.tips = self.tips_cache;
+    fn into_diff_with(self, look_up: bool) -> StateDiff {
+        let state = self.state;
+        // coins: the cache also contains coins that were merely read
+        let mut diff = StateDiff {
+            coins_spent: self.spent_coins.into_iter().collect(),
+            ..Default::default()
+        };
+        for (key, cached) in self.coin_cache.into_iter() {
+            if let (Some(value), true) = (cached.after, cached.written) {
+                match look_up.then(|| state.coins.get(&key).0).flatten() {
+                    Some(existing) if existing == value => {}
+                    existing => {
+                        // a replaced coin is spent as well, so that the diff accounts for its old data
+                        diff.coins_spent
+                            .extend(existing.map(|existing| (key, existing)));
+                        diff.coins_created.insert(key, value);
+                    }
+                }
+            }
+        }
+        for (key, value) in self.transient_coins.into_iter() {
+            if let Some(existing) = look_up.then(|| state.coins.get(&key).0).flatten() {
+                diff.coins_spent.insert(key, existing);
+            }
+            diff.coins_transient.insert(key, value);
+        }
+        diff.transactions = self.transactions_cache.into_iter().collect();
 
-        // commit stakes
-        self.stakes_cache.into_iter().for_each(|(key, value)| {
-            self.state.stakes.insert(key, value);
-        });
+        // stakes: likewise, only keep the ones that are new
+        for (key, cached) in self.stakes_cache.into_iter() {
+            if let (Some(value), true) = (cached.after, cached.written) {
+                if !look_up || state.stakes.get(&key).0.is_none() {
+                    diff.stakes_added.insert(key, value);
+                }
+            }
+        }
 
-        self.state.dosc_speed = *self.dosc_speed_cache.lock()
+        diff.fee_pool_delta = self.fee_pool_cache - state.fee_pool;
+        diff.tips_delta = self.tips_cache - state.tips;
+        diff.dosc_speed_delta = *self.dosc_speed_cache.lock() - state.dosc_speed;
+        diff
+    }
+
+    fn apply_tx_preliminary(
+        &mut self,
+        tx: &Transaction,
+        tx_index: usize,
+        spenders: &mut FxHashMap<CoinID, usize>,
+    ) -> Result<(), StateError> {
+        if tx.kind == TxKind::Faucet {
+            let pseudocoin = faucet_dedup_pseudocoin(tx.hash_nosigs());
+            if self.get_coin(pseudocoin).is_some() {
+                return Err(StateError::DuplicateTx);
+            } else {
+                self.set_coin(
+                    pseudocoin,
+                    CoinDataHeight {
+                        coin_data: CoinData {
+                            denom: Denom::Mel,
+                            value: 0.into(),
+                            additional_data: vec![],
+                            covhash: HashVal::default().into(),
+                        },
+                        height: 0.into(),
+                    },
+                );
+            }
+        }
+        if !tx.is_well_formed_with(self.state.params.max_coinval) {
+            return Err(StateError::MalformedTx);
+        }
+        if tx.kind == TxKind::Faucet && self.state.network == NetID::Mainnet {
+            return Err(StateError::UnbalancedInOut);
+        }
+        for coin in tx.inputs.iter() {
+            if let Some(first_tx) = spenders.insert(*coin, tx_index) {
+                return Err(StateError::DoubleSpend {
+                    coin: *coin,
+                    first_tx,
+                    second_tx: tx_index,
+                });
+            }
+        }
+        self.transactions_cache.insert(tx.hash_nosigs(), tx.clone());
+        self.apply_tx_fees(tx)
     }
 
     fn apply_tx_inputs(&self, tx: &Transaction) -> Result<(), StateError> {
@@ -190,7 +365,7 @@ impl<'a, C: ContentAddrStore> StateHandle<'a, C> {
                     ) {
                         return Err(StateError::ViolatesScript(coin_data.coin_data.covhash));
                     }
-                    self.del_coin(*coin_id);
+                    self.del_coin(*coin_id, coin_data.clone());
                     in_coins.insert(
                         coin_data.coin_data.denom,
                         in_coins.get(&coin_data.coin_data.denom).unwrap_or(&0)
@@ -270,7 +445,7 @@ impl<'a, C: ContentAddrStore> StateHandle<'a, C> {
     }
 
     fn apply_tx_special_doscmint(&self, tx: &Transaction) -> Result<(), StateError> {
-        let coin_id = *tx.inputs.get(0).ok_or(StateError::MalformedTx).unwrap();
+        let coin_id = *tx.inputs.first().ok_or(StateError::MalformedTx).unwrap();
         let coin_data = self.get_coin(coin_id).ok_or(StateError::MalformedTx)?;
         // make sure the time is long enough that we can easily measure it
         if (self.state.height - coin_data.height).0 < 100 {
@@ -279,8 +454,8 @@ impl<'a, C: ContentAddrStore> StateHandle<'a, C> {
         }
         // construct puzzle seed
         let chi = tmelcrypt::hash_keyed(
-            &self.state.history.get(&coin_data.height).0.unwrap().hash(),
-            &stdcode::serialize(tx.inputs.get(0).ok_or(StateError::MalformedTx).unwrap()).unwrap(),
+            self.state.history.get(&coin_data.height).0.unwrap().hash(),
+            stdcode::serialize(tx.inputs.first().ok_or(StateError::MalformedTx).unwrap()).unwrap(),
         );
         // get difficulty and proof
         let (difficulty, proof_bytes): (u32, Vec<u8>) =
@@ -330,16 +505,14 @@ impl<'a, C: ContentAddrStore> StateHandle<'a, C> {
         // first we check that the data is correct
         let stake_doc: StakeDoc =
             stdcode::deserialize(&tx.data).map_err(|_| StateError::MalformedTx)?;
-        let curr_epoch = self.state.height.epoch();
+        let curr_epoch = self.state.epoch();
         // then we check that the first coin is valid
-        let first_coin = tx.outputs.get(0).ok_or(StateError::MalformedTx)?;
+        let first_coin = tx.outputs.first().ok_or(StateError::MalformedTx)?;
 
         let is_first_coin_not_a_sym: bool = first_coin.denom != Denom::Sym;
 
         // Are we operating under OLD BUGGY RULES?
-        if (self.state.network == NetID::Mainnet || self.state.network == NetID::Testnet)
-            && self.state.height.0 < 500000
-        {
+        if self.state.height < self.state.params.old_staking_rules_until {
             log::warn!("LETTING THROUGH BAD STAKING TRANSACTION UNDER OLD BUGGY RULES");
             return Ok(());
         }
@@ -363,36 +536,333 @@ impl<'a, C: ContentAddrStore> StateHandle<'a, C> {
     fn get_coin(&self, coin_id: CoinID) -> Option<CoinDataHeight> {
         self.coin_cache
             .entry(coin_id)
-            .or_insert_with(|| self.state.coins.get(&coin_id).0)
-            .value()
+            .or_insert_with(|| Cached::read(self.state.coins.get(&coin_id).0))
+            .after
             .clone()
     }
 
     fn set_coin(&self, coin_id: CoinID, value: CoinDataHeight) {
-        self.coin_cache.insert(coin_id, Some(value));
+        self.coin_cache.insert(
+            coin_id,
+            Cached {
+                after: Some(value),
+                written: true,
+            },
+        );
     }
 
-    fn del_coin(&self, coin_id: CoinID) {
-        self.coin_cache.insert(coin_id, None);
+    /// Spends a coin, given its data as returned by [StateHandle::get_coin].
+    fn del_coin(&self, coin_id: CoinID, value: CoinDataHeight) {
+        let mut cached = self
+            .coin_cache
+            .entry(coin_id)
+            .or_insert_with(|| Cached::read(Some(value.clone())));
+        if cached.written {
+            self.transient_coins.insert(coin_id, value);
+        } else {
+            self.spent_coins.insert(coin_id, value);
+        }
+        cached.after = None;
+        cached.written = true;
     }
 
     fn get_stake(&self, txhash: TxHash) -> Option<StakeDoc> {
-        if let Some(cached_sd) = self.stakes_cache.get(&txhash).as_deref() {
-            Some(cached_sd).cloned()
+        if let Some(cached) = self.stakes_cache.get(&txhash).as_deref() {
+            cached.after
         } else if let Some(sd) = self.state.stakes.get(&txhash).0 {
-            self.stakes_cache.insert(txhash, sd)
+            self.stakes_cache.insert(txhash, Cached::read(Some(sd)));
+            Some(sd)
         } else {
             None
         }
     }
 
     fn set_stake(&self, txhash: TxHash, sdoc: StakeDoc) {
-        self.stakes_cache.insert(txhash, sdoc);
+        self.stakes_cache.insert(
+            txhash,
+            Cached {
+                after: Some(sdoc),
+                written: true,
+            },
+        );
     }
 }
 
 #[cfg(test)]
 pub(crate) mod tests {
+    use novasmt::{Database, InMemoryCas};
+
+    use crate::melvm::Covenant;
+    use crate::testing::functions::{always_true_coin, valid_txx, valid_txx_state};
+    use crate::{
+        BatchPhase, CoinData, CoinDataHeight, CoinID, Denom, GenesisConfig, NetID, StakeDoc,
+        StateError, Transaction, TxKind,
+    };
+
+    /// Runs the closure in a dedicated multi-threaded rayon pool.
+    fn in_parallel_pool<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(8)
+            .build()
+            .unwrap()
+            .install(f)
+    }
+
+    /// Creates a signed transaction that sends the genesis coin of [valid_txx_state] back to its owner.
+    fn spend_genesis(
+        pk: tmelcrypt::Ed25519PK,
+        sk: tmelcrypt::Ed25519SK,
+        value: u128,
+    ) -> Transaction {
+        let covenant = Covenant::std_ed25519_pk_legacy(pk);
+        Transaction::new(TxKind::Normal)
+            .add_input(CoinID::zero_zero())
+            .add_output(CoinData {
+                covhash: covenant.hash(),
+                value: value.into(),
+                denom: Denom::Mel,
+                additional_data: vec![],
+            })
+            .with_fee((crate::MICRO_CONVERTER * 1000 - value).into())
+            .add_script(covenant)
+            .signed_ed25519(sk)
+    }
+
+    #[test]
+    fn simulate_matches_apply() {
+        let sk = tmelcrypt::Ed25519SK::generate();
+        let pk = sk.to_public();
+        let txx = valid_txx((pk, sk));
+        let state = valid_txx_state(pk);
+
+        // simulating must not touch the state
+        let diff = state.simulate_tx_batch(&txx).unwrap();
+        assert_eq!(state.transactions.root_hash(), Default::default());
+        assert!(state.coins.get(&CoinID::zero_zero()).0.is_some());
+
+        // the transactions form a chain, so only the genesis coin is spent and only the last output survives
+        assert_eq!(diff.transactions.len(), txx.len());
+        assert_eq!(diff.coins_spent.len(), 1);
+        assert!(diff.coins_spent.contains_key(&CoinID::zero_zero()));
+        assert_eq!(diff.coins_created.len(), 1);
+        assert!(diff
+            .coins_created
+            .contains_key(&txx.last().unwrap().output_coinid(0)));
+        assert!(diff.stakes_added.is_empty());
+        assert_eq!(
+            diff.fee_pool_delta + diff.tips_delta,
+            txx.iter().map(|tx| tx.fee).sum()
+        );
+
+        // the diff describes exactly what applying does
+        let mut applied = state.clone();
+        applied.apply_tx_batch(&txx).unwrap();
+        assert_eq!(applied.fee_pool, state.fee_pool + diff.fee_pool_delta);
+        assert_eq!(applied.tips, state.tips + diff.tips_delta);
+        assert_eq!(applied.dosc_speed, state.dosc_speed + diff.dosc_speed_delta);
+        assert_eq!(applied.coins.get(&CoinID::zero_zero()).0, None);
+        diff.coins_created.iter().for_each(|(id, cdh)| {
+            assert_eq!(applied.coins.get(id).0.as_ref(), Some(cdh));
+        });
+    }
+
+    #[test]
+    fn only_simulation_looks_up_replaced_coins() {
+        let pk = tmelcrypt::Ed25519SK::generate().to_public();
+        let state = valid_txx_state(pk);
+        let existing = state.coins.get(&CoinID::zero_zero()).0.unwrap();
+        let mut replacement = existing.clone();
+        replacement.height += crate::BlockHeight(1);
+
+        let replace = || {
+            let handle = super::StateHandle::new(&state);
+            handle.set_coin(CoinID::zero_zero(), replacement.clone());
+            handle
+        };
+        let diff = replace().into_diff();
+        assert_eq!(diff.coins_spent.get(&CoinID::zero_zero()), Some(&existing));
+        assert_eq!(
+            diff.coins_created.get(&CoinID::zero_zero()),
+            Some(&replacement)
+        );
+
+        // applying doesn't look anything up, but still writes the same trees
+        let changes = replace().into_changes();
+        assert!(changes.coins_spent.is_empty());
+        assert_eq!(changes.coins_created, diff.coins_created);
+    }
+
+    #[test]
+    fn batch_error_identifies_fee_failure() {
+        let sk = tmelcrypt::Ed25519SK::generate();
+        let pk = sk.to_public();
+        let mut txx = valid_txx((pk, sk));
+        let state = valid_txx_state(pk);
+        txx[42].fee = 0.into();
+        let err = state.simulate_tx_batch(&txx).unwrap_err();
+        assert_eq!(err.tx_index, 42);
+        assert_eq!(err.txhash, txx[42].hash_nosigs());
+        assert_eq!(err.phase, BatchPhase::Preliminary);
+        assert!(matches!(err.error, StateError::InsufficientFees(_)));
+    }
+
+    #[test]
+    fn batch_error_identifies_script_failure() {
+        let sk = tmelcrypt::Ed25519SK::generate();
+        let pk = sk.to_public();
+        let mut txx = valid_txx((pk, sk));
+        let state = valid_txx_state(pk);
+        // stripping the signatures does not change the hash, so only this transaction fails
+        txx[17].sigs.clear();
+        let err = state.simulate_tx_batch(&txx).unwrap_err();
+        assert_eq!(err.tx_index, 17);
+        assert_eq!(err.txhash, txx[17].hash_nosigs());
+        assert_eq!(err.phase, BatchPhase::Inputs);
+        assert!(matches!(err.error, StateError::ViolatesScript(_)));
+    }
+
+    #[test]
+    fn double_spend_across_transactions() {
+        let sk = tmelcrypt::Ed25519SK::generate();
+        let pk = sk.to_public();
+        let state = valid_txx_state(pk);
+        let first = spend_genesis(pk, sk, 990_000_000);
+        let second = spend_genesis(pk, sk, 980_000_000);
+        for _ in 0..50 {
+            let err =
+                in_parallel_pool(|| state.simulate_tx_batch(&[first.clone(), second.clone()]))
+                    .unwrap_err();
+            assert_eq!(err.tx_index, 1);
+            assert_eq!(err.phase, BatchPhase::Preliminary);
+            match err.error {
+                StateError::DoubleSpend {
+                    coin,
+                    first_tx,
+                    second_tx,
+                } => {
+                    assert_eq!(coin, CoinID::zero_zero());
+                    assert_eq!(first_tx, 0);
+                    assert_eq!(second_tx, 1);
+                }
+                other => panic!("unexpected error {:?}", other),
+            }
+        }
+        // each of them is fine on its own
+        assert!(state.simulate_tx_batch(&[first]).is_ok());
+        assert!(state.simulate_tx_batch(&[second]).is_ok());
+    }
+
+    #[test]
+    fn double_spend_within_transaction() {
+        let sk = tmelcrypt::Ed25519SK::generate();
+        let pk = sk.to_public();
+        let state = valid_txx_state(pk);
+        let mut tx = spend_genesis(pk, sk, 990_000_000);
+        tx.inputs.push(CoinID::zero_zero());
+        let tx = tx.signed_ed25519(sk);
+        for _ in 0..50 {
+            let err = in_parallel_pool(|| state.simulate_tx_batch(std::slice::from_ref(&tx)))
+                .unwrap_err();
+            assert_eq!(err.tx_index, 0);
+            assert!(matches!(
+                err.error,
+                StateError::DoubleSpend { first_tx, second_tx, .. } if first_tx == second_tx
+            ));
+        }
+    }
+
+    #[test]
+    fn double_spend_in_long_batch() {
+        let sk = tmelcrypt::Ed25519SK::generate();
+        let pk = sk.to_public();
+        let state = valid_txx_state(pk);
+        let mut txx = valid_txx((pk, sk));
+        // a conflicting spend of the genesis coin at the very end of a valid chain
+        txx.push(spend_genesis(pk, sk, 990_000_000));
+        for _ in 0..10 {
+            let err = in_parallel_pool(|| state.simulate_tx_batch(&txx)).unwrap_err();
+            assert_eq!(err.tx_index, txx.len() - 1);
+            assert!(matches!(err.error, StateError::DoubleSpend { .. }));
+        }
+    }
+
+    #[test]
+    fn staked_coins_are_locked() {
+        let db = Database::new(InMemoryCas::default());
+        let mut genesis = GenesisConfig {
+            network: NetID::Custom02,
+            init_coindata: always_true_coin(1 << 40),
+            ..GenesisConfig::std_testnet()
+        }
+        .realize(&db);
+        let sym_coin = CoinData {
+            denom: Denom::Sym,
+            ..always_true_coin(1000)
+        };
+        let sym_coin_id = CoinID {
+            txhash: tmelcrypt::HashVal([1; 32]).into(),
+            index: 0,
+        };
+        genesis.coins.insert(
+            sym_coin_id,
+            CoinDataHeight {
+                coin_data: sym_coin.clone(),
+                height: 0.into(),
+            },
+        );
+        genesis.supply = genesis.scan_supply([]).ok();
+        let fee = 1 << 20;
+        let stake = Transaction::new(TxKind::Stake)
+            .add_input(CoinID::zero_zero())
+            .add_input(sym_coin_id)
+            .add_output(sym_coin)
+            .add_output(always_true_coin((1 << 40) - fee))
+            .with_fee(fee.into())
+            .add_script(Covenant::always_true())
+            .with_data(
+                stdcode::serialize(&StakeDoc {
+                    pubkey: tmelcrypt::Ed25519SK::generate().to_public(),
+                    e_start: 1,
+                    e_post_end: 2,
+                    syms_staked: 1000.into(),
+                })
+                .unwrap(),
+            );
+        let mut next = genesis.seal(None).next_state();
+        next.apply_tx(&stake).unwrap();
+        let staked = next.seal(None).next_state();
+        assert!(staked.stakes.get(&stake.hash_nosigs()).0.is_some());
+
+        // every output of the staking transaction is locked, not only the staked syms
+        let unlock = Transaction::new(TxKind::Normal)
+            .add_input(stake.output_coinid(1))
+            .add_output(always_true_coin((1 << 40) - 2 * fee))
+            .with_fee(fee.into())
+            .add_script(Covenant::always_true());
+        let err = staked
+            .simulate_tx_batch(std::slice::from_ref(&unlock))
+            .unwrap_err();
+        assert!(matches!(err.error, StateError::CoinLocked));
+        let err = staked
+            .clone()
+            .apply_tx_batch_sequential(std::slice::from_ref(&unlock))
+            .unwrap_err();
+        assert!(matches!(err.error, StateError::CoinLocked));
+    }
+
+    #[test]
+    fn failed_batch_leaves_state_unchanged() {
+        let sk = tmelcrypt::Ed25519SK::generate();
+        let pk = sk.to_public();
+        let mut txx = valid_txx((pk, sk));
+        let mut state = valid_txx_state(pk);
+        let coins_before = state.coins.root_hash();
+        txx.last_mut().unwrap().fee = 0.into();
+        assert!(state.simulate_tx_batch(&txx).is_err());
+        assert!(state.apply_tx_batch(&txx).is_err());
+        assert_eq!(state.coins.root_hash(), coins_before);
+    }
+
     // use crate::melvm::Covenant;
     // use crate::state::applytx::StateHandle;
     // // use crate::testing::factory::*;
@@ -0,0 +1,251 @@
+use super::{State, Supply, SupplyError};
+use crate::{BlockHeight, CoinDataHeight, CoinValue, Denom, PoolKey, PoolState, StakeDoc, Tip};
+
+use novasmt::ContentAddrStore;
+use thiserror::Error;
+use tmelcrypt::HashVal;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+/// A violation of a global invariant of the state, found by [State::audit]
+pub enum AuditViolation {
+    #[error("built-in pool {0} is missing")]
+    MissingBuiltinPool(PoolKey),
+    #[error("pool {pool} has {lefts} lefts and {rights} rights for {liqs} liquidity tokens")]
+    InconsistentPool {
+        pool: PoolKey,
+        lefts: u128,
+        rights: u128,
+        liqs: u128,
+    },
+    #[error(
+        "{outstanding} liquidity tokens of pool {pool} are outstanding, but it only issued {liqs}"
+    )]
+    ExcessLiquidityTokens {
+        pool: PoolKey,
+        outstanding: CoinValue,
+        liqs: u128,
+    },
+    #[error("coin worth {} exceeds the maximum coin value", .0.coin_data.value)]
+    CoinTooLarge(CoinDataHeight),
+    #[error("stake ends in epoch {} but starts in epoch {}", .0.e_post_end, .0.e_start)]
+    EmptyStake(StakeDoc),
+    #[error("history is missing the header at height {0}")]
+    MissingHistory(BlockHeight),
+    #[error("history has a header of height {found} at height {height}")]
+    WrongHistory {
+        height: BlockHeight,
+        found: BlockHeight,
+    },
+    #[error("tracked supply {tracked:?} differs from the scanned supply {scanned:?}")]
+    SupplyMismatch { tracked: Supply, scanned: Supply },
+    #[error("the scanned supply is invalid: {0}")]
+    BadSupply(SupplyError),
+    #[error("the {0} tree contains a value that cannot be decoded")]
+    BadValue(&'static str),
+}
+
+/// Checks the global invariants of a state. A sealed state must have every built-in pool of its height, while an unsealed one only needs those of its parent.
+pub(crate) fn audit<C: ContentAddrStore>(state: &State<C>, sealed: bool) -> Vec<AuditViolation> {
+    let mut violations = Vec::new();
+
+    // built-in pools, as created by the last seal
+    let last_sealed = if sealed {
+        Some(state.height)
+    } else {
+        state.height.0.checked_sub(1).map(BlockHeight)
+    };
+    if let Some(height) = last_sealed {
+        let mut builtins = vec![
+            PoolKey::mel_and(Denom::Sym),
+            PoolKey::mel_and(Denom::NomDosc),
+        ];
+        if state.params.is_active(Tip::Tip902, height) {
+            builtins.push(PoolKey::new(Denom::NomDosc, Denom::Sym));
+        }
+        for pool in builtins {
+            if !matches!(state.pools.try_get(&pool), Ok((Some(_), _))) {
+                violations.push(AuditViolation::MissingBuiltinPool(pool));
+            }
+        }
+    }
+
+    // pools whose keys are known
+    let mut pools: Vec<(PoolKey, PoolState)> = Vec::new();
+    let mut unknown_pools = 0;
+    for (hashed, value) in state.pools.mapping.iter() {
+        if value.is_empty() {
+            continue;
+        }
+        match stdcode::deserialize::<PoolState>(&value) {
+            Ok(pool_state) => match state.pools.key_preimage(HashVal(hashed)) {
+                Some(pool) => pools.push((pool, pool_state)),
+                None => unknown_pools += 1,
+            },
+            Err(_) => violations.push(AuditViolation::BadValue("pools")),
+        }
+    }
+    let scanned = scan_coins(state, &pools, &mut violations);
+    for (pool, pool_state) in pools.iter() {
+        let (lefts, rights, liqs) = (pool_state.lefts, pool_state.rights, pool_state.liqs());
+        let consistent = if liqs == 0 {
+            lefts == 0 && rights == 0
+        } else {
+            lefts > 0 && rights > 0
+        };
+        if !consistent {
+            violations.push(AuditViolation::InconsistentPool {
+                pool: *pool,
+                lefts,
+                rights,
+                liqs,
+            });
+        }
+        let outstanding = scanned
+            .coins
+            .get(&pool.liq_token_denom())
+            .copied()
+            .unwrap_or_default();
+        if outstanding.0 > liqs {
+            violations.push(AuditViolation::ExcessLiquidityTokens {
+                pool: *pool,
+                outstanding,
+                liqs,
+            });
+        }
+    }
+    if let Some(tracked) = state.supply.as_ref() {
+        let bad_supply = violations
+            .iter()
+            .any(|v| matches!(v, AuditViolation::BadSupply(_)));
+        if unknown_pools == 0 && !bad_supply && *tracked != scanned {
+            violations.push(AuditViolation::SupplyMismatch {
+                tracked: tracked.clone(),
+                scanned,
+            });
+        }
+    }
+
+    for stake in state.stakes.try_val_iter() {
+        match stake {
+            Ok(stake) if stake.e_post_end <= stake.e_start => {
+                violations.push(AuditViolation::EmptyStake(stake))
+            }
+            Ok(_) => {}
+            Err(_) => violations.push(AuditViolation::BadValue("stakes")),
+        }
+    }
+
+    for height in (0..state.height.0).map(BlockHeight) {
+        match state.history.try_get(&height) {
+            Ok((Some(header), _)) if header.height != height => {
+                violations.push(AuditViolation::WrongHistory {
+                    height,
+                    found: header.height,
+                })
+            }
+            Ok((Some(_), _)) => {}
+            Ok((None, _)) => violations.push(AuditViolation::MissingHistory(height)),
+            Err(_) => violations.push(AuditViolation::BadValue("history")),
+        }
+    }
+
+    violations
+}
+
+/// Scans every coin, checking their values, and returns the supply of the coins and the given pools.
+fn scan_coins<C: ContentAddrStore>(
+    state: &State<C>,
+    pools: &[(PoolKey, PoolState)],
+    violations: &mut Vec<AuditViolation>,
+) -> Supply {
+    let mut scanned = Supply::default();
+    for coin in state.coins.try_val_iter() {
+        match coin {
+            Ok(coin) => {
+                if coin.coin_data.value > state.params.max_coinval {
+                    violations.push(AuditViolation::CoinTooLarge(coin.clone()));
+                }
+                if let Err(err) = scanned.add_coin(&coin.coin_data) {
+                    violations.push(AuditViolation::BadSupply(err));
+                }
+            }
+            Err(_) => violations.push(AuditViolation::BadValue("coins")),
+        }
+    }
+    for (pool, pool_state) in pools {
+        if let Err(err) = scanned.add_pool(*pool, pool_state) {
+            violations.push(AuditViolation::BadSupply(err));
+        }
+    }
+    scanned
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::functions::{always_true_coin, split_genesis_state};
+    use crate::{
+        AuditViolation, BlockHeight, CoinData, CoinDataHeight, Denom, PoolKey, StakeDoc,
+        MAX_COINVAL,
+    };
+
+    #[test]
+    fn consistent_states_pass() {
+        let (base, _) = split_genesis_state(2);
+        assert_eq!(base.audit(), vec![]);
+        assert_eq!(base.next_state().audit(), vec![]);
+    }
+
+    #[test]
+    fn finds_violations() {
+        let (base, split) = split_genesis_state(2);
+        let mut state = base.next_state();
+        let sym_pool = PoolKey::mel_and(Denom::Sym);
+        let liqs = state.pools.get(&sym_pool).0.unwrap().liqs();
+        state.pools.delete(&PoolKey::mel_and(Denom::NomDosc));
+        let huge = CoinDataHeight {
+            coin_data: always_true_coin(MAX_COINVAL.0 + 1),
+            height: state.height,
+        };
+        state.coins.insert(split.output_coinid(0), huge.clone());
+        state.coins.insert(
+            split.output_coinid(1),
+            CoinDataHeight {
+                coin_data: CoinData {
+                    denom: sym_pool.liq_token_denom(),
+                    ..always_true_coin(liqs + 1)
+                },
+                height: state.height,
+            },
+        );
+        let stake = StakeDoc {
+            pubkey: tmelcrypt::Ed25519SK::generate().to_public(),
+            e_start: 5,
+            e_post_end: 5,
+            syms_staked: 1.into(),
+        };
+        state
+            .stakes
+            .insert(tmelcrypt::hash_single(b"stake").into(), stake);
+        state.history.delete(&BlockHeight(0));
+
+        let violations = state.audit();
+        for expected in [
+            AuditViolation::MissingBuiltinPool(PoolKey::mel_and(Denom::NomDosc)),
+            AuditViolation::CoinTooLarge(huge),
+            AuditViolation::ExcessLiquidityTokens {
+                pool: sym_pool,
+                outstanding: (liqs + 1).into(),
+                liqs,
+            },
+            AuditViolation::EmptyStake(stake),
+            AuditViolation::MissingHistory(BlockHeight(0)),
+        ] {
+            assert!(violations.contains(&expected), "{:?} not found", expected);
+        }
+        // the trees were changed behind the tracked supply's back
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, AuditViolation::SupplyMismatch { .. })));
+        assert_eq!(violations.len(), 6);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{CoinValue, ConfirmError, ConsensusProof, SealedState, State, CONFIRM_THRESHOLD};
+
+use std::collections::BTreeMap;
+
+use novasmt::ContentAddrStore;
+use num::{rational::Ratio, BigInt};
+use serde::{Deserialize, Serialize};
+use tmelcrypt::{Ed25519PK, Ed25519SK, HashVal};
+
+/// Returns true iff `signed / total` strictly exceeds the threshold. Computed with bigints to avoid overflow.
+pub(crate) fn exceeds_threshold(
+    signed: CoinValue,
+    total: CoinValue,
+    threshold: Ratio<u128>,
+) -> bool {
+    BigInt::from(signed.0) * BigInt::from(*threshold.denom())
+        > BigInt::from(total.0) * BigInt::from(*threshold.numer())
+}
+
+/// One staker's signature on a block header, i.e. one entry of a [ConsensusProof].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SignatureShare {
+    pub pubkey: Ed25519PK,
+    #[serde(with = "stdcode::hex")]
+    pub signature: Vec<u8>,
+}
+
+impl SignatureShare {
+    /// Signs the header of the given sealed state.
+    pub fn new<C: ContentAddrStore>(sealed: &SealedState<C>, sk: Ed25519SK) -> Self {
+        Self {
+            pubkey: sk.to_public(),
+            signature: sk.sign(&sealed.header().hash()),
+        }
+    }
+}
+
+/// A helper struct for collecting [SignatureShare]s into a [ConsensusProof] that [SealedState::confirm] will accept.
+#[derive(Debug, Clone)]
+pub struct ConsensusProofBuilder {
+    header_hash: HashVal,
+    stakers: BTreeMap<Ed25519PK, CoinValue>,
+    total_votes: CoinValue,
+    signed_votes: CoinValue,
+    threshold: Ratio<u128>,
+    proof: ConsensusProof,
+}
+
+impl ConsensusProofBuilder {
+    /// Creates a builder for a proof of the given sealed state, weighing votes by the stakes of the previous state, just like [SealedState::confirm].
+    pub fn new<C: ContentAddrStore>(sealed: &SealedState<C>, previous_state: &State<C>) -> Self {
+        Self::with_threshold(sealed, previous_state, CONFIRM_THRESHOLD)
+    }
+
+    /// Like [ConsensusProofBuilder::new], but with a custom threshold, as in [SealedState::confirm_with_threshold].
+    pub fn with_threshold<C: ContentAddrStore>(
+        sealed: &SealedState<C>,
+        previous_state: &State<C>,
+        threshold: Ratio<u128>,
+    ) -> Self {
+        let stakers = previous_state.stakes.stakers(sealed.inner_ref().epoch());
+        let total_votes = stakers.values().copied().sum();
+        Self {
+            header_hash: sealed.header().hash(),
+            stakers,
+            total_votes,
+            signed_votes: CoinValue(0),
+            threshold,
+            proof: ConsensusProof::new(),
+        }
+    }
+
+    /// Adds a share, returning whether the proof has now reached quorum. Shares with invalid signatures or from keys without stake in this epoch are rejected. Adding a share from the same key twice does not count it twice.
+    pub fn add_share(&mut self, share: SignatureShare) -> Result<bool, ConfirmError> {
+        if !share.pubkey.verify(&self.header_hash, &share.signature) {
+            return Err(ConfirmError::BadSignatures(vec![share.pubkey]));
+        }
+        let votes = *self
+            .stakers
+            .get(&share.pubkey)
+            .ok_or_else(|| ConfirmError::Unstaked(vec![share.pubkey]))?;
+        if self.proof.insert(share.pubkey, share.signature).is_none() {
+            self.signed_votes += votes;
+        }
+        Ok(self.has_quorum())
+    }
+
+    /// Returns whether the collected shares are enough to confirm the block.
+    pub fn has_quorum(&self) -> bool {
+        exceeds_threshold(self.signed_votes, self.total_votes, self.threshold)
+    }
+
+    /// Returns the fraction of the vote collected so far. This is the sum of [crate::StakeMapping::vote_power] over all signers.
+    pub fn vote_power(&self) -> f64 {
+        self.signed_votes.0 as f64 / (self.total_votes.0 as f64).max(1e-50)
+    }
+
+    /// Returns the syms staked by the signers so far, and in total.
+    pub fn votes(&self) -> (CoinValue, CoinValue) {
+        (self.signed_votes, self.total_votes)
+    }
+
+    /// Returns the finished proof, or an error if quorum has not been reached.
+    pub fn build(self) -> Result<ConsensusProof, ConfirmError> {
+        if self.has_quorum() {
+            Ok(self.proof)
+        } else {
+            Err(ConfirmError::InsufficientVotes {
+                signed: self.signed_votes,
+                total: self.total_votes,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::testing::functions::create_state;
+    use crate::{CoinValue, ConfirmError, ConsensusProofBuilder, SignatureShare};
+
+    #[test]
+    fn collect_until_quorum() {
+        let stakers = [100u128, 100, 100, 100]
+            .iter()
+            .map(|v| (tmelcrypt::Ed25519SK::generate(), CoinValue(*v)))
+            .collect::<HashMap<_, _>>();
+        let first = create_state(&stakers, 0).seal(None);
+        let second = first.next_state().seal(None);
+
+        let mut builder = ConsensusProofBuilder::new(&second, first.inner_ref());
+        let mut signers = stakers.keys();
+        for _ in 0..2 {
+            let share = SignatureShare::new(&second, *signers.next().unwrap());
+            assert!(!builder.add_share(share.clone()).unwrap());
+            // repeated shares don't count twice
+            assert!(!builder.add_share(share).unwrap());
+        }
+        assert_eq!(builder.votes(), (CoinValue(200), CoinValue(400)));
+        assert!((builder.vote_power() - 0.5).abs() < 1e-9);
+        assert!(builder
+            .add_share(SignatureShare::new(&second, *signers.next().unwrap()))
+            .unwrap());
+
+        let cproof = builder.build().unwrap();
+        assert_eq!(cproof.len(), 3);
+        assert!(second.confirm(cproof, first.inner_ref()).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_shares() {
+        let stakers = [100u128, 100]
+            .iter()
+            .map(|v| (tmelcrypt::Ed25519SK::generate(), CoinValue(*v)))
+            .collect::<HashMap<_, _>>();
+        let first = create_state(&stakers, 0).seal(None);
+        let second = first.next_state().seal(None);
+        let mut builder = ConsensusProofBuilder::new(&second, first.inner_ref());
+
+        // a key with no stake
+        let outsider = tmelcrypt::Ed25519SK::generate();
+        assert_eq!(
+            builder.add_share(SignatureShare::new(&second, outsider)),
+            Err(ConfirmError::Unstaked(vec![outsider.to_public()]))
+        );
+
+        // a share for a different block
+        let staker = *stakers.keys().next().unwrap();
+        assert_eq!(
+            builder.add_share(SignatureShare::new(&first, staker)),
+            Err(ConfirmError::BadSignatures(vec![staker.to_public()]))
+        );
+
+        assert!(matches!(
+            builder.build(),
+            Err(ConfirmError::InsufficientVotes { .. })
+        ));
+    }
+}
@@ -0,0 +1,253 @@
+use super::{DecodeError, NetID, ProposerAction, SealedState, State, Supply};
+use crate::{ChainParams, SmtMapping};
+
+use std::convert::TryInto;
+
+use novasmt::{ContentAddrStore, Database};
+use tmelcrypt::HashVal;
+
+/// Magic number at the start of every versioned encoding. The older, unversioned encoding can never start with it, since it starts with the length of the state's positional encoding.
+const MAGIC: [u8; 4] = *b"MLSS";
+
+/// Current version of the encoding. Unlike the unversioned encoding, it includes the chain parameters and the supply.
+const VERSION: u8 = 1;
+
+/// Encodes a sealed state as the magic number, the version, and then every field prefixed by its length as a big-endian u32.
+pub(crate) fn encode<C: ContentAddrStore>(sealed: &SealedState<C>) -> Vec<u8> {
+    let state = &sealed.0;
+    let mut out = Vec::new();
+    out.extend_from_slice(&MAGIC);
+    out.push(VERSION);
+    let mut field = |bts: &[u8]| {
+        out.extend_from_slice(&(bts.len() as u32).to_be_bytes());
+        out.extend_from_slice(bts);
+    };
+    field(&[state.network.into()]);
+    field(&state.height.0.to_be_bytes());
+    field(&state.history.root_hash());
+    field(&state.coins.root_hash());
+    field(&state.transactions.root_hash());
+    field(&state.fee_pool.0.to_be_bytes());
+    field(&state.fee_multiplier.to_be_bytes());
+    field(&state.tips.0.to_be_bytes());
+    field(&state.dosc_speed.to_be_bytes());
+    field(&state.pools.root_hash());
+    field(&state.stakes.root_hash());
+    field(&stdcode::serialize(&sealed.1).unwrap());
+    field(&stdcode::serialize(&state.params).unwrap());
+    field(&stdcode::serialize(&state.supply).unwrap());
+    out
+}
+
+/// Decodes a sealed state from either the versioned encoding or the older unversioned one.
+pub(crate) fn decode<C: ContentAddrStore>(
+    bts: &[u8],
+    params: Option<&ChainParams>,
+    db: &Database<C>,
+) -> Result<SealedState<C>, DecodeError> {
+    match bts.strip_prefix(&MAGIC) {
+        Some(rest) => decode_versioned(rest, params, db),
+        None => decode_unversioned(bts, params, db),
+    }
+}
+
+fn decode_versioned<C: ContentAddrStore>(
+    bts: &[u8],
+    expected_params: Option<&ChainParams>,
+    db: &Database<C>,
+) -> Result<SealedState<C>, DecodeError> {
+    let (version, bts) = bts.split_first().ok_or(DecodeError::Truncated)?;
+    if *version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(*version));
+    }
+    let mut fields = Fields(bts);
+    let network = u8::from_be_bytes(fields.fixed()?);
+    let network: NetID = network
+        .try_into()
+        .map_err(|_| DecodeError::BadNetwork(network))?;
+    let height = u64::from_be_bytes(fields.fixed()?);
+    let history = fields.tree(db)?;
+    let coins = fields.tree(db)?;
+    let transactions = fields.tree(db)?;
+    let fee_pool = u128::from_be_bytes(fields.fixed()?);
+    let fee_multiplier = u128::from_be_bytes(fields.fixed()?);
+    let tips = u128::from_be_bytes(fields.fixed()?);
+    let dosc_speed = u128::from_be_bytes(fields.fixed()?);
+    let pools = fields.tree(db)?;
+    let stakes = fields.tree(db)?;
+    let action: Option<ProposerAction> =
+        stdcode::deserialize(fields.next()?).map_err(|_| DecodeError::Malformed)?;
+    let params: ChainParams =
+        stdcode::deserialize(fields.next()?).map_err(|_| DecodeError::Malformed)?;
+    params.validate(network).map_err(DecodeError::BadParams)?;
+    if matches!(expected_params, Some(expected) if *expected != params) {
+        return Err(DecodeError::ParamsMismatch(Box::new(params)));
+    }
+    let supply: Option<Supply> =
+        stdcode::deserialize(fields.next()?).map_err(|_| DecodeError::Malformed)?;
+    if !fields.0.is_empty() {
+        return Err(DecodeError::TrailingBytes(fields.0.len()));
+    }
+    Ok(SealedState(
+        State {
+            network,
+            params,
+            height: height.into(),
+            history,
+            coins,
+            transactions,
+
+            fee_pool: fee_pool.into(),
+            fee_multiplier,
+            tips: tips.into(),
+
+            dosc_speed,
+            pools,
+
+            stakes,
+
+            supply,
+        },
+        action,
+    ))
+}
+
+fn decode_unversioned<C: ContentAddrStore>(
+    bts: &[u8],
+    params: Option<&ChainParams>,
+    db: &Database<C>,
+) -> Result<SealedState<C>, DecodeError> {
+    let tmp: (Vec<u8>, Option<ProposerAction>) =
+        stdcode::deserialize(bts).map_err(|_| DecodeError::Malformed)?;
+    Ok(SealedState(
+        State::decode_partial(&tmp.0, params, db)?,
+        tmp.1,
+    ))
+}
+
+/// Reads length-prefixed fields.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn next(&mut self) -> Result<&'a [u8], DecodeError> {
+        if self.0.len() < 4 {
+            return Err(DecodeError::Truncated);
+        }
+        let (len, rest) = self.0.split_at(4);
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        if rest.len() < len {
+            return Err(DecodeError::Truncated);
+        }
+        let (field, rest) = rest.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn fixed<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        self.next()?.try_into().map_err(|_| DecodeError::Malformed)
+    }
+
+    fn tree<
+        C: ContentAddrStore,
+        K: serde::Serialize,
+        V: serde::Serialize + serde::de::DeserializeOwned,
+    >(
+        &mut self,
+        db: &Database<C>,
+    ) -> Result<SmtMapping<C, K, V>, DecodeError> {
+        SmtMapping::from_root(db, HashVal(self.fixed()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use novasmt::InMemoryCas;
+
+    use crate::melvm::Covenant;
+    use crate::testing::functions::{spend_split, split_genesis_state};
+    use crate::{DecodeError, ProposerAction, SealedState};
+
+    const GOLDEN_UNVERSIONED: &str = include_str!("../testing/golden/sealed_state_unversioned.hex");
+    const GOLDEN_V1: &str = include_str!("../testing/golden/sealed_state_v1.hex");
+
+    /// The state the golden vectors were generated from. Everything in it is deterministic.
+    fn golden_state() -> SealedState<InMemoryCas> {
+        let (base, split) = split_genesis_state(2);
+        let mut next = base.next_state();
+        next.apply_tx(&spend_split(&split, 0, 100_000)).unwrap();
+        next.seal(Some(ProposerAction {
+            fee_multiplier_delta: 3,
+            reward_dest: Covenant::always_true().hash(),
+        }))
+    }
+
+    fn golden(hex: &str) -> Vec<u8> {
+        hex::decode(hex.trim()).unwrap()
+    }
+
+    #[test]
+    fn encodes_golden_v1() {
+        assert_eq!(golden_state().partial_encoding(), golden(GOLDEN_V1));
+    }
+
+    #[test]
+    fn decodes_both_formats() {
+        let state = golden_state();
+        let db = state.inner_ref().coins.mapping.database();
+        let params = state.inner_ref().params;
+        for (bts, versioned) in [
+            (golden(GOLDEN_V1), true),
+            (golden(GOLDEN_UNVERSIONED), false),
+        ] {
+            let decoded = SealedState::from_partial_encoding(&bts, &params, &db).unwrap();
+            assert_eq!(decoded.header(), state.header());
+            assert_eq!(decoded.proposer_action(), state.proposer_action());
+            assert_eq!(decoded.inner_ref().tips, state.inner_ref().tips);
+            assert_eq!(decoded.inner_ref().params, state.inner_ref().params);
+            // decoding and encoding again migrates to the versioned encoding, but the unversioned one does not know the supply
+            if versioned {
+                assert_eq!(decoded.inner_ref().supply, state.inner_ref().supply);
+                assert_eq!(decoded.partial_encoding(), golden(GOLDEN_V1));
+            } else {
+                assert_eq!(decoded.inner_ref().supply, None);
+                assert_eq!(decoded.partial_encoding()[4], 1);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_versioned() {
+        let state = golden_state();
+        let db = state.inner_ref().coins.mapping.database();
+        let params = state.inner_ref().params;
+        let v1 = golden(GOLDEN_V1);
+
+        for version in [0, 2] {
+            let mut unknown = v1.clone();
+            unknown[4] = version;
+            assert_eq!(
+                SealedState::from_partial_encoding(&unknown, &params, &db).unwrap_err(),
+                DecodeError::UnsupportedVersion(version)
+            );
+        }
+        assert_eq!(
+            SealedState::from_partial_encoding(&v1[..v1.len() - 1], &params, &db).unwrap_err(),
+            DecodeError::Truncated
+        );
+        let mut trailing = v1.clone();
+        trailing.extend_from_slice(&[0; 4]);
+        assert_eq!(
+            SealedState::from_partial_encoding(&trailing, &params, &db).unwrap_err(),
+            DecodeError::TrailingBytes(4)
+        );
+        // the height field with the wrong length
+        let mut wrong_length = v1[..10].to_vec();
+        wrong_length.extend_from_slice(&[0, 0, 0, 7]);
+        wrong_length.extend_from_slice(&v1[14..21]);
+        wrong_length.extend_from_slice(&v1[22..]);
+        assert_eq!(
+            SealedState::from_partial_encoding(&wrong_length, &params, &db).unwrap_err(),
+            DecodeError::Malformed
+        );
+    }
+}
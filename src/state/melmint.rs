@@ -1,10 +1,13 @@
 use crate::state::melswap::PoolState;
+use crate::state::receipt::MelmintSink;
+use crate::state::supply::{self, Supply, SupplyError};
+use crate::state::NoopObserver;
 use crate::{
-    BlockHeight, CoinData, CoinDataHeight, CoinValue, Denom, PoolKey, State, Transaction, TxKind,
-    MAX_COINVAL, MICRO_CONVERTER,
+    BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, PoolKey, State, Tip,
+    Transaction, TxHash, TxKind, MICRO_CONVERTER,
 };
 
-use std::{cell::RefCell, convert::TryInto};
+use std::{cell::RefCell, collections::BTreeSet, convert::TryInto};
 
 use novasmt::ContentAddrStore;
 use num::{integer::Roots, rational::Ratio, BigInt, BigRational};
@@ -71,22 +74,104 @@ pub fn calculate_reward(my_speed: u128, dosc_speed: u128, difficulty: u32) -> u1
 
 /// Presealing function that is called before a state is sealed to apply melmint actions.
 pub fn preseal_melmint<C: ContentAddrStore>(state: State<C>) -> State<C> {
-    let state = create_builtins(state);
+    preseal_melmint_recording(state, &mut NoopObserver)
+}
+
+/// Like [preseal_melmint], but also tells a sink what happened to each transaction.
+///
+/// Whatever the sink, the keys of the coins and pools melmint writes are kept, so that the tracked supply can be updated by reading them back before and after. That costs a couple of lookups per melmint transaction.
+pub(crate) fn preseal_melmint_recording<C: ContentAddrStore>(
+    state: State<C>,
+    sink: &mut impl MelmintSink,
+) -> State<C> {
+    let before = state.clone();
+    let effects = &mut Touched {
+        sink,
+        coins: BTreeSet::new(),
+        pools: BTreeSet::new(),
+    };
+    let state = create_builtins(state, effects);
     assert!(state.pools.val_iter().count() >= 2);
-    let state = process_swaps(state);
+    let state = process_swaps(state, effects);
     assert!(state.pools.val_iter().count() >= 2);
-    let state = process_deposits(state);
+    let state = process_deposits(state, effects);
     assert!(state.pools.val_iter().count() >= 2);
-    let state = process_withdrawals(state);
+    let state = process_withdrawals(state, effects);
     assert!(state.pools.val_iter().count() >= 2);
-    process_pegging(state)
+    let mut state = process_pegging(state, effects);
+    let after = state.clone();
+    supply::update(&mut state, |supply| {
+        effects.update_supply(&before, &after, supply)
+    });
+    state
+}
+
+/// Passes melmint's effects on to a sink, remembering the keys of the coins and pools written.
+struct Touched<'a, S> {
+    sink: &'a mut S,
+    coins: BTreeSet<CoinID>,
+    pools: BTreeSet<PoolKey>,
+}
+
+impl<S: MelmintSink> MelmintSink for Touched<'_, S> {
+    fn record_outcome(
+        &mut self,
+        txhash: TxHash,
+        pool: PoolKey,
+        before: &CoinData,
+        after: &CoinData,
+    ) {
+        self.sink.record_outcome(txhash, pool, before, after)
+    }
+
+    fn record_coin(&mut self, coin_id: CoinID, value: Option<&CoinDataHeight>) {
+        self.coins.insert(coin_id);
+        self.sink.record_coin(coin_id, value)
+    }
+
+    fn record_pool(&mut self, pool: PoolKey, state: PoolState) {
+        self.pools.insert(pool);
+        self.sink.record_pool(pool, state)
+    }
+}
+
+impl<S> Touched<'_, S> {
+    /// Accounts in a supply for every coin and pool written, given the states before and after melmint.
+    fn update_supply<C: ContentAddrStore>(
+        &self,
+        before: &State<C>,
+        after: &State<C>,
+        supply: &mut Supply,
+    ) -> Result<(), SupplyError> {
+        for coin_id in self.coins.iter() {
+            if let Some(old) = before.coins.get(coin_id).0 {
+                supply.remove_coin(&old.coin_data)?;
+            }
+            if let Some(new) = after.coins.get(coin_id).0 {
+                supply.add_coin(&new.coin_data)?;
+            }
+        }
+        for key in self.pools.iter() {
+            if let Some(old) = before.pools.get(key).0 {
+                supply.remove_pool(*key, &old)?;
+            }
+            if let Some(new) = after.pools.get(key).0 {
+                supply.add_pool(*key, &new)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Creates the built-in pools if they don't exist. The built-in pools start out with nonzero liq, so that they can never be completely depleted. This ensures that built-in pools will always exist in the state.
-fn create_builtins<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
+fn create_builtins<C: ContentAddrStore>(
+    mut state: State<C>,
+    effects: &mut impl MelmintSink,
+) -> State<C> {
     let mut def = PoolState::new_empty();
     let _ = def.deposit(MICRO_CONVERTER * 1000, MICRO_CONVERTER * 1000);
     if state.pools.get(&PoolKey::mel_and(Denom::Sym)).0.is_none() {
+        effects.record_pool(PoolKey::mel_and(Denom::Sym), def);
         state.pools.insert(PoolKey::mel_and(Denom::Sym), def)
     }
     if state
@@ -95,15 +180,17 @@ fn create_builtins<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
         .0
         .is_none()
     {
+        effects.record_pool(PoolKey::mel_and(Denom::NomDosc), def);
         state.pools.insert(PoolKey::mel_and(Denom::NomDosc), def)
     }
-    if state.tip_902()
+    if state.is_active(Tip::Tip902)
         && state
             .pools
             .get(&PoolKey::new(Denom::NomDosc, Denom::Sym))
             .0
             .is_none()
     {
+        effects.record_pool(PoolKey::new(Denom::NomDosc, Denom::Sym), def);
         state
             .pools
             .insert(PoolKey::new(Denom::NomDosc, Denom::Sym), def)
@@ -112,18 +199,21 @@ fn create_builtins<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
 }
 
 /// Process swaps.
-fn process_swaps<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
+fn process_swaps<C: ContentAddrStore>(
+    mut state: State<C>,
+    effects: &mut impl MelmintSink,
+) -> State<C> {
     // find the swap requests
     let swap_reqs: Vec<Transaction> = state
         .transactions
         .val_iter()
         .filter_map(|tx| {
-            (!tx.outputs.is_empty()).then(|| ())?; // ensure not empty
+            (!tx.outputs.is_empty()).then_some(())?; // ensure not empty
             state.coins.get(&tx.output_coinid(0)).0?; // ensure that first output is unspent
             let pool_key = PoolKey::from_bytes(&tx.data)?; // ensure that data contains a pool key
             state.pools.get(&pool_key).0?; // ensure that pool key points to a valid pool
             (tx.outputs[0].denom == pool_key.left || tx.outputs[0].denom == pool_key.right)
-                .then(|| ())?; // ensure that the first output is either left or right
+                .then_some(())?; // ensure that the first output is either left or right
             Some(tx)
         })
         .collect::<Vec<Transaction>>();
@@ -171,10 +261,12 @@ fn process_swaps<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
             })
             .fold(0u128, |a, b| a.saturating_add(b.0));
         // transmute coins
-        let (left_withdrawn, right_withdrawn) = pool_state.swap_many(total_lefts, total_rights);
+        let (left_withdrawn, right_withdrawn) =
+            pool_state.swap_many_with_fee(total_lefts, total_rights, state.params.swap_fee_micro);
 
         relevant_swaps.iter_mut().for_each(|swap| {
             let correct_coinid = swap.output_coinid(0);
+            let before = swap.outputs[0].clone();
 
             if swap.outputs[0].denom == pool.left {
                 swap.outputs[0].denom = pool.right;
@@ -182,24 +274,25 @@ fn process_swaps<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
                     right_withdrawn,
                     Ratio::new(swap.outputs[0].value.0, total_lefts),
                 ))
-                .min(MAX_COINVAL);
+                .min(state.params.max_coinval);
             } else {
                 swap.outputs[0].denom = pool.left;
                 swap.outputs[0].value = CoinValue(multiply_frac(
                     left_withdrawn,
                     Ratio::new(swap.outputs[0].value.0, total_rights),
                 ))
-                .min(MAX_COINVAL);
+                .min(state.params.max_coinval);
             }
-            state.coins.insert(
-                correct_coinid,
-                CoinDataHeight {
-                    coin_data: swap.outputs[0].clone(),
-                    height: state.height,
-                },
-            );
+            let after = CoinDataHeight {
+                coin_data: swap.outputs[0].clone(),
+                height: state.height,
+            };
+            effects.record_outcome(correct_coinid.txhash, *pool, &before, &after.coin_data);
+            effects.record_coin(correct_coinid, Some(&after));
+            state.coins.insert(correct_coinid, after);
         });
 
+        effects.record_pool(*pool, pool_state);
         state.pools.insert(*pool, pool_state);
     });
 
@@ -207,7 +300,10 @@ fn process_swaps<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
 }
 
 /// Process deposits.
-fn process_deposits<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
+fn process_deposits<C: ContentAddrStore>(
+    mut state: State<C>,
+    effects: &mut impl MelmintSink,
+) -> State<C> {
     // find the deposit requests
     let deposit_reqs = state
         .transactions
@@ -217,10 +313,10 @@ fn process_deposits<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
                 && tx.outputs.len() >= 2
                 && state.coins.get(&tx.output_coinid(0)).0.is_some()
                 && state.coins.get(&tx.output_coinid(1)).0.is_some())
-            .then(|| ())?;
+            .then_some(())?;
             let pool_key = PoolKey::from_bytes(&tx.data)?;
             (tx.outputs[0].denom == pool_key.left && tx.outputs[1].denom == pool_key.right)
-                .then(|| tx)
+                .then_some(tx)
         })
         .collect::<Vec<_>>();
     log::trace!("{} deposit reqs", deposit_reqs.len());
@@ -256,17 +352,20 @@ fn process_deposits<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
         // main logic here
         let total_liqs = if let Some(mut pool_state) = state.pools.get(pool).0 {
             let liq = pool_state.deposit(total_lefts, total_rights);
+            effects.record_pool(*pool, pool_state);
             state.pools.insert(*pool, pool_state);
             liq
         } else {
             let mut pool_state = PoolState::new_empty();
             let liq = pool_state.deposit(total_lefts, total_rights);
+            effects.record_pool(*pool, pool_state);
             state.pools.insert(*pool, pool_state);
             liq
         };
         // divvy up the liqs
         relevant_txx.iter_mut().for_each(|deposit| {
             let correct_coinid = deposit.output_coinid(0);
+            let before = deposit.outputs[0].clone();
             let my_mtsqrt = deposit.outputs[0]
                 .value
                 .0
@@ -275,13 +374,14 @@ fn process_deposits<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
             deposit.outputs[0].denom = pool.liq_token_denom();
             deposit.outputs[0].value =
                 multiply_frac(total_liqs, Ratio::new(my_mtsqrt, total_mtsqrt)).into();
-            state.coins.insert(
-                correct_coinid,
-                CoinDataHeight {
-                    coin_data: deposit.outputs[0].clone(),
-                    height: state.height,
-                },
-            );
+            let after = CoinDataHeight {
+                coin_data: deposit.outputs[0].clone(),
+                height: state.height,
+            };
+            effects.record_outcome(correct_coinid.txhash, *pool, &before, &after.coin_data);
+            effects.record_coin(correct_coinid, Some(&after));
+            effects.record_coin(deposit.output_coinid(1), None);
+            state.coins.insert(correct_coinid, after);
             state.coins.delete(&deposit.output_coinid(1));
         });
     });
@@ -290,7 +390,10 @@ fn process_deposits<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
 }
 
 /// Process deposits.
-fn process_withdrawals<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
+fn process_withdrawals<C: ContentAddrStore>(
+    mut state: State<C>,
+    effects: &mut impl MelmintSink,
+) -> State<C> {
     // find the withdrawal requests
     let withdraw_reqs: Vec<Transaction> = state
         .transactions
@@ -299,10 +402,10 @@ fn process_withdrawals<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
             (tx.kind == TxKind::LiqWithdraw
                 && tx.outputs.len() == 1
                 && state.coins.get(&tx.output_coinid(0)).0.is_some())
-            .then(|| ())?;
+            .then_some(())?;
             let pool_key = PoolKey::from_bytes(&tx.data)?;
             state.pools.get(&pool_key).0?;
-            (tx.outputs[0].denom == pool_key.liq_token_denom()).then(|| tx)
+            (tx.outputs[0].denom == pool_key.liq_token_denom()).then_some(tx)
         })
         .collect::<Vec<_>>();
     // find the pools mentioned
@@ -330,11 +433,17 @@ fn process_withdrawals<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
         // get the state
         let mut pool_state = state.pools.get(pool).0.unwrap();
         let (total_left, total_write) = pool_state.withdraw(total_liqs);
+        effects.record_pool(*pool, pool_state);
         state.pools.insert(*pool, pool_state);
         // divvy up the lefts and rights
         relevant_txx.iter_mut().for_each(|deposit| {
             let coinid_0 = deposit.output_coinid(0);
-            let coinid_1 = deposit.output_coinid(1);
+            // withdrawals have a single output, so the second one is synthetic
+            let coinid_1 = CoinID {
+                txhash: coinid_0.txhash,
+                index: 1,
+            };
+            let before = deposit.outputs[0].clone();
 
             let my_liqs = deposit.outputs[0].value.0;
             deposit.outputs[0].denom = pool.left;
@@ -347,20 +456,19 @@ fn process_withdrawals<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
                 additional_data: deposit.outputs[0].additional_data.clone(),
             };
 
-            state.coins.insert(
-                coinid_0,
-                CoinDataHeight {
-                    coin_data: deposit.outputs[0].clone(),
-                    height: state.height,
-                },
-            );
-            state.coins.insert(
-                coinid_1,
-                CoinDataHeight {
-                    coin_data: synth,
-                    height: state.height,
-                },
-            );
+            let after_0 = CoinDataHeight {
+                coin_data: deposit.outputs[0].clone(),
+                height: state.height,
+            };
+            let after_1 = CoinDataHeight {
+                coin_data: synth,
+                height: state.height,
+            };
+            effects.record_outcome(coinid_0.txhash, *pool, &before, &after_0.coin_data);
+            effects.record_coin(coinid_0, Some(&after_0));
+            effects.record_coin(coinid_1, Some(&after_1));
+            state.coins.insert(coinid_0, after_0);
+            state.coins.insert(coinid_1, after_1);
         });
     });
 
@@ -368,9 +476,12 @@ fn process_withdrawals<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
 }
 
 /// Process pegging.
-fn process_pegging<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
+fn process_pegging<C: ContentAddrStore>(
+    mut state: State<C>,
+    effects: &mut impl MelmintSink,
+) -> State<C> {
     // first calculate the implied sym/nomDOSC exchange rate
-    let x_sd = if state.tip_902() {
+    let x_sd = if state.is_active(Tip::Tip902) {
         state
             .pools
             .get(&PoolKey::new(Denom::Sym, Denom::NomDosc))
@@ -396,7 +507,11 @@ fn process_pegging<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
         x_s / x_d
     };
 
-    let throttler = if state.tip_902() { 200 } else { 1000 };
+    let throttler = if state.is_active(Tip::Tip902) {
+        state.params.pegging_throttler_tip_902
+    } else {
+        state.params.pegging_throttler
+    };
 
     // get the right pool
     let mut sm_pool = state.pools.get(&PoolKey::mel_and(Denom::Sym)).0.unwrap();
@@ -422,12 +537,13 @@ fn process_pegging<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
         let delta = (desired_mel - sm_pool.lefts) / throttler;
         // we increase mel liquidity by delta, throwing away the syms generated.
         // this nudges the exchange rate while minimizing long-term inflation
-        let _ = sm_pool.swap_many(delta, 0);
+        let _ = sm_pool.swap_many_with_fee(delta, 0, state.params.swap_fee_micro);
     }
     if desired_sym > sm_pool.rights {
         let delta = (desired_sym - sm_pool.rights) / throttler;
-        let _ = sm_pool.swap_many(0, delta);
+        let _ = sm_pool.swap_many_with_fee(0, delta, state.params.swap_fee_micro);
     }
+    effects.record_pool(PoolKey::mel_and(Denom::Sym), sm_pool);
     state.pools.insert(PoolKey::mel_and(Denom::Sym), sm_pool);
     // return the state now
     assert!(state.pools.val_iter().count() >= 2);
@@ -460,7 +576,8 @@ mod tests {
     #[test]
     // test a simple deposit flow
     fn simple_deposit() {
-        let (my_pk, my_sk) = tmelcrypt::ed25519_keygen();
+        let my_sk = tmelcrypt::Ed25519SK::generate();
+        let my_pk = my_sk.to_public();
         let my_covhash = melvm::Covenant::std_ed25519_pk_legacy(my_pk).hash();
         let start_state = genesis_state(
             CoinID::zero_zero(),
@@ -1,4 +1,4 @@
-use crate::{PoolKey, SmtMapping, MICRO_CONVERTER};
+use crate::{ChainParams, PoolKey, SmtMapping, MICRO_CONVERTER};
 
 use std::convert::TryInto;
 
@@ -27,30 +27,41 @@ impl PoolState {
         }
     }
 
-    /// Executes a swap.
+    /// Executes a swap, with the mainnet swap fee.
     #[must_use]
     pub fn swap_many(&mut self, lefts: u128, rights: u128) -> (u128, u128) {
+        self.swap_many_with_fee(lefts, rights, ChainParams::mainnet().swap_fee_micro)
+    }
+
+    /// Executes a swap, keeping the given fee, in millionths, of both withdrawals in the pool.
+    #[must_use]
+    pub fn swap_many_with_fee(
+        &mut self,
+        lefts: u128,
+        rights: u128,
+        fee_micro: u128,
+    ) -> (u128, u128) {
+        let after_fee = BigRational::new(
+            BigInt::from(MICRO_CONVERTER - fee_micro),
+            BigInt::from(MICRO_CONVERTER),
+        );
         // deposit the tokens. intentionally saturate so that "overflowing" tokens are drained.
         self.lefts = self.lefts.saturating_add(lefts);
         self.rights = self.rights.saturating_add(rights);
         // "indiscriminately" use this new price to calculate how much of the other token to withdraw.
         let exchange_rate = Ratio::new(BigInt::from(self.lefts), BigInt::from(self.rights));
-        let rights_to_withdraw: u128 = (BigRational::from(BigInt::from(lefts))
-            / exchange_rate.clone()
-            * BigRational::from(BigInt::from(995))
-            / BigRational::from(BigInt::from(1000)))
-        .floor()
-        .numer()
-        .try_into()
-        .unwrap_or(u128::MAX);
-        let lefts_to_withdraw: u128 = (BigRational::from(BigInt::from(rights))
-            * exchange_rate
-            * BigRational::from(BigInt::from(995))
-            / BigRational::from(BigInt::from(1000)))
-        .floor()
-        .numer()
-        .try_into()
-        .unwrap_or(u128::MAX);
+        let rights_to_withdraw: u128 =
+            (BigRational::from(BigInt::from(lefts)) / exchange_rate.clone() * after_fee.clone())
+                .floor()
+                .numer()
+                .try_into()
+                .unwrap_or(u128::MAX);
+        let lefts_to_withdraw: u128 =
+            (BigRational::from(BigInt::from(rights)) * exchange_rate * after_fee)
+                .floor()
+                .numer()
+                .try_into()
+                .unwrap_or(u128::MAX);
         // do the withdrawal
         self.lefts -= lefts_to_withdraw;
         self.rights -= rights_to_withdraw;
@@ -124,6 +135,11 @@ impl PoolState {
         }
     }
 
+    /// Returns the number of liquidity tokens the pool has issued and not yet redeemed.
+    pub fn liqs(&self) -> u128 {
+        self.liqs
+    }
+
     /// Returns the implied price as lefts per right.
     #[must_use]
     pub fn implied_price(&self) -> BigRational {
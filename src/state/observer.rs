@@ -0,0 +1,191 @@
+use crate::{CoinDataHeight, CoinID, CoinValue, PoolKey, PoolState, StakeDoc, TxHash};
+
+/// Something that happened to the state while applying transactions or sealing a block.
+#[derive(Clone, Copy, Debug)]
+pub enum StateEvent<'a> {
+    /// A coin was created by a transaction, or replaced by melmint.
+    CoinCreated {
+        coin_id: CoinID,
+        coin: &'a CoinDataHeight,
+    },
+    /// A coin was spent or otherwise removed.
+    CoinSpent { coin_id: CoinID },
+    /// A stake was added by a staking transaction.
+    StakeAdded { txhash: TxHash, stake: &'a StakeDoc },
+    /// A pool got a new state through melmint.
+    PoolUpdated { pool: PoolKey, state: &'a PoolState },
+    /// The fee of a transaction was collected, split between the fee pool and the proposer's tips.
+    FeeCollected {
+        txhash: TxHash,
+        base_fee: CoinValue,
+        tip: CoinValue,
+    },
+    /// The proposer's reward pseudocoin was minted while sealing.
+    ProposerRewardMinted {
+        coin_id: CoinID,
+        coin: &'a CoinDataHeight,
+    },
+}
+
+/// A hook for following state transitions, registered per call through [crate::SealedState::apply_block_observed], [crate::State::apply_tx_batch_observed] and [crate::State::seal_observed].
+///
+/// Events are reported once a batch or block has been fully validated, so one that fails produces no events. Since the transactions of a batch are applied in parallel, events are grouped by kind rather than reported in the order of the transactions; a coin that is created and spent within the same batch is reported as a [StateEvent::CoinCreated] immediately followed by a [StateEvent::CoinSpent].
+pub trait StateObserver {
+    /// Called for every event.
+    fn on_event(&self, _event: StateEvent<'_>) {}
+}
+
+/// An observer that ignores every event.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopObserver;
+
+impl StateObserver for NoopObserver {}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::melvm::Covenant;
+    use crate::testing::functions::{always_true_coin, spend_split, split_genesis_state};
+    use crate::{CoinID, CoinValue, Denom, PoolKey, ProposerAction, Transaction, TxHash, TxKind};
+
+    use super::{StateEvent, StateObserver};
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Recorded {
+        CoinCreated(CoinID, Denom),
+        CoinSpent(CoinID),
+        StakeAdded(TxHash),
+        PoolUpdated(PoolKey),
+        FeeCollected(TxHash, CoinValue, CoinValue),
+        ProposerRewardMinted(CoinValue),
+    }
+
+    #[derive(Default)]
+    struct Recorder(RefCell<Vec<Recorded>>);
+
+    impl StateObserver for Recorder {
+        fn on_event(&self, event: StateEvent<'_>) {
+            self.0.borrow_mut().push(match event {
+                StateEvent::CoinCreated { coin_id, coin } => {
+                    Recorded::CoinCreated(coin_id, coin.coin_data.denom)
+                }
+                StateEvent::CoinSpent { coin_id } => Recorded::CoinSpent(coin_id),
+                StateEvent::StakeAdded { txhash, .. } => Recorded::StakeAdded(txhash),
+                StateEvent::PoolUpdated { pool, .. } => Recorded::PoolUpdated(pool),
+                StateEvent::FeeCollected {
+                    txhash,
+                    base_fee,
+                    tip,
+                } => Recorded::FeeCollected(txhash, base_fee, tip),
+                StateEvent::ProposerRewardMinted { coin, .. } => {
+                    Recorded::ProposerRewardMinted(coin.coin_data.value)
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn batch_events() {
+        let (base, split) = split_genesis_state(2);
+        let txx = [
+            spend_split(&split, 0, 100_000),
+            spend_split(&split, 1, 200_000),
+        ];
+        let mut state = base.next_state();
+        let recorder = Recorder::default();
+        state.apply_tx_batch_observed(&txx, &recorder).unwrap();
+
+        let events = recorder.0.into_inner();
+        assert_eq!(events.len(), 6);
+        for (i, tx) in txx.iter().enumerate() {
+            let base_fee = tx.base_fee(state.fee_multiplier, 0);
+            assert!(events.contains(&Recorded::FeeCollected(
+                tx.hash_nosigs(),
+                base_fee,
+                tx.fee - base_fee
+            )));
+            assert!(events.contains(&Recorded::CoinSpent(split.output_coinid(i as u8))));
+            assert!(events.contains(&Recorded::CoinCreated(tx.output_coinid(0), Denom::Mel)));
+        }
+
+        // the observed application gives the same state
+        let mut unobserved = base.next_state();
+        unobserved.apply_tx_batch(&txx).unwrap();
+        assert_eq!(unobserved.seal(None).header(), state.seal(None).header());
+    }
+
+    #[test]
+    fn intra_batch_coins() {
+        let (base, split) = split_genesis_state(1);
+        let parent = spend_split(&split, 0, 100_000);
+        let child = Transaction::new(TxKind::Normal)
+            .add_input(parent.output_coinid(0))
+            .add_output(always_true_coin((1 << 30) - 300_000))
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true());
+        let recorder = Recorder::default();
+        let mut state = base.next_state();
+        state
+            .apply_tx_batch_observed(&[child.clone(), parent.clone()], &recorder)
+            .unwrap();
+
+        let events = recorder.0.into_inner();
+        let intermediate = parent.output_coinid(0);
+        let created = events
+            .iter()
+            .position(|e| *e == Recorded::CoinCreated(intermediate, Denom::Mel))
+            .unwrap();
+        assert_eq!(events[created + 1], Recorded::CoinSpent(intermediate));
+        assert!(events.contains(&Recorded::CoinSpent(split.output_coinid(0))));
+        assert!(events.contains(&Recorded::CoinCreated(child.output_coinid(0), Denom::Mel)));
+        assert_eq!(events.len(), 6);
+        assert!(state.coins.get(&intermediate).0.is_none());
+    }
+
+    #[test]
+    fn failed_batch_reports_nothing() {
+        let (base, split) = split_genesis_state(2);
+        let txx = [
+            spend_split(&split, 0, 100_000),
+            spend_split(&split, 0, 200_000),
+        ];
+        let recorder = Recorder::default();
+        assert!(base
+            .next_state()
+            .apply_tx_batch_observed(&txx, &recorder)
+            .is_err());
+        assert!(recorder.0.into_inner().is_empty());
+    }
+
+    #[test]
+    fn seal_events() {
+        let (base, split) = split_genesis_state(1);
+        let pool = PoolKey::mel_and(Denom::Sym);
+        let swap = spend_split(&split, 0, 100_000).with_data(pool.to_bytes());
+        let mut state = base.next_state();
+        state.apply_tx(&swap).unwrap();
+        let action = ProposerAction {
+            fee_multiplier_delta: 0,
+            reward_dest: Covenant::always_true().hash(),
+        };
+
+        let recorder = Recorder::default();
+        let sealed = state.clone().seal_observed(Some(action), &recorder);
+        assert_eq!(sealed.header(), state.seal(Some(action)).header());
+
+        let events = recorder.0.into_inner();
+        assert!(events.contains(&Recorded::CoinCreated(swap.output_coinid(0), Denom::Sym)));
+        assert!(events.contains(&Recorded::PoolUpdated(pool)));
+        let reward = sealed
+            .inner_ref()
+            .coins
+            .get(&CoinID::proposer_reward(sealed.inner_ref().height))
+            .0
+            .unwrap();
+        assert_eq!(
+            events.last(),
+            Some(&Recorded::ProposerRewardMinted(reward.coin_data.value))
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use super::observer::{NoopObserver, StateEvent, StateObserver};
+use crate::{
+    melvm::Address, BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, PoolKey,
+    PoolState, Transaction, TxHash,
+};
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// What happened to a transaction when its block was sealed. Obtained through [crate::State::seal_with_receipts].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TxReceipt {
+    /// Hash of the transaction.
+    pub txhash: TxHash,
+    /// The part of the fee that went into the fee pool.
+    pub base_fee: CoinValue,
+    /// The part of the fee that went to the proposer.
+    pub tip: CoinValue,
+    /// Coins the transaction created, as they were after melmint processing. Includes coins that were spent later in the same block, but not coins absorbed into a pool or sent to the destroy address.
+    pub coins_created: BTreeMap<CoinID, CoinDataHeight>,
+    /// What melmint did to the first output, if anything.
+    pub melmint: Option<MelmintOutcome>,
+}
+
+/// The effect of melmint (swaps, liquidity deposits and withdrawals) on the first output of a transaction.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MelmintOutcome {
+    /// The pool involved.
+    pub pool: PoolKey,
+    /// Denomination of the first output, as written in the transaction.
+    pub denom_before: Denom,
+    /// Value of the first output, as written in the transaction.
+    pub value_before: CoinValue,
+    /// Denomination of the first output after melmint.
+    pub denom_after: Denom,
+    /// Value of the first output after melmint.
+    pub value_after: CoinValue,
+}
+
+/// Something that is told what melmint does while presealing a state. Every method does nothing by default, so that sealing without anyone listening costs nothing.
+pub(crate) trait MelmintSink {
+    /// Called when melmint rewrites the first output of a transaction, originally `before`, into `after`.
+    fn record_outcome(
+        &mut self,
+        _txhash: TxHash,
+        _pool: PoolKey,
+        _before: &CoinData,
+        _after: &CoinData,
+    ) {
+    }
+
+    /// Called when melmint replaces a coin, or deletes it if `value` is `None`.
+    fn record_coin(&mut self, _coin_id: CoinID, _value: Option<&CoinDataHeight>) {}
+
+    /// Called when melmint writes a new pool state.
+    fn record_pool(&mut self, _pool: PoolKey, _state: PoolState) {}
+}
+
+impl MelmintSink for NoopObserver {}
+
+/// Everything melmint did to transactions while presealing a state.
+#[derive(Default, Debug)]
+pub(crate) struct MelmintEffects {
+    outcomes: BTreeMap<TxHash, MelmintOutcome>,
+    coins: BTreeMap<CoinID, Option<CoinDataHeight>>,
+    pools: BTreeMap<PoolKey, PoolState>,
+}
+
+impl MelmintSink for MelmintEffects {
+    fn record_outcome(
+        &mut self,
+        txhash: TxHash,
+        pool: PoolKey,
+        before: &CoinData,
+        after: &CoinData,
+    ) {
+        self.outcomes.insert(
+            txhash,
+            MelmintOutcome {
+                pool,
+                denom_before: before.denom,
+                value_before: before.value,
+                denom_after: after.denom,
+                value_after: after.value,
+            },
+        );
+    }
+
+    fn record_coin(&mut self, coin_id: CoinID, value: Option<&CoinDataHeight>) {
+        self.coins.insert(coin_id, value.cloned());
+    }
+
+    fn record_pool(&mut self, pool: PoolKey, state: PoolState) {
+        self.pools.insert(pool, state);
+    }
+}
+
+impl MelmintEffects {
+    /// Returns the IDs of every coin melmint replaced or deleted.
+    pub(crate) fn coin_keys(&self) -> impl Iterator<Item = CoinID> + '_ {
+        self.coins.keys().copied()
+    }
+
+    /// Returns the keys of every pool melmint wrote.
+    pub(crate) fn pool_keys(&self) -> impl Iterator<Item = PoolKey> + '_ {
+        self.pools.keys().copied()
+    }
+
+    /// Tells an observer about every coin and pool melmint wrote.
+    pub(crate) fn notify(&self, observer: &(impl StateObserver + ?Sized)) {
+        self.coins.iter().for_each(|(coin_id, coin)| match coin {
+            Some(coin) => observer.on_event(StateEvent::CoinCreated {
+                coin_id: *coin_id,
+                coin,
+            }),
+            None => observer.on_event(StateEvent::CoinSpent { coin_id: *coin_id }),
+        });
+        self.pools.iter().for_each(|(pool, state)| {
+            observer.on_event(StateEvent::PoolUpdated { pool: *pool, state })
+        });
+    }
+
+    /// Builds the receipt of a transaction that was applied at the given height and fee multiplier.
+    pub(crate) fn receipt(
+        &self,
+        tx: &Transaction,
+        height: BlockHeight,
+        fee_multiplier: u128,
+    ) -> TxReceipt {
+        let txhash = tx.hash_nosigs();
+        let base_fee = tx.base_fee(fee_multiplier, 0);
+
+        // start from the outputs as applied, then overlay what melmint did
+        let mut coins_created: BTreeMap<CoinID, CoinDataHeight> = tx
+            .outputs
+            .iter()
+            .enumerate()
+            .filter(|(_, coin_data)| coin_data.covhash != Address::coin_destroy())
+            .map(|(index, coin_data)| {
+                let mut coin_data = coin_data.clone();
+                if coin_data.denom == Denom::NewCoin {
+                    coin_data.denom = Denom::Custom(txhash);
+                }
+                (
+                    CoinID {
+                        txhash,
+                        index: index as u8,
+                    },
+                    CoinDataHeight { coin_data, height },
+                )
+            })
+            .collect();
+        let first = CoinID { txhash, index: 0 };
+        let last = CoinID {
+            txhash,
+            index: u8::MAX,
+        };
+        self.coins
+            .range(first..=last)
+            .for_each(|(coin_id, value)| match value {
+                Some(value) => {
+                    coins_created.insert(*coin_id, value.clone());
+                }
+                None => {
+                    coins_created.remove(coin_id);
+                }
+            });
+
+        TxReceipt {
+            txhash,
+            base_fee,
+            tip: tx.fee - base_fee,
+            coins_created,
+            melmint: self.outcomes.get(&txhash).copied(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::melvm::{Address, Covenant};
+    use crate::testing::functions::{always_true_coin, spend_split, split_genesis_state};
+    use crate::{CoinData, Denom, PoolKey, Transaction, TxKind};
+
+    #[test]
+    fn receipts_split_fees_and_list_coins() {
+        let (base, split) = split_genesis_state(2);
+        let plain = spend_split(&split, 0, 100_000);
+        let newcoin = Transaction::new(TxKind::Normal)
+            .add_input(split.output_coinid(1))
+            .add_output(always_true_coin((1 << 30) - 200_000 - 1000))
+            .add_output(CoinData {
+                denom: Denom::NewCoin,
+                ..always_true_coin(1 << 20)
+            })
+            .add_output(CoinData {
+                covhash: Address::coin_destroy(),
+                ..always_true_coin(1000)
+            })
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true());
+        let mut next = base.next_state();
+        next.apply_tx_batch(&[plain.clone(), newcoin.clone()])
+            .unwrap();
+        let fee_multiplier = next.fee_multiplier;
+        let (sealed, receipts) = next.seal_with_receipts(None);
+
+        assert_eq!(receipts.len(), 2);
+        assert!(receipts[0].txhash < receipts[1].txhash);
+        for tx in [&plain, &newcoin] {
+            let receipt = receipts
+                .iter()
+                .find(|r| r.txhash == tx.hash_nosigs())
+                .unwrap();
+            assert_eq!(receipt.base_fee, tx.base_fee(fee_multiplier, 0));
+            assert_eq!(receipt.base_fee + receipt.tip, tx.fee);
+            assert!(receipt.melmint.is_none());
+            // the listed coins are exactly the ones in the sealed state
+            for (coin_id, coin) in receipt.coins_created.iter() {
+                assert_eq!(sealed.inner_ref().coins.get(coin_id).0.as_ref(), Some(coin));
+            }
+        }
+        let receipt = receipts
+            .iter()
+            .find(|r| r.txhash == newcoin.hash_nosigs())
+            .unwrap();
+        // the destroyed output is not listed, and the new coin gets its real denomination
+        assert_eq!(receipt.coins_created.len(), 2);
+        assert_eq!(
+            receipt.coins_created[&newcoin.output_coinid(1)]
+                .coin_data
+                .denom,
+            Denom::Custom(newcoin.hash_nosigs())
+        );
+    }
+
+    #[test]
+    fn receipts_show_melmint_outcomes() {
+        let (base, split) = split_genesis_state(2);
+        let swap =
+            spend_split(&split, 0, 100_000).with_data(PoolKey::mel_and(Denom::Sym).to_bytes());
+
+        let newcoin = Transaction::new(TxKind::Normal)
+            .add_input(split.output_coinid(1))
+            .add_output(always_true_coin((1 << 30) - 200_000))
+            .add_output(CoinData {
+                denom: Denom::NewCoin,
+                ..always_true_coin(1 << 20)
+            })
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true());
+        let pool = PoolKey::mel_and(Denom::Custom(newcoin.hash_nosigs()));
+        let deposit = Transaction::new(TxKind::LiqDeposit)
+            .add_input(newcoin.output_coinid(0))
+            .add_input(newcoin.output_coinid(1))
+            .add_output(always_true_coin((1 << 30) - 400_000))
+            .add_output(CoinData {
+                denom: pool.right,
+                ..always_true_coin(1 << 20)
+            })
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true())
+            .with_data(pool.to_bytes());
+
+        let mut next = base.next_state();
+        next.apply_tx(&swap).unwrap();
+        next.apply_tx(&newcoin).unwrap();
+        next.apply_tx(&deposit).unwrap();
+        let (sealed, receipts) = next.seal_with_receipts(None);
+        let receipt_of = |tx: &Transaction| {
+            receipts
+                .iter()
+                .find(|r| r.txhash == tx.hash_nosigs())
+                .unwrap()
+                .clone()
+        };
+
+        let receipt = receipt_of(&swap);
+        let outcome = receipt.melmint.unwrap();
+        assert_eq!(outcome.pool, PoolKey::mel_and(Denom::Sym));
+        assert_eq!(outcome.denom_before, Denom::Mel);
+        assert_eq!(outcome.value_before, swap.outputs[0].value);
+        assert_eq!(outcome.denom_after, Denom::Sym);
+        let swapped = sealed
+            .inner_ref()
+            .coins
+            .get(&swap.output_coinid(0))
+            .0
+            .unwrap();
+        assert_eq!(swapped.coin_data.value, outcome.value_after);
+        assert_eq!(receipt.coins_created[&swap.output_coinid(0)], swapped);
+
+        // the first output becomes the liquidity tokens
+        let receipt = receipt_of(&deposit);
+        let outcome = receipt.melmint.unwrap();
+        assert_eq!(outcome.pool, pool);
+        assert_eq!(outcome.denom_before, Denom::Mel);
+        assert_eq!(outcome.denom_after, pool.liq_token_denom());
+        for (coin_id, coin) in receipt.coins_created.iter() {
+            assert_eq!(sealed.inner_ref().coins.get(coin_id).0.as_ref(), Some(coin));
+        }
+        assert!(receipt_of(&newcoin).melmint.is_none());
+    }
+}
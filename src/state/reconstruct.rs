@@ -0,0 +1,138 @@
+use super::{AbbrBlock, Block, StateError};
+use crate::{Mempool, Transaction, TxHash};
+
+use std::{collections::HashMap, hash::BuildHasher};
+
+use novasmt::ContentAddrStore;
+use thiserror::Error;
+
+/// Somewhere to look up transactions by hash when reconstructing a block from an [AbbrBlock].
+pub trait TxSource {
+    /// Returns the transaction with the given hash, if known.
+    fn get_transaction(&self, txhash: TxHash) -> Option<Transaction>;
+}
+
+impl<S: BuildHasher> TxSource for HashMap<TxHash, Transaction, S> {
+    fn get_transaction(&self, txhash: TxHash) -> Option<Transaction> {
+        self.get(&txhash).cloned()
+    }
+}
+
+impl<C: ContentAddrStore> TxSource for Mempool<C> {
+    fn get_transaction(&self, txhash: TxHash) -> Option<Transaction> {
+        self.lookup(txhash)
+    }
+}
+
+#[derive(Error, Debug)]
+/// A error that happens while reconstructing a block from an [AbbrBlock]
+pub enum ReconstructError {
+    #[error("{} transactions are missing", .0.len())]
+    MissingTransactions(Vec<TxHash>),
+    #[error("reconstructed block is invalid: {0}")]
+    InvalidBlock(#[from] StateError),
+}
+
+/// Rebuilds the full block, listing every transaction the source does not have. A transaction whose hash does not match the one it was looked up by counts as missing.
+pub(crate) fn reconstruct(
+    abbr: &AbbrBlock,
+    source: &(impl TxSource + ?Sized),
+) -> Result<Block, ReconstructError> {
+    let mut transactions = Vec::with_capacity(abbr.txhashes.len());
+    let mut missing = Vec::new();
+    for txhash in abbr.txhashes.iter() {
+        match source.get_transaction(*txhash) {
+            Some(tx) if tx.hash_nosigs() == *txhash => transactions.push(tx),
+            _ => missing.push(*txhash),
+        }
+    }
+    if !missing.is_empty() {
+        return Err(ReconstructError::MissingTransactions(missing));
+    }
+    Ok(Block {
+        header: abbr.header,
+        transactions,
+        proposer_action: abbr.proposer_action,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::testing::functions::{spend_split, split_genesis_state};
+    use crate::{Mempool, StateError};
+
+    use super::ReconstructError;
+
+    #[test]
+    fn reconstruct_from_map_and_mempool() {
+        let (base, split) = split_genesis_state(4);
+        let txx = (0..4)
+            .map(|i| spend_split(&split, i, 100_000))
+            .collect::<Vec<_>>();
+        let mut next = base.next_state();
+        next.apply_tx_batch(&txx).unwrap();
+        let sealed = next.seal(None);
+        let block = sealed.to_block();
+        let abbr = block.abbreviate();
+
+        // only half of the transactions are known
+        let mut known = txx[..2]
+            .iter()
+            .map(|tx| (tx.hash_nosigs(), tx.clone()))
+            .collect::<HashMap<_, _>>();
+        match abbr.reconstruct(&known) {
+            Err(ReconstructError::MissingTransactions(missing)) => {
+                let mut expected = txx[2..]
+                    .iter()
+                    .map(|tx| tx.hash_nosigs())
+                    .collect::<Vec<_>>();
+                expected.sort_unstable();
+                assert_eq!(missing, expected);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        // a transaction under the wrong hash does not count
+        known.insert(txx[2].hash_nosigs(), txx[3].clone());
+        known.insert(txx[3].hash_nosigs(), txx[3].clone());
+        assert!(matches!(
+            abbr.reconstruct(&known),
+            Err(ReconstructError::MissingTransactions(missing)) if missing == vec![txx[2].hash_nosigs()]
+        ));
+        known.insert(txx[2].hash_nosigs(), txx[2].clone());
+        assert_eq!(
+            abbr.reconstruct(&known).unwrap().canonical_encoding(),
+            block.canonical_encoding()
+        );
+        assert_eq!(
+            base.apply_abbr_block(&abbr, &known).unwrap().header(),
+            sealed.header()
+        );
+
+        let mut mempool = Mempool::new(&base, u128::MAX);
+        for tx in txx.iter() {
+            mempool.apply_transaction(tx).unwrap();
+        }
+        assert_eq!(
+            base.apply_abbr_block(&abbr, &mempool).unwrap().header(),
+            sealed.header()
+        );
+    }
+
+    #[test]
+    fn reconstructed_block_is_checked() {
+        let (base, split) = split_genesis_state(2);
+        let tx = spend_split(&split, 0, 100_000);
+        let mut next = base.next_state();
+        next.apply_tx(&tx).unwrap();
+        let mut abbr = next.seal(None).to_block().abbreviate();
+        abbr.header.fee_pool += 1.into();
+
+        let known = HashMap::from([(tx.hash_nosigs(), tx)]);
+        assert!(matches!(
+            base.apply_abbr_block(&abbr, &known),
+            Err(ReconstructError::InvalidBlock(StateError::WrongHeader))
+        ));
+    }
+}
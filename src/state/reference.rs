@@ -0,0 +1,379 @@
+use super::applytx::faucet_dedup_pseudocoin;
+use crate::{
+    melpow,
+    melvm::{Address, CovenantEnv},
+    stake::StakeDoc,
+    state::{melmint, supply},
+    BatchError, BatchPhase, BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom,
+    Header, NetID, State, StateError, Transaction, TxHash, TxKind,
+};
+
+use std::collections::{BTreeSet, HashMap};
+
+use novasmt::ContentAddrStore;
+use tmelcrypt::HashVal;
+
+/// Applies a batch of transactions one at a time, fully validating each against the state left by the ones before it. Slow, but meant to be obviously correct, as a reference for the parallel algorithm of [State::apply_tx_batch].
+///
+/// Since the parallel algorithm does not depend on the order of the batch, transactions are applied in dependency order: a transaction goes after every transaction of the batch whose outputs it spends, and otherwise in batch order. Coins spent twice within the batch are rejected before anything is applied, attributing the error to the later transaction, just like the parallel algorithm. If any transaction fails, the state is left unchanged.
+pub(crate) fn apply_tx_batch<C: ContentAddrStore>(
+    state: &mut State<C>,
+    txx: &[Transaction],
+) -> Result<(), BatchError> {
+    check_double_spends(txx)?;
+    let before = state.clone();
+    let last_header = before
+        .history
+        .get(&(before.height.0.saturating_sub(1).into()))
+        .0
+        .unwrap_or_else(|| before.clone().seal(None).header());
+    let mut next = state.clone();
+    for tx_index in dependency_order(txx) {
+        let tx = &txx[tx_index];
+        apply_tx(&mut next, &before, &last_header, tx)
+            .map_err(|(phase, error)| BatchError::new(tx_index, tx, phase, error))?;
+    }
+    *state = next;
+    Ok(())
+}
+
+fn check_double_spends(txx: &[Transaction]) -> Result<(), BatchError> {
+    let mut spenders: HashMap<CoinID, usize> = HashMap::new();
+    for (tx_index, tx) in txx.iter().enumerate() {
+        for coin in tx.inputs.iter() {
+            if let Some(first_tx) = spenders.insert(*coin, tx_index) {
+                let error = StateError::DoubleSpend {
+                    coin: *coin,
+                    first_tx,
+                    second_tx: tx_index,
+                };
+                return Err(BatchError::new(
+                    tx_index,
+                    tx,
+                    BatchPhase::Preliminary,
+                    error,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Orders the batch so that every transaction comes after those whose outputs it spends, taking the earliest ready transaction first.
+fn dependency_order(txx: &[Transaction]) -> Vec<usize> {
+    let mut creators: HashMap<TxHash, Vec<usize>> = HashMap::new();
+    txx.iter()
+        .enumerate()
+        .for_each(|(i, tx)| creators.entry(tx.hash_nosigs()).or_default().push(i));
+    let mut waiting_for = vec![0usize; txx.len()];
+    let mut dependents = vec![Vec::new(); txx.len()];
+    for (i, tx) in txx.iter().enumerate() {
+        for coin in tx.inputs.iter() {
+            for creator in creators.get(&coin.txhash).into_iter().flatten() {
+                waiting_for[i] += 1;
+                dependents[*creator].push(i);
+            }
+        }
+    }
+    let mut ready: BTreeSet<usize> = (0..txx.len()).filter(|i| waiting_for[*i] == 0).collect();
+    let mut order = Vec::with_capacity(txx.len());
+    while let Some(i) = ready.pop_first() {
+        order.push(i);
+        for dependent in dependents[i].iter() {
+            waiting_for[*dependent] -= 1;
+            if waiting_for[*dependent] == 0 {
+                ready.insert(*dependent);
+            }
+        }
+    }
+    order
+}
+
+/// Applies one transaction, in the same order of checks as the phases of the parallel algorithm. Lookups that the parallel algorithm makes before any output is created are made against the state before the batch.
+fn apply_tx<C: ContentAddrStore>(
+    state: &mut State<C>,
+    before: &State<C>,
+    last_header: &Header,
+    tx: &Transaction,
+) -> Result<(), (BatchPhase, StateError)> {
+    check_preliminary(state, tx).map_err(|e| (BatchPhase::Preliminary, e))?;
+    match tx.kind {
+        TxKind::DoscMint => check_doscmint(state, before, tx),
+        TxKind::Stake => check_stake(state, tx),
+        _ => Ok(()),
+    }
+    .map_err(|e| (BatchPhase::Special, e))?;
+    spend_inputs(state, last_header, tx).map_err(|e| (BatchPhase::Inputs, e))?;
+    create_outputs(state, tx);
+    Ok(())
+}
+
+fn check_preliminary<C: ContentAddrStore>(
+    state: &mut State<C>,
+    tx: &Transaction,
+) -> Result<(), StateError> {
+    let txhash = tx.hash_nosigs();
+    if tx.kind == TxKind::Faucet {
+        let pseudocoin = faucet_dedup_pseudocoin(txhash);
+        if state.coins.get(&pseudocoin).0.is_some() {
+            return Err(StateError::DuplicateTx);
+        }
+        state.coins.insert(
+            pseudocoin,
+            CoinDataHeight {
+                coin_data: CoinData {
+                    denom: Denom::Mel,
+                    value: 0.into(),
+                    additional_data: vec![],
+                    covhash: HashVal::default().into(),
+                },
+                height: 0.into(),
+            },
+        );
+    }
+    if !tx.is_well_formed_with(state.params.max_coinval) {
+        return Err(StateError::MalformedTx);
+    }
+    if tx.kind == TxKind::Faucet && state.network == NetID::Mainnet {
+        return Err(StateError::UnbalancedInOut);
+    }
+    let min_fee = tx.base_fee(state.fee_multiplier, 0);
+    if tx.fee < min_fee {
+        return Err(StateError::InsufficientFees(min_fee));
+    }
+    state.tips.0 = state.tips.0.saturating_add((tx.fee - min_fee).0);
+    state.fee_pool.0 = state.fee_pool.0.saturating_add(min_fee.0);
+    state.transactions.insert(txhash, tx.clone());
+    Ok(())
+}
+
+fn check_doscmint<C: ContentAddrStore>(
+    state: &mut State<C>,
+    before: &State<C>,
+    tx: &Transaction,
+) -> Result<(), StateError> {
+    let coin_id = *tx.inputs.first().ok_or(StateError::MalformedTx)?;
+    let coin_data = before
+        .coins
+        .get(&coin_id)
+        .0
+        .ok_or(StateError::MalformedTx)?;
+    let age = (state.height - coin_data.height).0;
+    if age < 100 {
+        return Err(StateError::InvalidMelPoW);
+    }
+    let chi = tmelcrypt::hash_keyed(
+        state
+            .history
+            .get(&coin_data.height)
+            .0
+            .ok_or(StateError::MalformedTx)?
+            .hash(),
+        stdcode::serialize(&coin_id).unwrap(),
+    );
+    let (difficulty, proof_bytes): (u32, Vec<u8>) =
+        stdcode::deserialize(&tx.data).map_err(|_| StateError::MalformedTx)?;
+    let proof = melpow::Proof::from_bytes(&proof_bytes).ok_or(StateError::MalformedTx)?;
+    if !proof.verify(&chi, difficulty as _) {
+        return Err(StateError::InvalidMelPoW);
+    }
+    let my_speed = 2u128.pow(difficulty) / age as u128;
+    let previous_speed = state
+        .history
+        .get(&BlockHeight(state.height.0 - 1))
+        .0
+        .ok_or(StateError::MalformedTx)?
+        .dosc_speed;
+    let reward_real = melmint::calculate_reward(my_speed, previous_speed, difficulty);
+    state.dosc_speed = state.dosc_speed.max(my_speed);
+    let reward_nom = CoinValue(melmint::dosc_inflate_r2n(state.height, reward_real));
+    let total_dosc_output = tx
+        .total_outputs()
+        .get(&Denom::NomDosc)
+        .cloned()
+        .unwrap_or_default();
+    if total_dosc_output > reward_nom {
+        return Err(StateError::InvalidMelPoW);
+    }
+    Ok(())
+}
+
+fn check_stake<C: ContentAddrStore>(
+    state: &mut State<C>,
+    tx: &Transaction,
+) -> Result<(), StateError> {
+    let stake_doc: StakeDoc =
+        stdcode::deserialize(&tx.data).map_err(|_| StateError::MalformedTx)?;
+    let first_coin = tx.outputs.first().ok_or(StateError::MalformedTx)?;
+    if state.height < state.params.old_staking_rules_until {
+        return Ok(());
+    }
+    if first_coin.denom != Denom::Sym {
+        return Err(StateError::MalformedTx);
+    }
+    // inconsistent stakes are accepted, but have no effect
+    if stake_doc.e_start > state.epoch()
+        && stake_doc.e_post_end > stake_doc.e_start
+        && stake_doc.syms_staked == first_coin.value
+        && state.stakes.get(&tx.hash_nosigs()).0.is_none()
+    {
+        state.stakes.insert(tx.hash_nosigs(), stake_doc);
+    }
+    Ok(())
+}
+
+fn spend_inputs<C: ContentAddrStore>(
+    state: &mut State<C>,
+    last_header: &Header,
+    tx: &Transaction,
+) -> Result<(), StateError> {
+    let scripts = tx.script_as_map();
+    let mut in_coins: HashMap<Denom, u128> = HashMap::new();
+    for (spend_idx, coin_id) in tx.inputs.iter().enumerate() {
+        if state.stakes.get(&coin_id.txhash).0.is_some() {
+            return Err(StateError::CoinLocked);
+        }
+        let coin_data = state
+            .coins
+            .get(coin_id)
+            .0
+            .ok_or(StateError::NonexistentCoin(*coin_id))?;
+        let covhash = coin_data.coin_data.covhash;
+        let script = scripts
+            .get(&covhash)
+            .ok_or(StateError::NonexistentScript(covhash))?;
+        let env = CovenantEnv {
+            parent_coinid: coin_id,
+            parent_cdh: &coin_data,
+            spender_index: spend_idx as u8,
+            last_header,
+        };
+        if !script.check(tx, env) {
+            return Err(StateError::ViolatesScript(covhash));
+        }
+        state.coins.delete(coin_id);
+        supply::update(state, |supply| supply.remove_coin(&coin_data.coin_data));
+        *in_coins.entry(coin_data.coin_data.denom).or_default() += coin_data.coin_data.value.0;
+    }
+    if tx.kind != TxKind::Faucet {
+        for (denom, value) in tx.total_outputs() {
+            if tx.kind == TxKind::DoscMint && denom == Denom::NomDosc {
+                continue;
+            }
+            let in_value = in_coins.get(&denom).copied().unwrap_or(u128::MAX);
+            if denom != Denom::NewCoin && value != CoinValue(in_value) {
+                return Err(StateError::UnbalancedInOut);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn create_outputs<C: ContentAddrStore>(state: &mut State<C>, tx: &Transaction) {
+    for (index, coin_data) in tx.outputs.iter().enumerate() {
+        let mut coin_data = coin_data.clone();
+        if coin_data.denom == Denom::NewCoin {
+            coin_data.denom = Denom::Custom(tx.hash_nosigs());
+        }
+        if coin_data.covhash != Address::coin_destroy() {
+            supply::update(state, |supply| supply.add_coin(&coin_data));
+            let height = state.height;
+            state.coins.insert(
+                tx.output_coinid(index as u8),
+                CoinDataHeight { coin_data, height },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::melvm::Covenant;
+    use crate::testing::functions::{always_true_coin, spend_split, split_genesis_state};
+    use crate::{BatchPhase, Transaction, TxKind};
+
+    /// Spends the first output of the given transaction, paying the given fee.
+    fn spend_first_output(tx: &Transaction, fee: u128) -> Transaction {
+        Transaction::new(TxKind::Normal)
+            .add_input(tx.output_coinid(0))
+            .add_output(always_true_coin(tx.outputs[0].value.0 - fee))
+            .with_fee(fee.into())
+            .add_script(Covenant::always_true())
+    }
+
+    #[test]
+    fn matches_parallel_out_of_order() {
+        let (base, split) = split_genesis_state(2);
+        let first = spend_split(&split, 0, 100_000);
+        let second = spend_first_output(&first, 100_000);
+        let third = spend_first_output(&second, 100_000);
+        let faucet = Transaction::new(TxKind::Faucet)
+            .add_output(always_true_coin(1000))
+            .with_fee(100_000.into());
+        // every transaction comes before the one whose output it spends
+        let txx = [
+            third,
+            faucet,
+            second,
+            spend_split(&split, 1, 100_000),
+            first,
+        ];
+        let mut parallel = base.next_state();
+        parallel.apply_tx_batch(&txx).unwrap();
+        let mut sequential = base.next_state();
+        sequential.apply_tx_batch_sequential(&txx).unwrap();
+        assert_eq!(parallel.supply, sequential.supply);
+        assert_eq!(parallel.seal(None).header(), sequential.seal(None).header());
+    }
+
+    #[test]
+    fn blames_the_same_transaction() {
+        let (base, split) = split_genesis_state(3);
+        let first = spend_split(&split, 0, 100_000);
+        let second = spend_first_output(&first, 100_000);
+        let mut unbalanced = spend_split(&split, 1, 100_000);
+        unbalanced.outputs[0].value += 1.into();
+        let mut unscripted = spend_split(&split, 1, 100_000);
+        unscripted.scripts.clear();
+        let mut stealing = spend_split(&split, 2, 100_000);
+        stealing.inputs.push(split.output_coinid(0));
+        let cases = [
+            (
+                vec![second.clone(), unbalanced, first.clone()],
+                1,
+                BatchPhase::Inputs,
+            ),
+            (
+                vec![unscripted, second.clone(), first.clone()],
+                0,
+                BatchPhase::Inputs,
+            ),
+            (
+                vec![second.clone(), first.clone(), stealing],
+                2,
+                BatchPhase::Preliminary,
+            ),
+            (
+                vec![second, spend_split(&split, 1, 0), first],
+                1,
+                BatchPhase::Preliminary,
+            ),
+        ];
+        for (txx, tx_index, phase) in cases {
+            let parallel = base.next_state().apply_tx_batch(&txx).unwrap_err();
+            let mut sequential = base.next_state();
+            let error = sequential.apply_tx_batch_sequential(&txx).unwrap_err();
+            assert_eq!((parallel.tx_index, parallel.phase), (tx_index, phase));
+            assert_eq!((error.tx_index, error.phase), (tx_index, phase));
+            assert_eq!(
+                format!("{:?}", parallel.error),
+                format!("{:?}", error.error)
+            );
+            // the state is left unchanged
+            assert_eq!(
+                sequential.coins.root_hash(),
+                base.next_state().coins.root_hash()
+            );
+        }
+    }
+}
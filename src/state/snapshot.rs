@@ -0,0 +1,457 @@
+use super::{supply, DecodeError, Header, SealedState, Supply, SupplyError};
+use crate::{ChainParams, Denom, PoolKey};
+
+use std::{
+    collections::BTreeSet,
+    convert::TryInto,
+    io::{Read, Write},
+};
+
+use novasmt::{ContentAddrStore, Database, Tree};
+use thiserror::Error;
+use tmelcrypt::HashVal;
+
+/// Magic number at the start of every snapshot.
+const MAGIC: [u8; 4] = *b"MLSN";
+
+/// Current version of the snapshot format.
+const VERSION: u8 = 1;
+
+/// Maximum size of the payload of a chunk written by [export].
+const CHUNK_SIZE: usize = 1 << 20;
+
+#[derive(Error, Debug)]
+/// A error that happens while importing a snapshot
+pub enum SnapshotError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not a snapshot")]
+    BadMagic,
+    #[error("unsupported snapshot version {0}")]
+    UnsupportedVersion(u8),
+    #[error("chunk checksum mismatch")]
+    BadChecksum,
+    #[error("malformed chunk")]
+    Malformed,
+    #[error("cannot decode state: {0}")]
+    Decode(#[from] DecodeError),
+    #[error("snapshot is of block {0}, not the expected one")]
+    UnexpectedHeader(HashVal),
+    #[error("rebuilt state does not match the snapshot's header")]
+    HeaderMismatch,
+    #[error("snapshot has chain parameters {0:?}, which are not the network's")]
+    ParamsMismatch(Box<ChainParams>),
+    #[error("snapshot has supply {recorded:?}, but its coins and pools add up to {scanned:?}")]
+    SupplyMismatch { recorded: Supply, scanned: Supply },
+    #[error("the supply of the snapshot is invalid: {0}")]
+    BadSupply(SupplyError),
+}
+
+/// Writes a snapshot: the magic number and version, a chunk with the header, the partial encoding and the keys of the pools whose preimages are known, and then every entry of the five SMTs. Each SMT is a sequence of chunks ended by an empty one.
+///
+/// Every chunk is its payload prefixed by its length as a big-endian u32, followed by the hash of the payload.
+pub(crate) fn export<C: ContentAddrStore>(
+    sealed: &SealedState<C>,
+    mut writer: impl Write,
+) -> std::io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    let state = &sealed.0;
+    let pool_keys: BTreeSet<PoolKey> = state
+        .pools
+        .iter()
+        .filter_map(Result::ok)
+        .map(|(key, _)| key)
+        .chain(
+            builtin_pools()
+                .into_iter()
+                .filter(|key| matches!(state.pools.try_get(key), Ok((Some(_), _)))),
+        )
+        .collect();
+    let preamble = (sealed.header(), sealed.partial_encoding(), pool_keys);
+    write_chunk(&mut writer, &stdcode::serialize(&preamble).unwrap())?;
+
+    for tree in [
+        &state.history.mapping,
+        &state.coins.mapping,
+        &state.transactions.mapping,
+        &state.pools.mapping,
+        &state.stakes.mapping,
+    ] {
+        let mut payload = Vec::new();
+        for (key, value) in tree.iter() {
+            payload.extend_from_slice(&key);
+            payload.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            payload.extend_from_slice(&value);
+            if payload.len() >= CHUNK_SIZE {
+                write_chunk(&mut writer, &payload)?;
+                payload.clear();
+            }
+        }
+        if !payload.is_empty() {
+            write_chunk(&mut writer, &payload)?;
+        }
+        write_chunk(&mut writer, &[])?;
+    }
+    writer.flush()
+}
+
+/// Reads a snapshot written by [export], rebuilding its trees in the given database. The header it records must hash to `expected`.
+///
+/// The supply is not covered by the header, so it is recomputed from the coins and pools and must match. If some pool's key is unknown, the pools cannot all be scanned and the supply is dropped instead.
+pub(crate) fn import<C: ContentAddrStore>(
+    mut reader: impl Read,
+    expected: HashVal,
+    params: &ChainParams,
+    db: &Database<C>,
+) -> Result<SealedState<C>, SnapshotError> {
+    let mut magic = [0u8; 5];
+    reader.read_exact(&mut magic)?;
+    if magic[..4] != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = magic[4];
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let preamble = read_chunk(&mut reader)?;
+    let (header, encoding, pool_keys): (Header, Vec<u8>, BTreeSet<PoolKey>) =
+        stdcode::deserialize(&preamble).map_err(|_| SnapshotError::Malformed)?;
+    if header.hash() != expected {
+        return Err(SnapshotError::UnexpectedHeader(header.hash()));
+    }
+
+    let mut roots = Vec::with_capacity(5);
+    for _ in 0..5 {
+        let mut tree = db.get_tree([0; 32]).unwrap();
+        loop {
+            let payload = read_chunk(&mut reader)?;
+            if payload.is_empty() {
+                break;
+            }
+            insert_entries(&mut tree, &payload)?;
+        }
+        roots.push(HashVal(tree.root_hash()));
+    }
+
+    let expected_roots = [
+        header.history_hash,
+        header.coins_hash,
+        header.transactions_hash,
+        header.pools_hash,
+        header.stakes_hash,
+    ];
+    if roots != expected_roots {
+        return Err(SnapshotError::HeaderMismatch);
+    }
+    let mut sealed = match SealedState::from_partial_encoding(&encoding, params, db) {
+        Err(DecodeError::ParamsMismatch(found)) => {
+            return Err(SnapshotError::ParamsMismatch(found))
+        }
+        sealed => sealed?,
+    };
+    if sealed.header() != header {
+        return Err(SnapshotError::HeaderMismatch);
+    }
+
+    // the pool keys listed by the snapshot must all exist, while built-in pools are only looked for
+    let state = &mut sealed.0;
+    let mut known = BTreeSet::new();
+    for key in pool_keys {
+        if state.pools.try_get(&key)?.0.is_none() {
+            return Err(SnapshotError::Malformed);
+        }
+        known.insert(key);
+    }
+    for key in builtin_pools() {
+        if state.pools.try_get(&key)?.0.is_some() {
+            known.insert(key);
+        }
+    }
+    for key in known.iter() {
+        state.pools.record_key(key);
+    }
+    if known.len() == state.pools.mapping.iter().count() {
+        let scanned = supply::scan(state, known).map_err(SnapshotError::BadSupply)?;
+        match state.supply.take() {
+            Some(recorded) if recorded != scanned => {
+                return Err(SnapshotError::SupplyMismatch { recorded, scanned })
+            }
+            _ => state.supply = Some(scanned),
+        }
+    } else if state.supply.is_some() {
+        log::warn!(
+            "dropping the supply of the snapshot at height {}, since some pool keys are unknown",
+            state.height
+        );
+        state.supply = None;
+    }
+    Ok(sealed)
+}
+
+/// The pools that exist on every network once the right TIPs apply, whose keys are always known.
+fn builtin_pools() -> [PoolKey; 3] {
+    [
+        PoolKey::mel_and(Denom::Sym),
+        PoolKey::mel_and(Denom::NomDosc),
+        PoolKey::new(Denom::NomDosc, Denom::Sym),
+    ]
+}
+
+fn write_chunk(writer: &mut impl Write, payload: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)?;
+    writer.write_all(&tmelcrypt::hash_single(payload))
+}
+
+fn read_chunk(reader: &mut impl Read) -> Result<Vec<u8>, SnapshotError> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    let mut checksum = [0u8; 32];
+    reader.read_exact(&mut checksum)?;
+    if tmelcrypt::hash_single(&payload).0 != checksum {
+        return Err(SnapshotError::BadChecksum);
+    }
+    Ok(payload)
+}
+
+fn insert_entries<C: ContentAddrStore>(
+    tree: &mut Tree<C>,
+    mut payload: &[u8],
+) -> Result<(), SnapshotError> {
+    while !payload.is_empty() {
+        if payload.len() < 36 {
+            return Err(SnapshotError::Malformed);
+        }
+        let (key, rest) = payload.split_at(32);
+        let (len, rest) = rest.split_at(4);
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        if rest.len() < len || len == 0 {
+            return Err(SnapshotError::Malformed);
+        }
+        let (value, rest) = rest.split_at(len);
+        tree.insert(key.try_into().unwrap(), value);
+        payload = rest;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use novasmt::{Database, InMemoryCas};
+
+    use crate::melvm::Covenant;
+    use crate::testing::functions::{always_true_coin, spend_split, split_genesis_state};
+    use crate::{
+        ChainParams, CoinData, CoinID, CoinValue, Denom, PoolKey, SealedState, Tip, Transaction,
+        TxKind,
+    };
+
+    use super::SnapshotError;
+
+    fn two_states() -> (SealedState<InMemoryCas>, SealedState<InMemoryCas>) {
+        let (base, split) = split_genesis_state(3);
+        let mut next = base.next_state();
+        next.apply_tx_batch(&[
+            spend_split(&split, 0, 100_000),
+            spend_split(&split, 1, 100_000),
+        ])
+        .unwrap();
+        (base, next.seal(None))
+    }
+
+    /// Exports a state and imports it into an empty database, with the network's parameters.
+    fn round_trip(
+        sealed: &SealedState<InMemoryCas>,
+    ) -> Result<SealedState<InMemoryCas>, SnapshotError> {
+        let mut snapshot = Vec::new();
+        sealed.export_snapshot(&mut snapshot).unwrap();
+        let db = Database::new(InMemoryCas::default());
+        SealedState::import_snapshot(snapshot.as_slice(), sealed.header().hash(), &testnet(), &db)
+    }
+
+    /// The parameters of testnet, where [split_genesis_state] builds its states.
+    fn testnet() -> ChainParams {
+        ChainParams::mainnet()
+    }
+
+    /// Length of the magic number, the version and the preamble chunk.
+    fn preamble_len(snapshot: &[u8]) -> usize {
+        let len = u32::from_be_bytes(snapshot[5..9].try_into().unwrap()) as usize;
+        5 + 4 + len + 32
+    }
+
+    #[test]
+    fn round_trip_into_empty_database() {
+        let (_, sealed) = two_states();
+        let mut snapshot = Vec::new();
+        sealed.export_snapshot(&mut snapshot).unwrap();
+
+        let db = Database::new(InMemoryCas::default());
+        let imported = SealedState::import_snapshot(
+            snapshot.as_slice(),
+            sealed.header().hash(),
+            &testnet(),
+            &db,
+        )
+        .unwrap();
+        assert_eq!(imported.header(), sealed.header());
+        assert_eq!(imported.partial_encoding(), sealed.partial_encoding());
+        let state = imported.inner_ref();
+        assert_eq!(state.coins.val_iter().count(), 3);
+        assert!(state.coins.get(&CoinID::zero_zero()).0.is_none());
+        assert_eq!(state.transactions.val_iter().count(), 2);
+        // the imported state is fully usable
+        let next = imported.next_state().seal(None);
+        assert_eq!(next.header(), sealed.next_state().seal(None).header());
+    }
+
+    #[test]
+    fn rejects_corruption() {
+        let (base, sealed) = two_states();
+        let mut snapshot = Vec::new();
+        sealed.export_snapshot(&mut snapshot).unwrap();
+        let db = Database::new(InMemoryCas::default());
+
+        let mut flipped = snapshot.clone();
+        let last = flipped.len() - 40;
+        flipped[last] ^= 1;
+        assert!(matches!(
+            SealedState::import_snapshot(
+                flipped.as_slice(),
+                sealed.header().hash(),
+                &testnet(),
+                &db
+            ),
+            Err(SnapshotError::BadChecksum)
+        ));
+        assert!(matches!(
+            SealedState::import_snapshot(
+                &snapshot[..snapshot.len() - 1],
+                sealed.header().hash(),
+                &testnet(),
+                &db
+            ),
+            Err(SnapshotError::Io(_))
+        ));
+        assert!(matches!(
+            SealedState::import_snapshot(&snapshot[1..], sealed.header().hash(), &testnet(), &db),
+            Err(SnapshotError::BadMagic)
+        ));
+        let mut future = snapshot.clone();
+        future[4] = 2;
+        assert!(matches!(
+            SealedState::import_snapshot(
+                future.as_slice(),
+                sealed.header().hash(),
+                &testnet(),
+                &db
+            ),
+            Err(SnapshotError::UnsupportedVersion(2))
+        ));
+        // a valid snapshot, but not of the block asked for
+        assert!(matches!(
+            SealedState::import_snapshot(snapshot.as_slice(), base.header().hash(), &testnet(), &db),
+            Err(SnapshotError::UnexpectedHeader(found)) if found == sealed.header().hash()
+        ));
+
+        // the header of one state with the contents of another
+        let mut other = Vec::new();
+        base.export_snapshot(&mut other).unwrap();
+        let mut spliced = snapshot[..preamble_len(&snapshot)].to_vec();
+        spliced.extend_from_slice(&other[preamble_len(&other)..]);
+        assert!(matches!(
+            SealedState::import_snapshot(
+                spliced.as_slice(),
+                sealed.header().hash(),
+                &testnet(),
+                &db
+            ),
+            Err(SnapshotError::HeaderMismatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_other_params() {
+        let (_, sealed) = two_states();
+        let mut snapshot = Vec::new();
+        sealed.export_snapshot(&mut snapshot).unwrap();
+        let db = Database::new(InMemoryCas::default());
+        let other = testnet().with_activation_height(Tip::Tip903, 5.into());
+        assert!(matches!(
+            SealedState::import_snapshot(snapshot.as_slice(), sealed.header().hash(), &other, &db),
+            Err(SnapshotError::ParamsMismatch(params)) if *params == testnet()
+        ));
+    }
+
+    #[test]
+    fn checks_supply() {
+        let (_, sealed) = two_states();
+        let imported = round_trip(&sealed).unwrap();
+        assert!(imported.inner_ref().supply.is_some());
+        assert_eq!(imported.inner_ref().supply, sealed.inner_ref().supply);
+
+        // a supply that does not add up
+        let mut tampered = sealed.clone();
+        let supply = tampered.0.supply.as_mut().unwrap();
+        supply.coins.insert(Denom::Sym, CoinValue(1));
+        assert!(matches!(
+            round_trip(&tampered),
+            Err(SnapshotError::SupplyMismatch { recorded, scanned })
+                if scanned == sealed.inner_ref().supply.clone().unwrap()
+                    && recorded == tampered.inner_ref().supply.clone().unwrap()
+        ));
+    }
+
+    #[test]
+    fn scans_custom_pools_only_if_known() {
+        let (base, split) = split_genesis_state(1);
+        let newcoin = Transaction::new(TxKind::Normal)
+            .add_input(split.output_coinid(0))
+            .add_output(always_true_coin((1 << 30) - 200_000))
+            .add_output(CoinData {
+                denom: Denom::NewCoin,
+                ..always_true_coin(1 << 20)
+            })
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true());
+        let pool = PoolKey::mel_and(Denom::Custom(newcoin.hash_nosigs()));
+        let deposit = Transaction::new(TxKind::LiqDeposit)
+            .add_input(newcoin.output_coinid(0))
+            .add_input(newcoin.output_coinid(1))
+            .add_output(always_true_coin((1 << 30) - 400_000))
+            .add_output(CoinData {
+                denom: pool.right,
+                ..always_true_coin(1 << 20)
+            })
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true())
+            .with_data(pool.to_bytes());
+
+        // preimages are shared by the whole database, so the unrecorded case must come first
+        for record in [false, true] {
+            let mut next = base.next_state();
+            next.set_record_keys(record);
+            next.apply_tx_batch(&[newcoin.clone(), deposit.clone()])
+                .unwrap();
+            let sealed = next.seal(None);
+            assert!(sealed.inner_ref().supply.is_some());
+
+            let imported = round_trip(&sealed).unwrap();
+            let state = imported.inner_ref();
+            if record {
+                // the key of the pool travels with the snapshot
+                assert_eq!(state.supply, sealed.inner_ref().supply);
+                assert!(state.pools.iter().all(|entry| entry.is_ok()));
+            } else {
+                assert_eq!(state.supply, None);
+            }
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use super::State;
+use crate::{CoinData, CoinValue, Denom, PoolKey, PoolState};
+
+use std::collections::BTreeMap;
+
+use novasmt::ContentAddrStore;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// How much of every denomination exists, kept up to date as transactions are applied and blocks are sealed, so that it can be audited without scanning every coin. Denominations of which nothing exists are left out.
+///
+/// MEL in the fee pool and in tips is not included, since the state already keeps track of it; [State::total_supply] adds it in.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Supply {
+    /// Total value of the unspent coins of each denomination, including proposer reward pseudocoins.
+    pub coins: BTreeMap<Denom, CoinValue>,
+    /// Total value held in liquidity pools, of each denomination.
+    pub pooled: BTreeMap<Denom, CoinValue>,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+/// A error that happens while updating a [Supply]
+pub enum SupplyError {
+    #[error("total supply of {0} overflows")]
+    Overflow(Denom),
+    #[error("removing more {0} than the supply has")]
+    Underflow(Denom),
+}
+
+impl Supply {
+    /// Returns the total value of the given denomination in coins and pools.
+    pub fn get(&self, denom: Denom) -> CoinValue {
+        let coins = self.coins.get(&denom).copied().unwrap_or_default();
+        let pooled = self.pooled.get(&denom).copied().unwrap_or_default();
+        coins + pooled
+    }
+
+    /// Accounts for a new coin.
+    pub(crate) fn add_coin(&mut self, coin: &CoinData) -> Result<(), SupplyError> {
+        add(&mut self.coins, coin.denom, coin.value)
+    }
+
+    /// Accounts for a coin that was spent, destroyed, or replaced.
+    pub(crate) fn remove_coin(&mut self, coin: &CoinData) -> Result<(), SupplyError> {
+        remove(&mut self.coins, coin.denom, coin.value)
+    }
+
+    /// Accounts for the contents of a new pool state.
+    pub(crate) fn add_pool(&mut self, key: PoolKey, pool: &PoolState) -> Result<(), SupplyError> {
+        add(&mut self.pooled, key.left, pool.lefts.into())?;
+        add(&mut self.pooled, key.right, pool.rights.into())
+    }
+
+    /// Accounts for the contents of a pool state that was replaced.
+    pub(crate) fn remove_pool(
+        &mut self,
+        key: PoolKey,
+        pool: &PoolState,
+    ) -> Result<(), SupplyError> {
+        remove(&mut self.pooled, key.left, pool.lefts.into())?;
+        remove(&mut self.pooled, key.right, pool.rights.into())
+    }
+}
+
+fn add(
+    totals: &mut BTreeMap<Denom, CoinValue>,
+    denom: Denom,
+    value: CoinValue,
+) -> Result<(), SupplyError> {
+    if value.0 > 0 {
+        let total = totals.entry(denom).or_default();
+        total.0 = total
+            .0
+            .checked_add(value.0)
+            .ok_or(SupplyError::Overflow(denom))?;
+    }
+    Ok(())
+}
+
+fn remove(
+    totals: &mut BTreeMap<Denom, CoinValue>,
+    denom: Denom,
+    value: CoinValue,
+) -> Result<(), SupplyError> {
+    if value.0 == 0 {
+        return Ok(());
+    }
+    let total = totals
+        .get_mut(&denom)
+        .ok_or(SupplyError::Underflow(denom))?;
+    total.0 = total
+        .0
+        .checked_sub(value.0)
+        .ok_or(SupplyError::Underflow(denom))?;
+    if total.0 == 0 {
+        totals.remove(&denom);
+    }
+    Ok(())
+}
+
+/// Updates the tracked supply of a state, if any. If the update fails, the tracked supply no longer matches the trees, usually because they were changed directly, so it is dropped rather than kept wrong. [State::audit] is where mismatches are reported.
+pub(crate) fn update<C: ContentAddrStore>(
+    state: &mut State<C>,
+    f: impl FnOnce(&mut Supply) -> Result<(), SupplyError>,
+) {
+    if let Some(supply) = state.supply.as_mut() {
+        if let Err(err) = f(supply) {
+            log::warn!(
+                "dropping the tracked supply at height {}: {}",
+                state.height,
+                err
+            );
+            state.supply = None;
+        }
+    }
+}
+
+/// Computes the supply of a state from scratch, by scanning every coin and the given pools.
+pub(crate) fn scan<C: ContentAddrStore>(
+    state: &State<C>,
+    pools: impl IntoIterator<Item = PoolKey>,
+) -> Result<Supply, SupplyError> {
+    let mut supply = Supply::default();
+    for coin in state.coins.val_iter() {
+        supply.add_coin(&coin.coin_data)?;
+    }
+    for key in pools {
+        if let Some(pool) = state.pools.get(&key).0 {
+            supply.add_pool(key, &pool)?;
+        }
+    }
+    Ok(supply)
+}
+
+#[cfg(test)]
+mod tests {
+    use novasmt::InMemoryCas;
+
+    use crate::melvm::{Address, Covenant};
+    use crate::testing::functions::{
+        always_true_coin, check_supply, spend_split, split_genesis_state,
+    };
+    use crate::{
+        CoinData, CoinDataHeight, CoinID, CoinValue, Denom, PoolKey, ProposerAction, State, Supply,
+        SupplyError, Transaction, TxKind,
+    };
+
+    /// MEL that is not in any pool, which only transactions can change.
+    fn unpooled_mel(state: &State<InMemoryCas>) -> CoinValue {
+        let pooled = state.supply.as_ref().unwrap().pooled.get(&Denom::Mel);
+        state.total_supply(Denom::Mel).unwrap() - pooled.copied().unwrap_or_default()
+    }
+
+    #[test]
+    fn conserved_across_seal_and_apply_block() {
+        let (base, split) = split_genesis_state(3);
+        check_supply(base.inner_ref());
+        let burn = Transaction::new(TxKind::Normal)
+            .add_input(split.output_coinid(0))
+            .add_output(always_true_coin((1 << 30) - (1 << 20) - 100_000))
+            .add_output(CoinData {
+                covhash: Address::coin_destroy(),
+                ..always_true_coin(1 << 20)
+            })
+            .with_fee(100_000.into())
+            .add_script(Covenant::always_true());
+        let newcoin = Transaction::new(TxKind::Normal)
+            .add_input(split.output_coinid(1))
+            .add_output(always_true_coin((1 << 30) - 100_000))
+            .add_output(CoinData {
+                denom: Denom::NewCoin,
+                ..always_true_coin(1 << 25)
+            })
+            .with_fee(100_000.into())
+            .add_script(Covenant::always_true());
+        let faucet = Transaction::new(TxKind::Faucet)
+            .add_output(always_true_coin(1 << 20))
+            .with_fee(100_000.into());
+        let minted = faucet.outputs[0].value + faucet.fee - CoinValue(1 << 20);
+
+        let mut next = base.next_state();
+        next.apply_tx_batch(&[
+            burn,
+            newcoin.clone(),
+            faucet,
+            spend_split(&split, 2, 100_000),
+        ])
+        .unwrap();
+        check_supply(&next);
+        assert_eq!(unpooled_mel(&next), unpooled_mel(base.inner_ref()) + minted);
+        assert_eq!(
+            next.total_supply(Denom::Custom(newcoin.hash_nosigs())),
+            Some(CoinValue(1 << 25))
+        );
+
+        let sealed = next.seal(Some(ProposerAction {
+            fee_multiplier_delta: 0,
+            reward_dest: Covenant::always_true().hash(),
+        }));
+        check_supply(sealed.inner_ref());
+        // the proposer reward only moves MEL out of the fee pool and tips
+        let reward = CoinID::proposer_reward(sealed.inner_ref().height);
+        assert!(sealed.inner_ref().coins.get(&reward).0.is_some());
+        assert_eq!(
+            unpooled_mel(sealed.inner_ref()),
+            unpooled_mel(base.inner_ref()) + minted
+        );
+
+        let (applied, undo) = base.apply_block_with_undo(&sealed.to_block()).unwrap();
+        check_supply(applied.inner_ref());
+        assert_eq!(applied.inner_ref().supply, sealed.inner_ref().supply);
+        assert_eq!(
+            applied.rollback(&undo).unwrap().inner_ref().supply,
+            base.inner_ref().supply
+        );
+    }
+
+    #[test]
+    fn tracks_melmint() {
+        let (base, split) = split_genesis_state(2);
+        let swap =
+            spend_split(&split, 0, 100_000).with_data(PoolKey::mel_and(Denom::Sym).to_bytes());
+        let newcoin = Transaction::new(TxKind::Normal)
+            .add_input(split.output_coinid(1))
+            .add_output(always_true_coin((1 << 30) - 200_000))
+            .add_output(CoinData {
+                denom: Denom::NewCoin,
+                ..always_true_coin(1 << 20)
+            })
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true());
+        let pool = PoolKey::mel_and(Denom::Custom(newcoin.hash_nosigs()));
+        let deposit = Transaction::new(TxKind::LiqDeposit)
+            .add_input(newcoin.output_coinid(0))
+            .add_input(newcoin.output_coinid(1))
+            .add_output(always_true_coin((1 << 30) - 400_000))
+            .add_output(CoinData {
+                denom: pool.right,
+                ..always_true_coin(1 << 20)
+            })
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true())
+            .with_data(pool.to_bytes());
+
+        let mut next = base.next_state();
+        next.apply_tx_batch(&[swap, newcoin, deposit]).unwrap();
+        let sealed = next.seal(None);
+        check_supply(sealed.inner_ref());
+        let supply = sealed.inner_ref().supply.clone().unwrap();
+        // the deposited coins went into the pool, in exchange for liquidity tokens
+        assert_eq!(supply.pooled[&pool.right], CoinValue(1 << 20));
+        assert!(supply.coins[&pool.liq_token_denom()] > CoinValue(0));
+        assert!(supply.coins[&Denom::Sym] > CoinValue(0));
+    }
+
+    #[test]
+    fn dropped_when_trees_change_directly() {
+        let (base, split) = split_genesis_state(1);
+        let mut next = base.next_state();
+        // a coin the tracked supply does not know about
+        let coin = CoinData {
+            denom: Denom::Sym,
+            ..always_true_coin(1000)
+        };
+        let coin_id = CoinID {
+            txhash: tmelcrypt::HashVal([1; 32]).into(),
+            index: 0,
+        };
+        next.coins.insert(
+            coin_id,
+            CoinDataHeight {
+                coin_data: coin.clone(),
+                height: 0.into(),
+            },
+        );
+        let spend = Transaction::new(TxKind::Normal)
+            .add_input(coin_id)
+            .add_input(split.output_coinid(0))
+            .add_output(coin)
+            .add_output(always_true_coin((1 << 30) - 100_000))
+            .with_fee(100_000.into())
+            .add_script(Covenant::always_true());
+        // spending it is valid, and only makes the supply unknown
+        next.apply_tx(&spend).unwrap();
+        assert_eq!(next.supply, None);
+        assert_eq!(next.seal(None).inner_ref().supply, None);
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let mut supply = Supply::default();
+        let coin = always_true_coin(1000);
+        assert_eq!(
+            supply.remove_coin(&coin),
+            Err(SupplyError::Underflow(Denom::Mel))
+        );
+        supply.add_coin(&coin).unwrap();
+        assert_eq!(
+            supply.remove_coin(&always_true_coin(1001)),
+            Err(SupplyError::Underflow(Denom::Mel))
+        );
+        assert_eq!(
+            supply.add_coin(&always_true_coin(u128::MAX)),
+            Err(SupplyError::Overflow(Denom::Mel))
+        );
+        supply.remove_coin(&coin).unwrap();
+        assert_eq!(supply, Supply::default());
+    }
+}
@@ -0,0 +1,393 @@
+use super::{
+    applytx::faucet_dedup_pseudocoin, melswap::PoolState, receipt::MelmintEffects, PoolKey,
+    SealedState, Supply,
+};
+use crate::{
+    smtmapping::SmtMapping, stake::StakeDoc, BlockHeight, CoinDataHeight, CoinID, CoinValue,
+    ProposerAction, StateError, TxKind,
+};
+
+use std::collections::BTreeMap;
+
+use novasmt::ContentAddrStore;
+use serde::{Deserialize, Serialize};
+use tmelcrypt::HashVal;
+
+/// Compact data for undoing the application of a block, obtained through [SealedState::apply_block_with_undo] and consumed by [SealedState::rollback].
+///
+/// Only the previous values of what the block touched are stored. Since every block starts out with an empty transaction tree, the root of the previous block's transaction tree is stored as well; rolling back needs the database to still contain that tree.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlockUndo {
+    /// Previous values of every coin the block created, spent, or replaced. `None` means the coin did not exist.
+    pub coins: BTreeMap<CoinID, Option<CoinDataHeight>>,
+    /// Previous values of every pool the block changed. `None` means the pool did not exist.
+    pub pools: BTreeMap<PoolKey, Option<PoolState>>,
+    /// Previous values of every stake the block added or removed as stale, keyed by the hash of the staking transaction's hash as stored in the SMT.
+    pub stakes: BTreeMap<HashVal, Option<StakeDoc>>,
+    /// Root of the transaction tree of the previous block.
+    pub transactions_root: HashVal,
+    /// Previous fee pool.
+    pub fee_pool: CoinValue,
+    /// Previous fee multiplier.
+    pub fee_multiplier: u128,
+    /// Previous tips.
+    pub tips: CoinValue,
+    /// Previous DOSC speed.
+    pub dosc_speed: u128,
+    /// Proposer action of the previous block.
+    pub proposer_action: Option<ProposerAction>,
+    /// Previous supply.
+    pub supply: Option<Supply>,
+}
+
+impl BlockUndo {
+    /// Computes the data needed to go back from `next` to `previous`, where `next` is the result of applying a block to `previous` and `effects` is what melmint did while sealing it.
+    pub(crate) fn new<C: ContentAddrStore>(
+        previous: &SealedState<C>,
+        next: &SealedState<C>,
+        effects: &MelmintEffects,
+    ) -> Self {
+        let (previous_action, previous) = (previous.1, &previous.0);
+        let next = &next.0;
+
+        // every coin a block can touch is either an input or output of one of its transactions, a pseudocoin, or written by melmint, like the second output of a withdrawal
+        let mut touched = vec![CoinID::proposer_reward(next.height)];
+        touched.extend(effects.coin_keys());
+        // stakes are only added by staking transactions, and removed when they become stale
+        let mut touched_stakes = previous.stakes.stale_key_hashes(next.epoch());
+        next.transactions.val_iter().for_each(|tx| {
+            touched.extend(tx.inputs.iter().copied());
+            touched.extend((0..tx.outputs.len()).map(|i| tx.output_coinid(i as u8)));
+            match tx.kind {
+                TxKind::Faucet => touched.push(faucet_dedup_pseudocoin(tx.hash_nosigs())),
+                TxKind::Stake => touched_stakes
+                    .push(tmelcrypt::hash_single(stdcode::serialize(&tx.hash_nosigs()).unwrap()).0),
+                _ => {}
+            }
+        });
+        let coins = touched
+            .into_iter()
+            .map(|coin_id| (coin_id, previous.coins.get(&coin_id).0))
+            .collect();
+        // only melmint writes pools
+        let pools = effects
+            .pool_keys()
+            .map(|key| (key, previous.pools.get(&key).0))
+            .collect();
+        let stakes = touched_stakes
+            .into_iter()
+            .map(|key| {
+                let old = previous.stakes.mapping.get(key);
+                let old = (!old.is_empty())
+                    .then(|| stdcode::deserialize(&old).expect("SmtMapping saw invalid data"));
+                (HashVal(key), old)
+            })
+            .collect();
+
+        Self {
+            coins,
+            pools,
+            stakes,
+            transactions_root: previous.transactions.root_hash(),
+            fee_pool: previous.fee_pool,
+            fee_multiplier: previous.fee_multiplier,
+            tips: previous.tips,
+            dosc_speed: previous.dosc_speed,
+            proposer_action: previous_action,
+            supply: previous.supply.clone(),
+        }
+    }
+
+    /// Reverts `next` to the state before its block, checking the result against the header recorded in its history.
+    pub(crate) fn apply<C: ContentAddrStore>(
+        &self,
+        next: &SealedState<C>,
+    ) -> Result<SealedState<C>, StateError> {
+        let height = BlockHeight(
+            next.0
+                .height
+                .0
+                .checked_sub(1)
+                .ok_or(StateError::WrongHeader)?,
+        );
+        let expected_header = next
+            .0
+            .history
+            .get(&height)
+            .0
+            .ok_or(StateError::WrongHeader)?;
+
+        let mut state = next.0.clone();
+        state.height = height;
+        state.history.delete(&height);
+        self.coins.iter().for_each(|(coin_id, value)| match value {
+            Some(value) => state.coins.insert(*coin_id, value.clone()),
+            None => state.coins.delete(coin_id),
+        });
+        self.pools.iter().for_each(|(key, value)| match value {
+            Some(value) => state.pools.insert(*key, *value),
+            None => state.pools.delete(key),
+        });
+        self.stakes.iter().for_each(|(key, value)| {
+            let bytes = value
+                .as_ref()
+                .map(|v| stdcode::serialize(v).unwrap())
+                .unwrap_or_default();
+            state.stakes.mapping.insert(key.0, &bytes);
+        });
+        let database = state.transactions.mapping.database();
+        state.transactions =
+            SmtMapping::from_root(&database, self.transactions_root).map_err(|err| {
+                log::warn!("cannot restore the previous transactions: {}", err);
+                StateError::WrongHeader
+            })?;
+        state.fee_pool = self.fee_pool;
+        state.fee_multiplier = self.fee_multiplier;
+        state.tips = self.tips;
+        state.dosc_speed = self.dosc_speed;
+        state.supply = self.supply.clone();
+
+        let previous = SealedState(state, self.proposer_action);
+        if previous.header() != expected_header {
+            log::warn!("undo data does not lead back to {:?}", expected_header);
+            return Err(StateError::WrongHeader);
+        }
+        Ok(previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use novasmt::{Database, InMemoryCas};
+
+    use crate::melvm::Covenant;
+    use crate::testing::functions::{always_true_coin, spend_split, split_genesis_state};
+    use crate::{
+        ChainParams, CoinData, CoinDataHeight, CoinID, Denom, GenesisConfig, NetID, PoolKey,
+        ProposerAction, SealedState, StakeDoc, StateError, Transaction, TxKind,
+    };
+
+    fn assert_same_state(left: &SealedState<InMemoryCas>, right: &SealedState<InMemoryCas>) {
+        let (l, r) = (left.inner_ref(), right.inner_ref());
+        assert_eq!(l.history.root_hash(), r.history.root_hash());
+        assert_eq!(l.coins.root_hash(), r.coins.root_hash());
+        assert_eq!(l.transactions.root_hash(), r.transactions.root_hash());
+        assert_eq!(l.pools.root_hash(), r.pools.root_hash());
+        assert_eq!(l.stakes.root_hash(), r.stakes.root_hash());
+        assert_eq!(left.header(), right.header());
+        assert_eq!(left.partial_encoding(), right.partial_encoding());
+    }
+
+    #[test]
+    fn rollback_round_trips() {
+        let (base, split) = split_genesis_state(4);
+        let mut next = base.next_state();
+        let faucet = Transaction::new(TxKind::Faucet)
+            .add_output(always_true_coin(1000))
+            .with_fee(100_000.into());
+        next.apply_tx(&faucet).unwrap();
+        next.apply_tx_batch(&[
+            spend_split(&split, 0, 100_000),
+            spend_split(&split, 1, 200_000),
+        ])
+        .unwrap();
+        let block = next
+            .seal(Some(ProposerAction {
+                fee_multiplier_delta: 10,
+                reward_dest: Covenant::always_true().hash(),
+            }))
+            .to_block();
+
+        let (after, undo) = base.apply_block_with_undo(&block).unwrap();
+        assert_ne!(after.header(), base.header());
+        // only what the block touched is stored
+        assert_eq!(
+            undo.transactions_root,
+            base.inner_ref().transactions.root_hash()
+        );
+        assert!(undo.pools.len() <= 3);
+        assert!(undo.stakes.is_empty());
+        let undo: super::BlockUndo =
+            stdcode::deserialize(&stdcode::serialize(&undo).unwrap()).unwrap();
+        let rolled_back = after.rollback(&undo).unwrap();
+        assert_same_state(&rolled_back, &base);
+        // the restored state can apply the same block again
+        assert_eq!(
+            rolled_back.apply_block(&block).unwrap().header(),
+            after.header()
+        );
+    }
+
+    #[test]
+    fn rollback_restores_liquidity() {
+        let (base, split) = split_genesis_state(2);
+        let newcoin = Transaction::new(TxKind::Normal)
+            .add_input(split.output_coinid(0))
+            .add_output(always_true_coin((1 << 30) - 200_000))
+            .add_output(CoinData {
+                denom: Denom::NewCoin,
+                ..always_true_coin(1 << 20)
+            })
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true());
+        let pool = PoolKey::mel_and(Denom::Custom(newcoin.hash_nosigs()));
+        let deposit = Transaction::new(TxKind::LiqDeposit)
+            .add_input(newcoin.output_coinid(0))
+            .add_input(newcoin.output_coinid(1))
+            .add_output(always_true_coin((1 << 30) - 400_000))
+            .add_output(CoinData {
+                denom: pool.right,
+                ..always_true_coin(1 << 20)
+            })
+            .with_fee(200_000.into())
+            .add_script(Covenant::always_true())
+            .with_data(pool.to_bytes());
+        let mut next = base.next_state();
+        next.apply_tx_batch(&[newcoin, deposit.clone()]).unwrap();
+        let (deposited, undo) = base
+            .apply_block_with_undo(&next.seal(None).to_block())
+            .unwrap();
+        assert_same_state(&deposited.rollback(&undo).unwrap(), &base);
+
+        // withdraw every liquidity token the deposit got, paying the fee with another coin
+        let liqs = deposited
+            .inner_ref()
+            .coins
+            .get(&deposit.output_coinid(0))
+            .0
+            .unwrap()
+            .coin_data;
+        assert_eq!(liqs.denom, pool.liq_token_denom());
+        let withdraw = Transaction::new(TxKind::LiqWithdraw)
+            .add_input(deposit.output_coinid(0))
+            .add_input(split.output_coinid(1))
+            .add_output(liqs)
+            .with_fee((1 << 30).into())
+            .add_script(Covenant::always_true())
+            .with_data(pool.to_bytes());
+        let mut next = deposited.next_state();
+        next.apply_tx(&withdraw).unwrap();
+        let (withdrawn, undo) = deposited
+            .apply_block_with_undo(&next.seal(None).to_block())
+            .unwrap();
+        // melmint paid out the right side of the pool in a coin the transaction does not have
+        let synth = CoinID {
+            txhash: withdraw.hash_nosigs(),
+            index: 1,
+        };
+        assert!(withdrawn.inner_ref().coins.get(&synth).0.is_some());
+        assert_eq!(undo.coins.get(&synth), Some(&None));
+        assert_same_state(&withdrawn.rollback(&undo).unwrap(), &deposited);
+    }
+
+    #[test]
+    fn rollback_restores_stakes() {
+        let db = Database::new(InMemoryCas::default());
+        let mut genesis = GenesisConfig {
+            network: NetID::Custom02,
+            init_coindata: always_true_coin(1 << 40),
+            ..GenesisConfig::std_testnet()
+        }
+        .realize(&db);
+        let sym_coin = CoinData {
+            denom: Denom::Sym,
+            ..always_true_coin(1000)
+        };
+        let sym_coin_id = CoinID {
+            txhash: tmelcrypt::HashVal([1; 32]).into(),
+            index: 0,
+        };
+        genesis.coins.insert(
+            sym_coin_id,
+            CoinDataHeight {
+                coin_data: sym_coin.clone(),
+                height: 0.into(),
+            },
+        );
+        genesis.supply = genesis.scan_supply([]).ok();
+        let genesis = genesis.seal(None);
+
+        let fee = 1 << 20;
+        let stake = Transaction::new(TxKind::Stake)
+            .add_input(CoinID::zero_zero())
+            .add_input(sym_coin_id)
+            .add_output(sym_coin)
+            .add_output(always_true_coin((1 << 40) - fee))
+            .with_fee(fee.into())
+            .add_script(Covenant::always_true())
+            .with_data(
+                stdcode::serialize(&StakeDoc {
+                    pubkey: tmelcrypt::Ed25519SK::generate().to_public(),
+                    e_start: 1,
+                    e_post_end: 2,
+                    syms_staked: 1000.into(),
+                })
+                .unwrap(),
+            );
+        let mut next = genesis.next_state();
+        next.apply_tx(&stake).unwrap();
+        let block = next.seal(None).to_block();
+
+        let (after, undo) = genesis.apply_block_with_undo(&block).unwrap();
+        assert_eq!(undo.stakes.len(), 1);
+        assert_ne!(
+            after.inner_ref().stakes.root_hash(),
+            genesis.inner_ref().stakes.root_hash()
+        );
+        assert_same_state(&after.rollback(&undo).unwrap(), &genesis);
+    }
+
+    #[test]
+    fn rollback_restores_stale_stakes() {
+        let stake = StakeDoc {
+            pubkey: tmelcrypt::Ed25519SK::generate().to_public(),
+            e_start: 0,
+            e_post_end: 1,
+            syms_staked: 1000.into(),
+        };
+        let mut state = GenesisConfig {
+            network: NetID::Custom02,
+            init_coindata: always_true_coin(1 << 40),
+            stakes: [(tmelcrypt::hash_single(b"stake").into(), stake)]
+                .into_iter()
+                .collect(),
+            params: Some(ChainParams {
+                stake_epoch: 1,
+                ..ChainParams::custom()
+            }),
+            ..GenesisConfig::std_testnet()
+        }
+        .realize(&Database::new(InMemoryCas::default()))
+        .seal(None);
+        // the stake becomes stale at height 2, in epoch 2
+        while state.inner_ref().stakes.val_iter().count() > 0 {
+            let block = state.next_state().seal(None).to_block();
+            let (after, undo) = state.apply_block_with_undo(&block).unwrap();
+            assert_same_state(&after.rollback(&undo).unwrap(), &state);
+            state = after;
+        }
+        assert_eq!(state.inner_ref().height, 2.into());
+    }
+
+    #[test]
+    fn rollback_rejects_wrong_undo() {
+        let (base, split) = split_genesis_state(2);
+        let mut next = base.next_state();
+        next.apply_tx(&spend_split(&split, 0, 100_000)).unwrap();
+        let (first, first_undo) = base
+            .apply_block_with_undo(&next.seal(None).to_block())
+            .unwrap();
+        let mut next = first.next_state();
+        next.apply_tx(&spend_split(&split, 1, 100_000)).unwrap();
+        let (second, second_undo) = first
+            .apply_block_with_undo(&next.seal(None).to_block())
+            .unwrap();
+
+        assert!(matches!(
+            second.rollback(&first_undo),
+            Err(StateError::WrongHeader)
+        ));
+        assert_same_state(&second.rollback(&second_undo).unwrap(), &first);
+        assert_same_state(&first.rollback(&first_undo).unwrap(), &base);
+    }
+}
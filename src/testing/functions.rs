@@ -10,13 +10,13 @@ use crate::testing::utils::random_valid_txx;
 
 use crate::melvm::{Address, Covenant};
 use crate::{
-    CoinData, CoinDataHeight, CoinID, CoinValue, Denom, GenesisConfig, StakeDoc, State,
-    Transaction, MICRO_CONVERTER,
+    ChainParams, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, GenesisConfig, NetID,
+    SealedState, StakeDoc, State, Transaction, TxKind, MICRO_CONVERTER,
 };
 
 use std::collections::HashMap;
 
-use novasmt::{Database, InMemoryCas};
+use novasmt::{ContentAddrStore, Database, InMemoryCas};
 use tmelcrypt::{Ed25519PK, Ed25519SK};
 
 pub fn valid_txx(keypair: (Ed25519PK, Ed25519SK)) -> Vec<Transaction> {
@@ -41,6 +41,25 @@ pub fn valid_txx(keypair: (Ed25519PK, Ed25519SK)) -> Vec<Transaction> {
     )
 }
 
+/// Create a state one block after genesis, in which the transactions from [valid_txx] with the same public key can be applied
+pub fn valid_txx_state(pk: Ed25519PK) -> State<InMemoryCas> {
+    genesis_state(
+        genesis_mel_coin_id(),
+        CoinDataHeight {
+            coin_data: CoinData {
+                covhash: Covenant::std_ed25519_pk_legacy(pk).hash(),
+                value: (MICRO_CONVERTER * 1000).into(),
+                denom: Denom::Mel,
+                additional_data: vec![],
+            },
+            height: 0.into(),
+        },
+        Default::default(),
+    )
+    .seal(None)
+    .next_state()
+}
+
 /// Create a state using a mapping from sk to syms staked for an epoch
 pub fn create_state(
     stakers: &HashMap<Ed25519SK, CoinValue>,
@@ -76,7 +95,7 @@ pub fn create_state(
         .enumerate()
         .for_each(|(index, (sk, syms_staked))| {
             state.stakes.insert(
-                tmelcrypt::hash_single(&(index as u128).to_be_bytes()).into(),
+                tmelcrypt::hash_single((index as u128).to_be_bytes()).into(),
                 StakeDoc {
                     pubkey: sk.to_public(),
                     e_start: epoch_start,
@@ -89,6 +108,78 @@ pub fn create_state(
     state
 }
 
+/// A MEL coin of the given value that anyone can spend
+pub fn always_true_coin(value: u128) -> CoinData {
+    CoinData {
+        covhash: Covenant::always_true().hash(),
+        value: value.into(),
+        denom: Denom::Mel,
+        additional_data: vec![],
+    }
+}
+
+/// Create a sealed state one block after genesis, in which the genesis coin was split into `count` independent coins of 2^30 µMEL each by the returned transaction
+pub fn split_genesis_state(count: u8) -> (SealedState<InMemoryCas>, Transaction) {
+    split_genesis(
+        count,
+        GenesisConfig {
+            init_coindata: always_true_coin(1 << 40),
+            ..GenesisConfig::std_testnet()
+        },
+    )
+}
+
+/// Like [split_genesis_state], but on a custom network with the given consensus parameters
+pub fn split_custom_genesis_state(
+    count: u8,
+    params: ChainParams,
+) -> (SealedState<InMemoryCas>, Transaction) {
+    split_genesis(
+        count,
+        GenesisConfig {
+            network: NetID::Custom02,
+            init_coindata: always_true_coin(1 << 40),
+            params: Some(params),
+            ..GenesisConfig::std_testnet()
+        },
+    )
+}
+
+fn split_genesis(count: u8, config: GenesisConfig) -> (SealedState<InMemoryCas>, Transaction) {
+    let db = Database::new(InMemoryCas::default());
+    let mut genesis = config.realize(&db);
+    // so that every pool can be listed by [check_supply]
+    genesis.set_record_keys(true);
+    let genesis = genesis.seal(None);
+    let split = Transaction::new(TxKind::Normal)
+        .add_input(CoinID::zero_zero())
+        .with_outputs(vec![always_true_coin(1 << 30); count as usize])
+        .with_fee(CoinValue((1 << 40) - (1 << 30) * count as u128))
+        .add_script(Covenant::always_true());
+    let mut next = genesis.next_state();
+    next.apply_tx(&split).unwrap();
+    (next.seal(None), split)
+}
+
+/// Check that the supply tracked by a state matches the one computed by scanning it. The state must have recorded keys since genesis, so that every pool can be listed
+pub fn check_supply<C: ContentAddrStore>(state: &State<C>) {
+    let pools = state
+        .pools
+        .iter()
+        .map(|entry| entry.unwrap().0)
+        .collect::<Vec<_>>();
+    assert_eq!(state.supply, Some(state.scan_supply(pools).unwrap()));
+}
+
+/// Spend one of the coins created by the transaction from [split_genesis_state], paying the given fee
+pub fn spend_split(split: &Transaction, index: u8, fee: u128) -> Transaction {
+    Transaction::new(TxKind::Normal)
+        .add_input(split.output_coinid(index))
+        .add_output(always_true_coin((1 << 30) - fee))
+        .with_fee(fee.into())
+        .add_script(Covenant::always_true())
+}
+
 pub fn genesis_mel_coin_id() -> CoinID {
     CoinID::zero_zero()
 }
@@ -106,11 +197,13 @@ pub fn genesis_state(
     state
         .coins
         .insert(genesis_mel_coin_id, genesis_mel_coin_data_height);
+    // the coins were changed directly, and there are no pools yet
+    state.supply = state.scan_supply([]).ok();
 
     // Insert stake holders
     for (i, (&keypair, &syms_staked)) in genesis_stakeholders.iter().enumerate() {
         state.stakes.insert(
-            tmelcrypt::hash_single(&(i as u64).to_be_bytes()).into(),
+            tmelcrypt::hash_single((i as u64).to_be_bytes()).into(),
             StakeDoc {
                 pubkey: keypair.0,
                 e_start: GENESIS_EPOCH_START,
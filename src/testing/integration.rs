@@ -4,7 +4,7 @@
 
 use novasmt::{Database, InMemoryCas};
 
-use crate::SmtMapping;
+use crate::{DecodeError, SmtMapping};
 
 // // Add fuzz params ranges for rstest (range of num swaps, diff liquidity, etc...)
 // #[rstest]
@@ -226,3 +226,58 @@ fn smt_mapping() {
     }
     // assert_eq!(&map.mapping.root_hash(), [0; 32]);
 }
+
+#[test]
+fn smt_mapping_iter() {
+    let db = Database::new(InMemoryCas::default());
+    let tree = db.get_tree(Default::default()).unwrap();
+    let mut map: SmtMapping<_, u64, u64> = SmtMapping::new(tree);
+    map.insert(100, 100);
+    map.set_record_keys(true);
+    for i in 0..10 {
+        map.insert(i, i * 2);
+    }
+    let mut recorded = map.clone();
+    assert!(recorded.records_keys());
+    recorded.delete(&5);
+    // the key inserted before recording was turned on is missing until recorded explicitly
+    let hashed = tmelcrypt::hash_single(stdcode::serialize(&100u64).unwrap());
+    assert_eq!(recorded.key_preimage(hashed), None);
+    assert!(recorded
+        .iter()
+        .any(|entry| entry == Err(DecodeError::MissingKeyPreimage(hashed))));
+    recorded.record_key(&100);
+    assert_eq!(recorded.key_preimage(hashed), Some(100));
+
+    let mut pairs = recorded.iter().collect::<Result<Vec<_>, _>>().unwrap();
+    pairs.sort_unstable();
+    let mut expected = (0..10)
+        .filter(|i| *i != 5)
+        .map(|i| (i, i * 2))
+        .chain([(100, 100)])
+        .collect::<Vec<_>>();
+    expected.sort_unstable();
+    assert_eq!(pairs, expected);
+    // recording does not change the tree
+    assert_eq!(map.iter().count(), 11);
+}
+
+#[test]
+fn smt_mapping_fallible() {
+    let db = Database::new(InMemoryCas::default());
+    let tree = db.get_tree(Default::default()).unwrap();
+    let mut map: SmtMapping<_, u64, String> = SmtMapping::new(tree);
+    map.insert(1, "hello".into());
+    // a corrupted value
+    let hashed = tmelcrypt::hash_single(stdcode::serialize(&2u64).unwrap());
+    map.mapping.insert(hashed.0, &[0xff; 3]);
+
+    assert_eq!(map.try_get(&1).unwrap().0, Some("hello".to_string()));
+    assert_eq!(map.try_get(&3).unwrap().0, None);
+    assert_eq!(map.try_get(&2).unwrap_err(), DecodeError::BadValue);
+    assert_eq!(map.try_val_iter().filter(|v| v.is_err()).count(), 1);
+
+    let reopened: SmtMapping<_, u64, String> = SmtMapping::from_root(&db, map.root_hash()).unwrap();
+    assert_eq!(reopened.try_get(&1).unwrap().0, Some("hello".to_string()));
+    assert!(SmtMapping::<_, u64, String>::from_root(&db, tmelcrypt::HashVal([1; 32])).is_err());
+}
@@ -181,16 +181,21 @@ impl Transaction {
 
     /// Checks whether or not the transaction is well formed, respecting coin size bounds and such. **Does not** fully validate the transaction.
     pub fn is_well_formed(&self) -> bool {
+        self.is_well_formed_with(MAX_COINVAL)
+    }
+
+    /// Like [Transaction::is_well_formed], but with the given maximum coin value, such as that of a network's [crate::ChainParams].
+    pub fn is_well_formed_with(&self, max_coinval: CoinValue) -> bool {
         // check bounds
         let mut output: bool = true;
 
         self.outputs.iter().for_each(|out| {
-            if out.value > MAX_COINVAL {
+            if out.value > max_coinval {
                 output = false;
             }
         });
 
-        if self.fee > MAX_COINVAL {
+        if self.fee > max_coinval {
             output = false;
         }
 
@@ -361,7 +366,7 @@ impl CoinID {
     /// The pseudo-coin-ID for the proposer reward for the given height.
     pub fn proposer_reward(height: BlockHeight) -> Self {
         CoinID {
-            txhash: tmelcrypt::hash_keyed(b"reward_coin_pseudoid", &height.0.to_be_bytes()).into(),
+            txhash: tmelcrypt::hash_keyed(b"reward_coin_pseudoid", height.0.to_be_bytes()).into(),
             index: 0,
         }
     }
@@ -497,7 +502,10 @@ pub(crate) mod tests {
     // use std::sync::Arc;
 
     lazy_static!{
-        pub static ref VALID_TRANSACTION: Vec<Transaction> = crate::testing::functions::valid_txx(tmelcrypt::ed25519_keygen());
+        pub static ref VALID_TRANSACTION: Vec<Transaction> = crate::testing::functions::valid_txx({
+            let sk = tmelcrypt::Ed25519SK::generate();
+            (sk.to_public(), sk)
+        });
     }
 
     #[test]
@@ -510,9 +518,9 @@ pub(crate) mod tests {
     #[test]
     fn test_is_not_well_formed_if_value_gt_max() {
         // Extract out first coin data from first transaction in valid transactions
-        let valid_tx = VALID_TRANSACTION.get(0).unwrap().clone();
+        let valid_tx = VALID_TRANSACTION.first().unwrap().clone();
         let valid_outputs = valid_tx.outputs;
-        let valid_output = valid_outputs.get(0).unwrap().clone();
+        let valid_output = valid_outputs.first().unwrap().clone();
 
         // Create an invalid tx by setting an invalid output value
         let invalid_output_value = MAX_COINVAL + 1.into();
@@ -536,7 +544,7 @@ pub(crate) mod tests {
 
         offsets.iter().for_each(|offset| {
             // Extract out first coin data from first transaction in valid transactions
-            let valid_tx = VALID_TRANSACTION.get(0).unwrap().clone();
+            let valid_tx = VALID_TRANSACTION.first().unwrap().clone();
 
             // Create an invalid tx by setting an invalid fee value
             let invalid_tx = Transaction {
@@ -555,9 +563,9 @@ pub(crate) mod tests {
 
         offsets.iter().for_each(|offset| {
             // Extract out first coin data from first transaction in valid transactions
-            let valid_tx = VALID_TRANSACTION.get(0).unwrap().clone();
+            let valid_tx = VALID_TRANSACTION.first().unwrap().clone();
             let valid_outputs = valid_tx.outputs;
-            let valid_output = valid_outputs.get(0).unwrap().clone();
+            let valid_output = valid_outputs.first().unwrap().clone();
 
             // Create an invalid tx by setting an invalid output value
             let invalid_output_count = 255 + offset;
@@ -577,7 +585,7 @@ pub(crate) mod tests {
     #[test]
     fn test_hash_no_sigs() {
         // Check that valid transaction has a non zero number of signatures
-        let valid_tx = VALID_TRANSACTION.get(0).unwrap().clone();
+        let valid_tx = VALID_TRANSACTION.first().unwrap().clone();
         assert_ne!(valid_tx.sigs.len(), 0);
 
         // Create a transaction from it which has no signatures
@@ -586,7 +594,7 @@ pub(crate) mod tests {
 
         // Create a transaction from valid which has another signature
         let more_sig_tx = valid_tx.clone();
-        let new_sk = tmelcrypt::ed25519_keygen().1;
+        let new_sk = tmelcrypt::Ed25519SK::generate();
         let more_sig_tx = more_sig_tx.signed_ed25519(new_sk);
 
         // Ensure they all hash to same value
@@ -601,7 +609,7 @@ pub(crate) mod tests {
     #[test]
     fn test_sign_sigs() {
         // Create a transaction from it which has no signatures
-        let valid_tx = VALID_TRANSACTION.get(0).unwrap().clone();
+        let valid_tx = VALID_TRANSACTION.first().unwrap().clone();
         assert_ne!(valid_tx.sigs.len(), 0);
         let mut no_sigs_tx = valid_tx;
         no_sigs_tx.sigs = vec![];
@@ -611,7 +619,7 @@ pub(crate) mod tests {
         let mut mult_signature_tx = no_sigs_tx;
         let n = 5;
 
-        vec![tmelcrypt::ed25519_keygen(); n].iter().for_each(|(_pk, sk)| {
+        vec![tmelcrypt::Ed25519SK::generate(); n].iter().for_each(|sk| {
             mult_signature_tx = mult_signature_tx.clone().signed_ed25519(*sk);
         });
 
@@ -620,7 +628,7 @@ pub(crate) mod tests {
 
         // sign it M times
         let m = 8;
-        vec![tmelcrypt::ed25519_keygen(); m].iter().for_each(|(_pk, sk)| {
+        vec![tmelcrypt::Ed25519SK::generate(); m].iter().for_each(|sk| {
             mult_signature_tx = mult_signature_tx.clone().signed_ed25519(*sk);
         });
 
@@ -631,15 +639,17 @@ pub(crate) mod tests {
     #[test]
     fn test_sign_sigs_and_verify() {
         // Create a transaction from it which has no signatures
-        let valid_tx = VALID_TRANSACTION.get(0).unwrap().clone();
+        let valid_tx = VALID_TRANSACTION.first().unwrap().clone();
         assert_ne!(valid_tx.sigs.len(), 0);
         let mut no_sigs_tx = valid_tx;
         no_sigs_tx.sigs = vec![];
         assert_eq!(no_sigs_tx.sigs.len(), 0);
 
         // create two key pairs
-        let (pk1, sk1) = tmelcrypt::ed25519_keygen();
-        let (pk2, sk2) = tmelcrypt::ed25519_keygen();
+        let sk1 = tmelcrypt::Ed25519SK::generate();
+        let pk1 = sk1.to_public();
+        let sk2 = tmelcrypt::Ed25519SK::generate();
+        let pk2 = sk2.to_public();
 
         // sign it
         let mut tx = no_sigs_tx;
@@ -650,8 +660,8 @@ pub(crate) mod tests {
         let sig1 = tx.sigs[0].clone();
         let sig2 = tx.sigs[1].clone();
 
-        pk1.verify(&tx.hash_nosigs().0.to_vec(), &sig1);
-        pk2.verify(&tx.hash_nosigs().0.to_vec(), &sig2);
+        pk1.verify(&tx.hash_nosigs().0, &sig1);
+        pk2.verify(&tx.hash_nosigs().0, &sig2);
 
         assert_eq!(tx.sigs.len(), 2);
     }
@@ -659,8 +669,8 @@ pub(crate) mod tests {
     #[test]
     fn test_total_output() {
         // create transaction
-        let mut valid_tx = VALID_TRANSACTION.get(0).unwrap().clone();
-        let (pk, _sk) = tmelcrypt::ed25519_keygen();
+        let mut valid_tx = VALID_TRANSACTION.first().unwrap().clone();
+        let pk = tmelcrypt::Ed25519SK::generate().to_public();
         let scr = melvm::Covenant::std_ed25519_pk_legacy(pk);
 
         // insert coins
@@ -683,7 +693,7 @@ pub(crate) mod tests {
 
         // Check total is valid
         let value_by_coin_type = valid_tx.total_outputs();
-        let total: u128 = value_by_coin_type.iter().map(|(_k, v)| v.0).sum();
+        let total: u128 = value_by_coin_type.values().map(|v| v.0).sum();
 
         let fee = 1577000; // Temporary hack
         assert_eq!(total, val1 + val2 + fee);
@@ -692,8 +702,8 @@ pub(crate) mod tests {
     #[test]
     fn test_script_as_map() {
         // create transaction
-        let valid_tx = VALID_TRANSACTION.get(0).unwrap().clone();
-        let (pk, _sk) = tmelcrypt::ed25519_keygen();
+        let valid_tx = VALID_TRANSACTION.first().unwrap().clone();
+        let pk = tmelcrypt::Ed25519SK::generate().to_public();
         let _scr = melvm::Covenant::std_ed25519_pk_legacy(pk);
 
         // add scripts